@@ -0,0 +1,202 @@
+//! Account addresses. An account is identified by the hash of its public key, written as bech32 text
+//! with the human readable prefix of the chain, like `qan1...`. The prefix is the `address_prefix` of the genesis, or the chain id without one,
+//! and the node sets it with `set_hrp` once it knows its genesis. The checksum catches typos, and addresses with the prefix of another chain
+//! are rejected, so chains with different prefixes can't mix up their addresses. The prefix doesn't tell apart chains sharing it.
+//! RPC methods taking an address also accept the raw hash as an array of 32 numbers.
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+use rocksdb::DB;
+use crate::error::QanError;
+
+/// Human readable prefix used until the node sets the prefix of its chain.
+pub const DEFAULT_HRP : &str = "qan";
+/// Longest human readable prefix, so addresses stay within the 90 characters of bech32.
+pub const MAX_HRP_LEN : usize = 24;
+/// Key of the human readable prefix of the chain in the block database.
+const HRP_KEY : &str = "hrp";
+
+static HRP : RwLock<String> = RwLock::new(String::new());
+
+/// Human readable prefix of the addresses of the chain `chain_id`, its `address_prefix` if there is one, the chain id otherwise.
+/// Fails if that isn't a lowercase prefix of letters, digits and dashes up to `MAX_HRP_LEN` long.
+pub fn hrp_of(chain_id : &str, address_prefix : &str) -> Result<String, QanError> {
+    let hrp = if address_prefix.is_empty() { chain_id } else { address_prefix };
+    if hrp.is_empty() || hrp.len() > MAX_HRP_LEN
+        || !hrp.bytes().all(|b|b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-') {
+        return Err(QanError::Address(format!("{} is not a valid address prefix", hrp)))
+    }
+    Ok(hrp.to_string())
+}
+
+/// Sets the human readable prefix addresses are displayed and parsed with.
+pub fn set_hrp(hrp : &str) {
+    *HRP.write().unwrap_or_else(|e|e.into_inner()) = hrp.to_string();
+}
+
+/// The human readable prefix addresses are displayed and parsed with, `DEFAULT_HRP` until `set_hrp` is called.
+pub fn hrp() -> String {
+    let hrp = HRP.read().unwrap_or_else(|e|e.into_inner());
+    if hrp.is_empty() { DEFAULT_HRP.to_string() } else { hrp.clone() }
+}
+
+/// Persists the human readable prefix of the genesis.
+pub fn store_hrp(db : &DB, hrp : &str) -> Result<(), QanError> {
+    db.put(HRP_KEY, hrp).map_err(|e|QanError::Database(e))?;
+    db.flush().map_err(|e|QanError::Database(e))
+}
+
+/// The persisted human readable prefix, the chain id for chains that didn't persist one.
+pub fn load_hrp(db : &DB) -> Result<String, QanError> {
+    match db.get(HRP_KEY).map_err(|e|QanError::Database(e))?{
+        Some(h) => Ok(String::from_utf8_lossy(&h).to_string()),
+        None => hrp_of(&crate::chainspec::Domain::load_chain_id(db)?, "")
+    }
+}
+
+const CHARSET : &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR : [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+/// Hash of an account's public key, displayed and parsed as bech32 text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address(pub [u8;32]);
+
+fn polymod(values : &[u8]) -> u32 {
+    let mut chk : u32 = 1;
+    for v in values {
+        let top = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ *v as u32;
+        for (i, g) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 { chk ^= g }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp : &str) -> Vec<u8> {
+    let mut v : Vec<u8> = hrp.bytes().map(|b|b >> 5).collect();
+    v.push(0);
+    v.extend(hrp.bytes().map(|b|b & 31));
+    v
+}
+
+fn checksum(hrp : &str, data : &[u8]) -> Vec<u8> {
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0;6]);
+    let m = polymod(&values) ^ 1;
+    (0..6).map(|i|((m >> (5 * (5 - i))) & 31) as u8).collect()
+}
+
+/// Regroups `from` bit groups into `to` bit groups. Decoding (`pad` false) rejects leftover bits that aren't zero padding.
+fn convert_bits(data : &[u8], from : u32, to : u32, pad : bool) -> Option<Vec<u8>> {
+    let mut acc : u32 = 0;
+    let mut bits : u32 = 0;
+    let mut ret = Vec::new();
+    let maxv = (1 << to) - 1;
+    for d in data {
+        acc = (acc << from) | *d as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            ret.push(((acc >> bits) & maxv) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 { ret.push(((acc << (to - bits)) & maxv) as u8) }
+    } else if bits >= from || ((acc << (to - bits)) & maxv) != 0 {
+        return None
+    }
+    Some(ret)
+}
+
+impl Address {
+    /// The bech32 text of the address with the human readable prefix `hrp`.
+    pub fn encode(&self, hrp : &str) -> String {
+        let data = convert_bits(&self.0, 8, 5, true).unwrap_or_default();
+        let checksum = checksum(hrp, &data);
+        let text : String = data.iter().chain(checksum.iter()).map(|d|CHARSET[*d as usize] as char).collect();
+        format!("{}1{}", hrp, text)
+    }
+
+    /// Parses the bech32 text `s` of an address, which has to have the human readable prefix `expected`.
+    pub fn decode(s : &str, expected : &str) -> Result<Address, QanError> {
+        let invalid = |reason : &str| QanError::Address(format!("{}: {}", s, reason));
+        if s.to_lowercase() != s && s.to_uppercase() != s { return Err(invalid("mixed case")) }
+        let s_lower = s.to_lowercase();
+        let sep = s_lower.rfind('1').ok_or(invalid("missing separator"))?;
+        let (hrp, rest) = (&s_lower[..sep], &s_lower[sep+1..]);
+        if hrp != expected { return Err(invalid(&format!("prefix {} belongs to another chain than {}", hrp, expected))) }
+        if rest.len() < 6 { return Err(invalid("too short")) }
+        let mut data = Vec::new();
+        for c in rest.bytes() {
+            data.push(CHARSET.iter().position(|x|*x == c).ok_or(invalid("invalid character"))? as u8);
+        }
+        let mut values = hrp_expand(hrp);
+        values.extend_from_slice(&data);
+        if polymod(&values) != 1 { return Err(invalid("checksum mismatch")) }
+        let bytes = convert_bits(&data[..data.len()-6], 5, 8, false).ok_or(invalid("invalid padding"))?;
+        if bytes.len() != 32 { return Err(invalid("not 32 bytes")) }
+        Ok(Address(crate::util::vec_to_arr(&bytes)))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.encode(&hrp()))
+    }
+}
+
+impl FromStr for Address {
+    type Err = QanError;
+
+    fn from_str(s : &str) -> Result<Address, QanError> {
+        Address::decode(s, &hrp())
+    }
+}
+
+impl serde::Serialize for Address {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AddressRepr {
+    Text(String),
+    Raw([u8;32]),
+}
+
+impl<'de> serde::Deserialize<'de> for Address {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Address, D::Error> {
+        match AddressRepr::deserialize(deserializer)? {
+            AddressRepr::Text(s) => s.parse().map_err(serde::de::Error::custom),
+            AddressRepr::Raw(h) => Ok(Address(h)),
+        }
+    }
+}
+
+#[test]
+fn address_roundtrip() {
+    let a = Address(crate::util::do_hash(&b"pk".to_vec()));
+    let text = a.to_string();
+    assert!(text.starts_with("qan1"));
+    assert_eq!(text.parse::<Address>().unwrap(), a);
+    assert_eq!(text.to_uppercase().parse::<Address>().unwrap(), a);
+    let mut typo = text.into_bytes();
+    let last = typo.len() - 1;
+    typo[last] = if typo[last] == b'q' { b'p' } else { b'q' };
+    assert!(String::from_utf8(typo).unwrap().parse::<Address>().is_err());
+    assert!(Address([0;32]).to_string().replacen("qan", "qaz", 1).parse::<Address>().is_err());
+    let testnet = a.encode("qan-testnet");
+    assert_eq!(Address::decode(&testnet, "qan-testnet").unwrap(), a);
+    assert!(Address::decode(&testnet, "qan").is_err() && Address::decode(&a.encode("qan"), "qan-testnet").is_err());
+    assert_eq!(hrp_of("qan-testnet", "").unwrap(), "qan-testnet");
+    assert_eq!(hrp_of("qan-testnet", "qant").unwrap(), "qant");
+    assert!(hrp_of("Qan Testnet", "").is_err() && hrp_of(&"q".repeat(MAX_HRP_LEN + 1), "").is_err());
+    assert_eq!(serde_json::from_str::<Address>(&serde_json::to_string(&a).unwrap()).unwrap(), a);
+    let mut counting = [0;32];
+    for i in 0..32 { counting[i] = i as u8 }
+    assert_eq!(Address(counting).to_string(), "qan1qqqsyqcyq5rqwzqfpg9scrgwpugpzysnzs23v9ccrydpk8qarc0sdv6dvk");
+}
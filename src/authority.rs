@@ -0,0 +1,277 @@
+use rocksdb::DB;
+use crate::error::QanError;
+use crate::block::Block;
+use crate::transaction::Transaction;
+use crate::nemezis::NemezisData;
+
+/// Key of the persisted authority set in the block database.
+pub const AUTHORITIES_KEY : &'static str = "authorities";
+/// Key of the persisted authority set history in the block database.
+pub const HISTORY_KEY : &'static str = "authority_history";
+
+/// Proof-of-Authority validator set.
+/// Members are identified by the hash of their public key, which is the same value a block carries in `proposer_pub`.
+/// The set is defined by the genesis transaction and persisted next to the chain.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct AuthoritySet {
+    members : Vec<[u8;32]>,
+}
+
+impl AuthoritySet {
+    /// Constructor function for AuthoritySet. Members are kept sorted and deduplicated so every node orders them the same way.
+    pub fn new(mut members : Vec<[u8;32]>) -> Self {
+        members.sort();
+        members.dedup();
+        AuthoritySet{ members }
+    }
+
+    /// Reads the authority set out of the genesis transaction.
+    pub fn from_nemezis(tx : &Transaction) -> Result<Self, QanError> {
+        let data : NemezisData = serde_json::from_slice(&tx.get_data()).map_err(|e|QanError::Serde(e))?;
+        if data.authorities.is_empty() {
+            return Err(QanError::Internal("genesis defines no authorities".to_string()))
+        }
+        Ok(AuthoritySet::new(data.authorities))
+    }
+
+    /// Loads the persisted authority set. Returns `None` if the chain has not been initialized yet.
+    pub fn load(db : &DB) -> Result<Option<Self>, QanError> {
+        match db.get(AUTHORITIES_KEY).map_err(|e|QanError::Database(e))?{
+            Some(s) => Ok(Some(serde_json::from_slice(&s).map_err(|e|QanError::Serde(e))?)),
+            None => Ok(None)
+        }
+    }
+
+    /// Persists the authority set in the block database.
+    pub fn store(&self, db : &DB) -> Result<(), QanError> {
+        db.put(AUTHORITIES_KEY, serde_json::to_vec(&self).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
+        db.flush().map_err(|e|QanError::Database(e))
+    }
+
+    /// Checks whether the public key hash belongs to an authority.
+    pub fn contains(&self, id : &[u8;32]) -> bool {
+        self.members.binary_search(id).is_ok()
+    }
+
+    /// getter for the members of the set
+    pub fn members(&self) -> &Vec<[u8;32]> {
+        &self.members
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+}
+
+/// Change of the authority set proposed by a system transaction.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub enum AuthorityChange {
+    Add([u8;32]),
+    Remove([u8;32]),
+}
+
+/// Approval of an authority change, by `authority` in the block `block`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Approval {
+    pub authority   : [u8;32],
+    pub block       : [u8;32],
+}
+
+/// Authority change waiting for a majority of approvals.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct PendingChange {
+    pub change      : AuthorityChange,
+    pub approvals   : Vec<Approval>,
+}
+
+/// Authority set active from block height `activation` on, scheduled in the block `block` by `change`, or by evidence if there's no change.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ScheduledSet {
+    pub activation  : u64,
+    pub block       : [u8;32],
+    pub set         : AuthoritySet,
+    pub change      : Option<PendingChange>,
+}
+
+/// Every authority set of the canonical chain ordered by activation height, and the changes still waiting for approval.
+/// Old sets are kept so blocks can be validated against the set that was active at their height.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct AuthorityHistory {
+    sets    : Vec<ScheduledSet>,
+    pending : Vec<PendingChange>,
+}
+
+impl AuthorityHistory {
+    /// Constructor function for AuthorityHistory, starting with the set of the genesis block.
+    pub fn genesis(set : AuthoritySet, genesis : [u8;32]) -> Self {
+        AuthorityHistory{ sets: vec![ScheduledSet{ activation: 0, block: genesis, set, change: None }], pending: Vec::new() }
+    }
+
+    /// Loads the persisted history. Chains without one start from the genesis set.
+    /// Returns `None` if the chain has not been initialized yet.
+    pub fn load(db : &DB) -> Result<Option<Self>, QanError> {
+        match db.get(HISTORY_KEY).map_err(|e|QanError::Database(e))?{
+            Some(s) => Ok(Some(serde_json::from_slice(&s).map_err(|e|QanError::Serde(e))?)),
+            None => Ok(AuthoritySet::load(db)?.map(|set|AuthorityHistory::genesis(set, [0;32])))
+        }
+    }
+
+    /// Persists the history in the block database.
+    pub fn store(&self, db : &DB) -> Result<(), QanError> {
+        db.put(HISTORY_KEY, serde_json::to_vec(&self).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
+        db.flush().map_err(|e|QanError::Database(e))
+    }
+
+    /// The authority set active at `height`.
+    pub fn set_at(&self, height : u64) -> &AuthoritySet {
+        &self.sets.iter().rev().find(|s|s.activation <= height).unwrap_or(&self.sets[0]).set
+    }
+
+    /// getter for the scheduled sets
+    pub fn sets(&self) -> &Vec<ScheduledSet> {
+        &self.sets
+    }
+
+    /// getter for the changes waiting for approval
+    pub fn pending(&self) -> &Vec<PendingChange> {
+        &self.pending
+    }
+
+    /// The set following the latest scheduled set after `change`, if the change applies to it.
+    /// The last authority is never removed.
+    fn next_set(&self, change : &AuthorityChange) -> Option<AuthoritySet> {
+        let latest = &self.sets[self.sets.len()-1].set;
+        match change {
+            AuthorityChange::Add(id) if !latest.contains(id) => {
+                let mut m = latest.members().clone();
+                m.push(*id);
+                Some(AuthoritySet::new(m))
+            },
+            AuthorityChange::Remove(id) if latest.contains(id) && latest.len() > 1 => {
+                Some(AuthoritySet::new(latest.members().iter().filter(|m|*m != id).cloned().collect()))
+            },
+            _ => None
+        }
+    }
+
+    /// Records the approval of `change` by `authority` in the block `block` at `height`.
+    /// Once more than half of the set active at `height` approved, the change is scheduled at the next multiple of `epoch_length`.
+    /// Approvals by non-members and changes that don't apply to the latest set are refused.
+    /// Returns the activation height if the change got scheduled.
+    pub fn approve(&mut self, change : AuthorityChange, authority : [u8;32], block : [u8;32], height : u64, epoch_length : u64) -> Option<u64> {
+        let current = self.set_at(height).clone();
+        if !current.contains(&authority) { return None }
+        let set = self.next_set(&change)?;
+        let pos = match self.pending.iter().position(|p|p.change == change){
+            Some(pos) => pos,
+            None => {
+                self.pending.push(PendingChange{ change, approvals: Vec::new() });
+                self.pending.len()-1
+            }
+        };
+        if self.pending[pos].approvals.iter().any(|a|a.authority == authority) { return None }
+        self.pending[pos].approvals.push(Approval{ authority, block });
+        let approved = self.pending[pos].approvals.iter().filter(|a|current.contains(&a.authority)).count();
+        if approved * 2 <= current.len() { return None }
+        let activation = next_epoch(height, epoch_length);
+        let change = self.pending.remove(pos);
+        self.sets.push(ScheduledSet{ activation, block, set, change: Some(change) });
+        Some(activation)
+    }
+
+    /// Removes the authority `id` at the next multiple of `epoch_length` without approvals, as punishment proven by evidence in the block `block`.
+    /// Returns the activation height if the removal got scheduled.
+    pub fn exclude(&mut self, id : [u8;32], block : [u8;32], height : u64, epoch_length : u64) -> Option<u64> {
+        let set = self.next_set(&AuthorityChange::Remove(id))?;
+        let activation = next_epoch(height, epoch_length);
+        self.sets.push(ScheduledSet{ activation, block, set, change: None });
+        Some(activation)
+    }
+
+    /// Removes the sets scheduled and the approvals given in `block`. Approvals consumed by a removed set become pending again.
+    /// Returns whether anything changed.
+    pub fn revert(&mut self, block : &[u8;32]) -> bool {
+        let before = self.clone();
+        while self.sets.len() > 1 && &self.sets[self.sets.len()-1].block == block {
+            if let Some(change) = self.sets.pop().and_then(|s|s.change) {
+                match self.pending.iter_mut().find(|p|p.change == change.change){
+                    Some(p) => p.approvals.extend(change.approvals),
+                    None => self.pending.push(change)
+                }
+            }
+        }
+        for p in self.pending.iter_mut() {
+            p.approvals.retain(|a|&a.block != block);
+        }
+        self.pending.retain(|p|!p.approvals.is_empty());
+        before != *self
+    }
+}
+
+/// First epoch boundary after `height`.
+fn next_epoch(height : u64, epoch_length : u64) -> u64 {
+    let epoch_length = epoch_length.max(1);
+    (height / epoch_length + 1) * epoch_length
+}
+
+/// The authority set active at `height` on the canonical chain.
+pub fn set_at(db : &DB, height : u64) -> Result<AuthoritySet, QanError> {
+    Ok(AuthorityHistory::load(db)?.ok_or(QanError::Internal("no authority set, genesis is not initialized".to_string()))?.set_at(height).clone())
+}
+
+/// In-memory copy of the authority history of the canonical chain up to a head, so looking up a set doesn't read the database.
+/// Sets scheduled up to the head are known, and a new set only becomes active at an epoch boundary,
+/// so the copy is reloaded when the block after the head starts an epoch or the chain is reorganized.
+pub struct AuthorityCache {
+    history : AuthorityHistory,
+    head    : [u8;32],
+}
+
+impl AuthorityCache {
+    /// Loads the history of the canonical chain ending in `head`.
+    pub fn load(db : &DB, head : &Block) -> Result<Self, QanError> {
+        let history = AuthorityHistory::load(db)?.ok_or(QanError::Internal("no authority set, genesis is not initialized".to_string()))?;
+        Ok(AuthorityCache{ history, head: head.hash() })
+    }
+
+    /// Follows the canonical chain to `head`. The history is reloaded if `head` isn't a child of the previous head,
+    /// or the block after it starts an epoch or changes the epoch length.
+    pub fn update(&mut self, db : &DB, head : &Block) -> Result<(), QanError> {
+        if head.hash() == self.head { return Ok(()) }
+        let epoch_length = crate::governance::settings_at(db, head.height()+1)?.epoch_length();
+        if head.prev_hash() != self.head || (head.height()+1) % epoch_length.max(1) == 0
+            || epoch_length != crate::governance::settings_at(db, head.height())?.epoch_length() {
+            *self = AuthorityCache::load(db, head)?;
+        }
+        self.head = head.hash();
+        Ok(())
+    }
+
+    /// The authority set active at `height`. Valid up to the height after the head.
+    pub fn set_at(&self, height : u64) -> &AuthoritySet {
+        self.history.set_at(height)
+    }
+}
+
+#[test]
+fn authority_membership() {
+    let set = AuthoritySet::new(vec![[3;32], [1;32], [3;32]]);
+    assert_eq!(set.len(), 2);
+    assert!(set.contains(&[1;32]));
+    assert!(!set.contains(&[2;32]));
+}
+
+#[test]
+fn majority_rotation() {
+    let mut history = AuthorityHistory::genesis(AuthoritySet::new(vec![[1;32], [2;32], [3;32]]), [0;32]);
+    let add = AuthorityChange::Add([4;32]);
+    assert_eq!(history.approve(add.clone(), [9;32], [5;32], 5, 10), None);
+    assert_eq!(history.approve(add.clone(), [1;32], [5;32], 5, 10), None);
+    assert_eq!(history.approve(add.clone(), [2;32], [6;32], 6, 10), Some(10));
+    assert!(!history.set_at(9).contains(&[4;32]));
+    assert!(history.set_at(10).contains(&[4;32]));
+    // reverting the block of the deciding approval keeps the first one pending
+    assert!(history.revert(&[6;32]));
+    assert!(!history.set_at(10).contains(&[4;32]));
+    assert_eq!(history.pending()[0].approvals.len(), 1);
+}
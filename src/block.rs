@@ -2,6 +2,7 @@ use serde::{Serialize, Deserialize};
 use rmps::{Serializer, Deserializer};
 use crate::error::QanError;
 use crate::util::do_hash;
+use crate::chainspec::Domain;
 #[cfg(not(feature = "quantum"))]
 use ed25519_dalek::{Keypair, PublicKey, Signature};
 #[cfg(feature = "quantum")]
@@ -17,132 +18,266 @@ pub fn merge(l:&[u8;32],r:&[u8;32])->[u8;32]{
     do_hash(&buf) 
 }
 
-#[derive(Debug, Deserialize, Serialize,  Clone)]
-pub struct BlockData {
-    pub timestamp   : u64,
-    pub merkle_root : Vec<u8>,
-    pub prev_hash   : [u8;32],
-    pub txes        : Vec<[u8;32]>,
+/// Root hash of an empty tree, used for the roots of blocks without content.
+pub const EMPTY_ROOT : [u8;32] = [0;32];
+
+/// Hash of a transaction tree leaf. Leaves and inner nodes are hashed with different prefixes, so an inner node can't be proven as a transaction.
+fn leaf_hash(tx : &[u8;32]) -> [u8;32] {
+    let mut buf = vec![0x00];
+    buf.extend_from_slice(tx);
+    do_hash(&buf)
 }
 
-impl fmt::Display for BlockData {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut tx_bases = Vec::new();
-        for i in &self.txes{
-            tx_bases.push(encode(&i));
-        } 
-        write!(f, "timestamp : {}\nmerkle_root : {}\nprev_hash : {:?}\ntxes : {:?}",
-        self.timestamp, encode(&self.merkle_root), self.prev_hash, tx_bases)
+/// Hash of a transaction tree inner node.
+fn node_hash(l : &[u8;32], r : &[u8;32]) -> [u8;32] {
+    let mut buf = vec![0x01];
+    buf.extend_from_slice(l);
+    buf.extend_from_slice(r);
+    do_hash(&buf)
+}
+
+/// Nodes of the Merkle tree over `txes`, laid out as an array like in `static_merkle_tree`.
+/// Leaf `i` is node `leaves-1+i` holding `leaf_hash` of the transaction, node `n` is the `node_hash` of nodes `2n+1` and `2n+2`, and node 0 is the root.
+fn tree_nodes(txes : &[[u8;32]]) -> Vec<[u8;32]> {
+    if txes.is_empty() { return Vec::new() }
+    let leaves = txes.len();
+    let mut nodes = vec![EMPTY_ROOT; 2*leaves-1];
+    for (i, tx) in txes.iter().enumerate() {
+        nodes[leaves-1+i] = leaf_hash(tx);
+    }
+    for i in (0..leaves-1).rev() {
+        nodes[i] = node_hash(&nodes[2*i+1], &nodes[2*i+2]);
     }
+    nodes
 }
 
-impl BlockData {
-    /// Constructor function for BlockData. Takes a vector of the transaction hashes, and the hash of the previous block.
-    pub fn new(prev_hash : [u8;32], txes : Vec<[u8;32]>) -> Result<Self, QanError> {
-        let tree = static_merkle_tree::Tree::from_hashes(txes.to_vec(),merge);
-        let merkle_root : Vec<u8> = tree.get_root_hash().unwrap().to_vec();
-        Ok(BlockData{
-            prev_hash,
-            timestamp: crate::util::timestamp(),
-            merkle_root,
-            txes,
-        })
+/// Merkle root of the transaction hashes of a block.
+pub fn tx_root(txes : &[[u8;32]]) -> [u8;32] {
+    tree_nodes(txes).first().copied().unwrap_or(EMPTY_ROOT)
+}
+
+/// Proof that a transaction hash is a leaf of a block's transaction root.
+/// `index` is the position of the transaction in the block of `leaves` transactions, `siblings` the hashes on the path to the root, bottom up.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct MerkleProof {
+    pub index       : usize,
+    pub leaves      : usize,
+    pub siblings    : Vec<[u8;32]>,
+}
+
+/// Inclusion proof of the transaction at `index` of `txes`, built from the same tree as `tx_root`.
+pub fn prove(txes : &[[u8;32]], index : usize) -> Option<MerkleProof> {
+    if index >= txes.len() { return None }
+    let nodes = tree_nodes(txes);
+    let mut siblings = Vec::new();
+    let mut node = txes.len()-1+index;
+    while node > 0 {
+        siblings.push(if node % 2 == 1 { nodes[node+1] } else { nodes[node-1] });
+        node = (node-1)/2;
     }
+    Some(MerkleProof{ index, leaves: txes.len(), siblings })
+}
 
-    /// getter for block timestamp
-    pub fn timestamp(&self) -> u64{
-        self.timestamp
+/// Checks that `proof` connects the transaction hash `leaf` to the transaction root `root`.
+pub fn verify_proof(leaf : &[u8;32], proof : &MerkleProof, root : &[u8;32]) -> bool {
+    if proof.index >= proof.leaves { return false }
+    let mut node = proof.leaves-1+proof.index;
+    let mut hash = leaf_hash(leaf);
+    for sibling in &proof.siblings {
+        if node == 0 { return false }
+        hash = if node % 2 == 1 { node_hash(&hash, sibling) } else { node_hash(sibling, &hash) };
+        node = (node-1)/2;
     }
+    node == 0 && &hash == root
+}
 
+/// Compact block header. It commits to the body through `tx_root`, so headers can be fetched and checked without the transaction lists.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub prev_hash       : [u8;32],
+    pub height          : u64,
+    pub timestamp       : u64,
+    pub tx_root         : [u8;32],
+    pub state_root      : [u8;32],
+    pub receipts_root   : [u8;32],
+    pub proposer        : [u8;32],
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct HashedBlock {
-    pub blockdata   : BlockData,
-    pub hash        : [u8;32],
+impl fmt::Display for BlockHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "prev_hash : {}\nheight : {}\ntimestamp : {}\ntx_root : {}\nstate_root : {}\nreceipts_root : {}\nproposer : {}",
+        encode(&self.prev_hash), self.height, self.timestamp, encode(&self.tx_root), encode(&self.state_root), encode(&self.receipts_root), encode(&self.proposer))
+    }
 }
 
-impl fmt::Display for HashedBlock {
+impl BlockHeader {
+    /// getter for the header hash, which is the block hash
+    pub fn hash(&self) -> Result<[u8;32], QanError> {
+        Ok(do_hash(&crate::codec::encode(&self).map_err(|e|QanError::Codec(e))?))
+    }
+}
+
+/// Block header with its hash and the proposer's signature over it.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SignedHeader {
+    pub header  : BlockHeader,
+    pub hash    : [u8;32],
+    pub sig     : Vec<u8>,
+}
+
+impl fmt::Display for SignedHeader {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "blockdata: {} \nhash : {:?}",
-        self.blockdata, self.hash)
+        write!(f, "header : {}\nhash : {}\nsig : {}",
+        self.header, encode(&self.hash), encode(&self.sig))
     }
 }
 
-impl HashedBlock {
-    pub fn new(prev_hash : [u8;32], txes : Vec<[u8;32]>) -> Result<Self, QanError> {
-        let blockdata = BlockData::new(prev_hash, txes)?;
-        let hash = do_hash(&serde_json::to_vec(&blockdata).map_err(|e|QanError::Serde(e))?);
-        Ok(HashedBlock{
-            blockdata,
-            hash 
-        })
+impl SignedHeader {
+    #[cfg(not(feature = "quantum"))]
+    pub fn new(header : BlockHeader, kp : &Keypair, domain : &Domain) -> Result<Self, QanError> {
+        let sig = kp.sign(&domain.payload(&crate::codec::encode(&header).map_err(|e|QanError::Codec(e))?)).to_bytes().to_vec();
+        Ok(SignedHeader{ hash: header.hash()?, header, sig })
     }
 
-    /// getter for block timestamp
-    pub fn timestamp(&self) -> u64 {
-        self.blockdata.timestamp()
+    #[cfg(feature = "quantum")]
+    pub fn new(header : BlockHeader, sk : &GlpSk, domain : &Domain) -> Result<Self, QanError> {
+        let sig = sign(&sk, domain.payload(&crate::codec::encode(&header).map_err(|e|QanError::Codec(e))?)).unwrap().to_bytes();
+        Ok(SignedHeader{ hash: header.hash()?, header, sig })
+    }
+
+    /// header verification function, checks the hash and the signature in the chain's signing domain
+    #[cfg(feature = "quantum")]
+    pub fn verify(&self, pk : &GlpPk, domain : &Domain) -> Result<bool, QanError> {
+        if self.header.hash()? != self.hash { return Ok(false) }
+        Ok(verify(&pk, &GlpSig::from_bytes(&self.sig), &domain.payload(&crate::codec::encode(&self.header).map_err(|e|QanError::Codec(e))?)))
+    }
+
+    /// header verification function, checks the hash and the signature in the chain's signing domain
+    #[cfg(not(feature = "quantum"))]
+    pub fn verify(&self, pk : &PublicKey, domain : &Domain) -> Result<bool, QanError>{
+        if self.header.hash()? != self.hash { return Ok(false) }
+        let sig = match Signature::from_bytes(&self.sig){
+            Ok(s) => s,
+            Err(_) => return Ok(false)
+        };
+        Ok(match pk.verify(&domain.payload(&crate::codec::encode(&self.header).map_err(|e|QanError::Codec(e))?), &sig){
+            Ok(_)=>true,
+            Err(_)=>false
+        })
     }
+}
+
+/// Block body, the hashes of the included transactions.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct BlockBody {
+    pub txes    : Vec<[u8;32]>,
+}
 
+impl fmt::Display for BlockBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tx_bases : Vec<String> = self.txes.iter().map(|i|encode(&i)).collect();
+        write!(f, "txes : {:?}", tx_bases)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Block {
-    pub hashedblock : HashedBlock,
-    pub proposer_pub: [u8;32],
-    pub sig         : Vec<u8>,
-    pub height      : u64,
+    pub signed  : SignedHeader,
+    pub body    : BlockBody,
 }
 
 impl fmt::Display for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {  
-        write!(f, "hashedblock : {}\nsig : {}\n",
-        self.hashedblock, encode(&self.sig))
+        write!(f, "{}\n{}\n",
+        self.signed, self.body)
     }
 }
 
 
 impl Block{
+    /// Constructor function for Block. Takes the hash of the previous block, the transaction hashes, the proposer's key and the height.
+    /// The state and receipts roots are left empty.
     #[cfg(not(feature = "quantum"))]
-    pub fn new(prev_hash: [u8;32], txes: Vec<[u8;32]>, kp: &Keypair, height : u64) -> Result<Self, QanError> {
-        let hashedblock = HashedBlock::new(prev_hash, txes)?;
-        let sig = kp.sign(&serde_json::to_vec(&hashedblock).map_err(|e|QanError::Serde(e))?).to_bytes().to_vec();
-        let proposer_pub = do_hash(&kp.public.to_bytes().to_vec());
-        Ok(Block{
-            proposer_pub,
-            hashedblock, 
+    pub fn new(prev_hash: [u8;32], txes: Vec<[u8;32]>, kp: &Keypair, domain: &Domain, height : u64) -> Result<Self, QanError> {
+        Block::with_roots(prev_hash, txes, EMPTY_ROOT, EMPTY_ROOT, kp, domain, height)
+    }
+
+    /// Constructor function for Block. Takes the hash of the previous block, the transaction hashes, the proposer's key and the height.
+    /// The state and receipts roots are left empty.
+    #[cfg(feature = "quantum")]
+    pub fn new(prev_hash: [u8;32], txes: Vec<[u8;32]>, sk: &GlpSk, domain: &Domain, height : u64) -> Result<Self, QanError> {
+        Block::with_roots(prev_hash, txes, EMPTY_ROOT, EMPTY_ROOT, sk, domain, height)
+    }
+
+    /// Constructor function for Block committing to the state and receipts after executing it.
+    #[cfg(not(feature = "quantum"))]
+    pub fn with_roots(prev_hash: [u8;32], txes: Vec<[u8;32]>, state_root: [u8;32], receipts_root: [u8;32], kp: &Keypair, domain: &Domain, height : u64) -> Result<Self, QanError> {
+        let header = BlockHeader{
+            prev_hash,
             height,
-            sig
+            timestamp: crate::util::timestamp(),
+            tx_root: tx_root(&txes),
+            state_root,
+            receipts_root,
+            proposer: do_hash(&kp.public.to_bytes().to_vec()),
+        };
+        Ok(Block{
+            signed: SignedHeader::new(header, kp, domain)?,
+            body: BlockBody{ txes }
         })
     }
 
+    /// Constructor function for Block committing to the state and receipts after executing it.
     #[cfg(feature = "quantum")]
-    pub fn new(prev_hash: [u8;32], txes: Vec<[u8;32]>, sk: &GlpSk, height : u64) -> Result<Self, QanError> {
-        let hashedblock = HashedBlock::new(prev_hash, txes)?;
-        let sig = sign(&sk, serde_json::to_vec(&hashedblock).map_err(|e|QanError::Serde(e))?).unwrap().to_bytes();
-        let proposer_pub = do_hash(&gen_pk(&sk).to_bytes().to_vec());
-        Ok(Block{
-            proposer_pub,
-            hashedblock, 
+    pub fn with_roots(prev_hash: [u8;32], txes: Vec<[u8;32]>, state_root: [u8;32], receipts_root: [u8;32], sk: &GlpSk, domain: &Domain, height : u64) -> Result<Self, QanError> {
+        let header = BlockHeader{
+            prev_hash,
             height,
-            sig
+            timestamp: crate::util::timestamp(),
+            tx_root: tx_root(&txes),
+            state_root,
+            receipts_root,
+            proposer: do_hash(&gen_pk(&sk).to_bytes().to_vec()),
+        };
+        Ok(Block{
+            signed: SignedHeader::new(header, sk, domain)?,
+            body: BlockBody{ txes }
+        })
+    }
+
+    /// Constructor function for the genesis block of a chain spec. It has no proposer and no signature,
+    /// so its hash only depends on the transactions, the state root and the timestamp.
+    pub fn genesis(txes: Vec<[u8;32]>, state_root: [u8;32], timestamp: u64) -> Result<Self, QanError> {
+        let header = BlockHeader{
+            prev_hash: [0;32],
+            height: 0,
+            timestamp,
+            tx_root: tx_root(&txes),
+            state_root,
+            receipts_root: EMPTY_ROOT,
+            proposer: [0;32],
+        };
+        Ok(Block{
+            signed: SignedHeader{ hash: header.hash()?, header, sig: Vec::new() },
+            body: BlockBody{ txes }
         })
     }
 
-    /// block verification function
+    /// block verification function, checks the header's hash and signature in the chain's signing domain
     #[cfg(feature = "quantum")]
-    pub fn verify(&self, pk : &GlpPk) -> Result<bool, QanError> {
-        Ok(verify(&pk, &GlpSig::from_bytes(&self.sig), &serde_json::to_vec(&self.hashedblock).map_err(|e|QanError::Serde(e))?))
+    pub fn verify(&self, pk : &GlpPk, domain : &Domain) -> Result<bool, QanError> {
+        self.signed.verify(pk, domain)
     }
 
-    /// block verification function
+    /// block verification function, checks the header's hash and signature in the chain's signing domain
     #[cfg(not(feature = "quantum"))]
-    pub fn verify(&self, pk : &PublicKey) -> Result<bool, QanError>{
-        let sig = Signature::from_bytes(&self.sig).unwrap();
-        Ok(match pk.verify(&serde_json::to_vec(&self.hashedblock).map_err(|e|QanError::Serde(e))?, &sig){
-            Ok(_)=>true,
-            Err(_)=>false
-        })
+    pub fn verify(&self, pk : &PublicKey, domain : &Domain) -> Result<bool, QanError>{
+        self.signed.verify(pk, domain)
+    }
+
+    /// Checks that the body matches the transaction root of the header.
+    pub fn check_body(&self) -> bool {
+        tx_root(&self.body.txes) == self.signed.header.tx_root
     }
 
     /// Block validation function. Checks if this block 
@@ -150,27 +285,52 @@ impl Block{
     ///  hat a height higher than the parameter,
     ///  is built on the same block determined in the parameter  
     pub fn validate(&self, timestamp: u64, height: u64, prev_hash: [u8;32]) -> (bool, bool, bool){
-        ( timestamp < self.timestamp(), height < self.height, prev_hash == self.hashedblock.blockdata.prev_hash )
+        ( timestamp < self.timestamp(), height < self.height(), prev_hash == self.prev_hash() )
     }
 
     /// getter for block hash
     pub fn hash(&self)->[u8;32]{
-        self.hashedblock.hash
+        self.signed.hash
+    }
+
+    /// getter for the signed header
+    pub fn header(&self) -> &SignedHeader {
+        &self.signed
+    }
+
+    /// getter for block height
+    pub fn height(&self) -> u64 {
+        self.signed.header.height
+    }
+
+    /// getter for the public key hash of the proposer
+    pub fn proposer(&self) -> [u8;32] {
+        self.signed.header.proposer
     }
 
     /// getter for block merkle root
-    pub fn merkle(&self)->Vec<u8>{
-        self.hashedblock.blockdata.merkle_root.clone()
+    pub fn merkle(&self)->[u8;32]{
+        self.signed.header.tx_root
     }
 
     /// getter for previous block hash
     pub fn prev_hash(&self) -> [u8;32]{
-        self.hashedblock.blockdata.prev_hash
+        self.signed.header.prev_hash
     }
 
     /// getter for block timestamp
     pub fn timestamp(&self)->u64{
-        self.hashedblock.timestamp()
+        self.signed.header.timestamp
+    }
+
+    /// getter for the transaction hashes of the body
+    pub fn txes(&self) -> &Vec<[u8;32]> {
+        &self.body.txes
+    }
+
+    /// Inclusion proof of the transaction `txh` against the transaction root, `None` if the block doesn't hold it.
+    pub fn prove(&self, txh : &[u8;32]) -> Option<MerkleProof> {
+        prove(&self.body.txes, self.body.txes.iter().position(|t|t == txh)?)
     }
 }
 
@@ -182,4 +342,24 @@ fn merk() {
     let root : Vec<u8> = tree.get_root_hash().unwrap().to_vec();
     let ret = [85, 83, 19, 65, 189, 78, 73, 18, 202, 219, 205, 133, 143, 168, 181, 5, 137, 77, 197, 123, 49, 124, 243, 20, 206, 207, 161, 3, 90, 131, 240, 91]; 
     assert_eq!(root,ret.to_vec());
-}
\ No newline at end of file
+}
+#[test]
+fn inclusion_proof() {
+    let txes : Vec<[u8;32]> = (0..5u8).map(|i|[i;32]).collect();
+    for n in 1..=txes.len() {
+        let root = tx_root(&txes[..n]);
+        assert_eq!(&root, static_merkle_tree::Tree::from_hashes(txes[..n].iter().map(leaf_hash).collect(), node_hash).get_root_hash().unwrap());
+        for i in 0..n {
+            let proof = prove(&txes[..n], i).unwrap();
+            assert!(verify_proof(&txes[i], &proof, &root));
+            assert!(!verify_proof(&[9;32], &proof, &root));
+        }
+    }
+    let mut proof = prove(&txes, 1).unwrap();
+    proof.index = 2;
+    assert!(!verify_proof(&txes[1], &proof, &tx_root(&txes)));
+    assert!(prove(&txes, 5).is_none());
+    let nodes = tree_nodes(&txes[..4]);
+    let inner = MerkleProof{ index: 0, leaves: 2, siblings: vec![nodes[2]] };
+    assert!(!verify_proof(&nodes[1], &inner, &tx_root(&txes[..4])));
+}
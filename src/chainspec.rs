@@ -0,0 +1,173 @@
+//! Chain spec file. It defines everything the genesis block commits to: the chain id, the prefix of its addresses, the genesis timestamp,
+//! the authorities, the initial balances and the consensus settings. The addresses in the spec are written with the chain's own prefix. The genesis transaction and block built from it are unsigned,
+//! so every node reading the same spec gets the same genesis hash without exchanging the `genesis` file.
+use std::io::Read;
+use rocksdb::DB;
+use crate::address::Address;
+use crate::block::Block;
+use crate::conset::ConsensusSettings;
+use crate::error::QanError;
+use crate::nemezis::NemezisData;
+use crate::transaction::{Transaction, TxBody};
+
+const CHAIN_ID_KEY : &str = "chain_id";
+
+/// Signing domain of a chain, the hash of its id and signature scheme. Every signed payload, transactions, block headers
+/// and finality votes, starts with it, so signatures of one chain don't verify on another, nor on a network of the other scheme.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Domain(pub [u8;32]);
+
+impl Domain {
+    pub fn new(chain_id : &str) -> Domain {
+        #[cfg(not(feature = "quantum"))]
+        let scheme = "ed25519";
+        #[cfg(feature = "quantum")]
+        let scheme = "glp";
+        Domain(crate::util::do_hash(&format!("qan/{}/{}", scheme, chain_id).into_bytes()))
+    }
+
+    /// The bytes to sign for `message`.
+    pub fn payload(&self, message : &[u8]) -> Vec<u8> {
+        let mut buf = self.0.to_vec();
+        buf.extend_from_slice(message);
+        buf
+    }
+
+    /// Persists the chain id of the genesis.
+    pub fn store_chain_id(db : &DB, chain_id : &str) -> Result<(), QanError> {
+        db.put(CHAIN_ID_KEY, chain_id).map_err(|e|QanError::Database(e))?;
+        db.flush().map_err(|e|QanError::Database(e))
+    }
+
+    /// The persisted chain id, empty if the chain wasn't initialized yet.
+    pub fn load_chain_id(db : &DB) -> Result<String, QanError> {
+        Ok(db.get(CHAIN_ID_KEY).map_err(|e|QanError::Database(e))?.map_or(String::new(), |id|String::from_utf8_lossy(&id).to_string()))
+    }
+
+    /// Domain of the persisted chain id. Fails without a chain id, chains without one would share their domain.
+    pub fn load(db : &DB) -> Result<Domain, QanError> {
+        let chain_id = Domain::load_chain_id(db)?;
+        if chain_id.is_empty() { return Err(QanError::Internal("the chain has no chain id".to_string())) }
+        Ok(Domain::new(&chain_id))
+    }
+}
+
+/// Initial balance of an account.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GenesisBalance {
+    pub address : String,
+    pub balance : u64,
+}
+
+/// Parameters of a chain, read from a TOML file. Without an `address_prefix` the chain id is the prefix of the addresses.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ChainSpec {
+    pub chain_id            : String,
+    #[serde(default)]
+    pub address_prefix      : String,
+    pub genesis_timestamp   : u64,
+    pub authorities         : Vec<String>,
+    #[serde(default)]
+    pub balances            : Vec<GenesisBalance>,
+    #[serde(default = "ConsensusSettings::default")]
+    pub consensus           : ConsensusSettings,
+}
+
+impl ChainSpec {
+    pub fn from_string(s : &str) -> Result<Self, QanError> {
+        toml::from_str(s).map_err(|e|QanError::Internal(format!("invalid chain spec: {}", e)))
+    }
+
+    /// Loads the chain spec at `path`, `None` if there is no such file.
+    pub fn load(path : &str) -> Result<Option<Self>, QanError> {
+        if !std::path::Path::new(path).exists() { return Ok(None) }
+        let mut buf = String::new();
+        std::fs::File::open(path).map_err(|e|QanError::Io(e))?.read_to_string(&mut buf).map_err(|e|QanError::Io(e))?;
+        Ok(Some(ChainSpec::from_string(&buf)?))
+    }
+
+    /// Content of the genesis transaction. Authorities without a balance in the spec get `GENESIS_BALANCE`.
+    pub fn nemezis_data(&self) -> Result<NemezisData, QanError> {
+        if self.authorities.is_empty() {
+            return Err(QanError::Internal("chain spec defines no authorities".to_string()))
+        }
+        if self.chain_id.is_empty() {
+            return Err(QanError::Internal("chain spec defines no chain id".to_string()))
+        }
+        let hrp = crate::address::hrp_of(&self.chain_id, &self.address_prefix)?;
+        let mut authorities = Vec::new();
+        for a in &self.authorities {
+            authorities.push(Address::decode(a, &hrp)?.0);
+        }
+        let mut balances : Vec<([u8;32], u64)> = Vec::new();
+        for b in &self.balances {
+            balances.push((Address::decode(&b.address, &hrp)?.0, b.balance));
+        }
+        for a in &authorities {
+            if !balances.iter().any(|(id, _)|id == a) {
+                balances.push((*a, crate::state::GENESIS_BALANCE));
+            }
+        }
+        Ok(NemezisData{
+            authorities,
+            consensus: self.consensus.clone(),
+            balances,
+            chain_id: self.chain_id.clone(),
+            address_prefix: self.address_prefix.clone(),
+        })
+    }
+
+    /// Builds the genesis block and its transaction.
+    pub fn genesis(&self) -> Result<(Block, Transaction), QanError> {
+        let nemezis_data = self.nemezis_data()?;
+        let mut body = TxBody::new([0;32], 0, 0, serde_json::to_vec(&nemezis_data).map_err(|e|QanError::Serde(e))?);
+        body.timestamp = self.genesis_timestamp;
+        let tx = Transaction{ transaction: body, pubkey: [0;32], sig: Vec::new() };
+        let state_root = crate::state::genesis_root(&nemezis_data.balances)?;
+        Ok((Block::genesis(vec![tx.hash()?], state_root, self.genesis_timestamp)?, tx))
+    }
+}
+
+#[test]
+fn deterministic_genesis() {
+    let spec = ChainSpec::from_string(r#"
+chain_id = "qan-test"
+address_prefix = "qan"
+genesis_timestamp = 1600000000000
+authorities = ["qan1qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqyc30v0"]
+
+[[balances]]
+address = "qan1qqqsyqcyq5rqwzqfpg9scrgwpugpzysnzs23v9ccrydpk8qarc0sdv6dvk"
+balance = 500
+"#).unwrap();
+    let (a, tx) = spec.genesis().unwrap();
+    let (b, _) = spec.genesis().unwrap();
+    assert_eq!(a.hash(), b.hash());
+    assert_eq!(a.body.txes, vec![tx.hash().unwrap()]);
+    assert_eq!(spec.nemezis_data().unwrap().balances.len(), 2);
+    let mut other = spec.clone();
+    other.chain_id = "qan-other".to_string();
+    assert_ne!(other.genesis().unwrap().0.hash(), a.hash());
+    other.chain_id = String::new();
+    assert!(other.genesis().is_err());
+    //without a prefix the addresses have to carry the chain id
+    other.chain_id = "qan-test".to_string();
+    other.address_prefix = String::new();
+    assert!(other.genesis().is_err());
+    other.authorities = vec![Address([0;32]).encode("qan-test")];
+    other.balances.clear();
+    assert_eq!(other.nemezis_data().unwrap().authorities, vec![[0;32]]);
+}
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn domain_separation() {
+    let kp = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng);
+    let (devnet, testnet) = (Domain::new("devnet"), Domain::new("testnet"));
+    let tx = Transaction::new(TxBody::new([1;32], 0, 5, vec![]), &kp, &devnet).unwrap();
+    assert!(tx.verify(&kp.public, &devnet).unwrap());
+    assert!(!tx.verify(&kp.public, &testnet).unwrap());
+    let block = Block::new([0;32], vec![tx.hash().unwrap()], &kp, &devnet, 1).unwrap();
+    assert!(block.verify(&kp.public, &devnet).unwrap());
+    assert!(!block.verify(&kp.public, &testnet).unwrap());
+}
@@ -0,0 +1,268 @@
+//! Canonical binary encoding of the consensus objects, the bytes their hashes and signatures are computed over.
+//! An encoding starts with the `CODEC_VERSION` byte, followed by the value:
+//! - `bool` is one byte, 0 or 1, integers are fixed width big-endian, floats their IEEE 754 bits, `char` a `u32`
+//! - strings, byte strings and sequences are a `u32` length followed by the bytes or elements, so `Vec<u8>` is a length and the raw bytes
+//! - arrays, tuples and struct fields follow each other without lengths or names, in declaration order, so `[u8;32]` is its 32 bytes
+//! - `None` is 0, `Some` is 1 followed by the value, enum variants are their `u32` index followed by their content
+//! Maps are rejected, their iteration order is not deterministic.
+//! The wire and database formats stay JSON. Databases written with an older encoding are moved aside by `upgrade_databases`.
+use std::fmt;
+use std::path::Path;
+use serde::ser::{self, Serialize};
+use rocksdb::DB;
+use crate::error::QanError;
+
+/// Version of the encoding. Version 0 was the JSON encoding of the objects.
+pub const CODEC_VERSION : u8 = 1;
+/// Key of the encoding version of the chain in the block database.
+const VERSION_KEY : &str = "codec";
+
+/// Reason a value has no canonical encoding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error(pub String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg : T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+/// Canonical encoding of `value`, starting with the version byte.
+pub fn encode<T : Serialize + ?Sized>(value : &T) -> Result<Vec<u8>, Error> {
+    let mut encoder = Encoder{ buf: vec![CODEC_VERSION] };
+    value.serialize(&mut encoder)?;
+    Ok(encoder.buf)
+}
+
+struct Encoder {
+    buf : Vec<u8>,
+}
+
+impl Encoder {
+    fn length(&mut self, len : Option<usize>) -> Result<(), Error> {
+        match len {
+            Some(l) if l <= u32::max_value() as usize => { self.buf.extend_from_slice(&(l as u32).to_be_bytes()); Ok(()) },
+            Some(l) => Err(Error(format!("length {} doesn't fit 32 bits", l))),
+            None => Err(Error("sequence of unknown length".to_string()))
+        }
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Encoder {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = ser::Impossible<(), Error>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v : bool) -> Result<(), Error> { self.buf.push(v as u8); Ok(()) }
+    fn serialize_i8(self, v : i8) -> Result<(), Error> { self.buf.extend_from_slice(&v.to_be_bytes()); Ok(()) }
+    fn serialize_i16(self, v : i16) -> Result<(), Error> { self.buf.extend_from_slice(&v.to_be_bytes()); Ok(()) }
+    fn serialize_i32(self, v : i32) -> Result<(), Error> { self.buf.extend_from_slice(&v.to_be_bytes()); Ok(()) }
+    fn serialize_i64(self, v : i64) -> Result<(), Error> { self.buf.extend_from_slice(&v.to_be_bytes()); Ok(()) }
+    fn serialize_u8(self, v : u8) -> Result<(), Error> { self.buf.push(v); Ok(()) }
+    fn serialize_u16(self, v : u16) -> Result<(), Error> { self.buf.extend_from_slice(&v.to_be_bytes()); Ok(()) }
+    fn serialize_u32(self, v : u32) -> Result<(), Error> { self.buf.extend_from_slice(&v.to_be_bytes()); Ok(()) }
+    fn serialize_u64(self, v : u64) -> Result<(), Error> { self.buf.extend_from_slice(&v.to_be_bytes()); Ok(()) }
+    fn serialize_f32(self, v : f32) -> Result<(), Error> { self.serialize_u32(v.to_bits()) }
+    fn serialize_f64(self, v : f64) -> Result<(), Error> { self.serialize_u64(v.to_bits()) }
+    fn serialize_char(self, v : char) -> Result<(), Error> { self.serialize_u32(v as u32) }
+    fn serialize_str(self, v : &str) -> Result<(), Error> { self.serialize_bytes(v.as_bytes()) }
+
+    fn serialize_bytes(self, v : &[u8]) -> Result<(), Error> {
+        self.length(Some(v.len()))?;
+        self.buf.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> { self.buf.push(0); Ok(()) }
+
+    fn serialize_some<T : Serialize + ?Sized>(self, value : &T) -> Result<(), Error> {
+        self.buf.push(1);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> { Ok(()) }
+    fn serialize_unit_struct(self, _name : &'static str) -> Result<(), Error> { Ok(()) }
+
+    fn serialize_unit_variant(self, _name : &'static str, index : u32, _variant : &'static str) -> Result<(), Error> {
+        self.serialize_u32(index)
+    }
+
+    fn serialize_newtype_struct<T : Serialize + ?Sized>(self, _name : &'static str, value : &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T : Serialize + ?Sized>(self, _name : &'static str, index : u32, _variant : &'static str, value : &T) -> Result<(), Error> {
+        self.serialize_u32(index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len : Option<usize>) -> Result<Self, Error> {
+        self.length(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len : usize) -> Result<Self, Error> { Ok(self) }
+    fn serialize_tuple_struct(self, _name : &'static str, _len : usize) -> Result<Self, Error> { Ok(self) }
+
+    fn serialize_tuple_variant(self, _name : &'static str, index : u32, _variant : &'static str, _len : usize) -> Result<Self, Error> {
+        self.serialize_u32(index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len : Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(Error("maps have no canonical encoding".to_string()))
+    }
+
+    fn serialize_struct(self, _name : &'static str, _len : usize) -> Result<Self, Error> { Ok(self) }
+
+    fn serialize_struct_variant(self, _name : &'static str, index : u32, _variant : &'static str, _len : usize) -> Result<Self, Error> {
+        self.serialize_u32(index)?;
+        Ok(self)
+    }
+}
+
+impl<'a> ser::SerializeSeq for &'a mut Encoder {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_element<T : Serialize + ?Sized>(&mut self, value : &T) -> Result<(), Error> { value.serialize(&mut **self) }
+    fn end(self) -> Result<(), Error> { Ok(()) }
+}
+
+impl<'a> ser::SerializeTuple for &'a mut Encoder {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_element<T : Serialize + ?Sized>(&mut self, value : &T) -> Result<(), Error> { value.serialize(&mut **self) }
+    fn end(self) -> Result<(), Error> { Ok(()) }
+}
+
+impl<'a> ser::SerializeTupleStruct for &'a mut Encoder {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_field<T : Serialize + ?Sized>(&mut self, value : &T) -> Result<(), Error> { value.serialize(&mut **self) }
+    fn end(self) -> Result<(), Error> { Ok(()) }
+}
+
+impl<'a> ser::SerializeTupleVariant for &'a mut Encoder {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_field<T : Serialize + ?Sized>(&mut self, value : &T) -> Result<(), Error> { value.serialize(&mut **self) }
+    fn end(self) -> Result<(), Error> { Ok(()) }
+}
+
+impl<'a> ser::SerializeStruct for &'a mut Encoder {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_field<T : Serialize + ?Sized>(&mut self, _key : &'static str, value : &T) -> Result<(), Error> { value.serialize(&mut **self) }
+    fn end(self) -> Result<(), Error> { Ok(()) }
+}
+
+impl<'a> ser::SerializeStructVariant for &'a mut Encoder {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_field<T : Serialize + ?Sized>(&mut self, _key : &'static str, value : &T) -> Result<(), Error> { value.serialize(&mut **self) }
+    fn end(self) -> Result<(), Error> { Ok(()) }
+}
+
+/// Records that the chain in `blockdb` is hashed and signed with the current encoding.
+pub fn store_version(blockdb : &DB) -> Result<(), QanError> {
+    blockdb.put(VERSION_KEY, [CODEC_VERSION]).map_err(|e|QanError::Database(e))
+}
+
+/// Encoding version of the chain in `blockdb`. Chains from before the versioning have version 0.
+pub fn load_version(blockdb : &DB) -> Result<u8, QanError> {
+    Ok(blockdb.get(VERSION_KEY).map_err(|e|QanError::Database(e))?.and_then(|v|v.get(0).cloned()).unwrap_or(0))
+}
+
+/// Upgrade path of the databases of an older encoding. Their hashes and signatures can't be recomputed, so when the chain in `blockdb`
+/// was written with another version, it and the databases derived from the chain, `derived`, are renamed with a `.v<version>` suffix,
+/// and the node starts over from the genesis. Any stored block or chain height makes a chain, chains from before the versioning are version 0.
+/// A `genesis` file whose block hash doesn't match its header in this encoding is stale too, and gets a `.stale` suffix.
+/// The public key database stays, keys don't depend on the encoding. Returns whether anything was moved.
+pub fn upgrade_databases(blockdb : &str, derived : &[&str], genesis : &str) -> Result<bool, QanError> {
+    let mut moved = false;
+    if Path::new(genesis).exists() {
+        //a genesis that doesn't parse as a block was written before the header split
+        let bytes = std::fs::read(genesis).map_err(|e|QanError::Io(e))?;
+        let stale = match serde_json::from_slice::<crate::block::Block>(&bytes) {
+            Ok(b) => b.signed.header.hash()? != b.hash(),
+            Err(_) => true
+        };
+        if stale {
+            let to = format!("{}.stale", genesis);
+            std::fs::rename(genesis, &to).map_err(|e|QanError::Io(e))?;
+            warn!("moved genesis file {} of an older encoding to {}", genesis, to);
+            moved = true;
+        }
+    }
+    if !Path::new(blockdb).exists() { return Ok(moved) }
+    let version = {
+        let db = DB::open_default(blockdb).map_err(|e|QanError::Database(e))?;
+        let has_chain = db.get("height").map_err(|e|QanError::Database(e))?.is_some()
+            || db.get("block0").map_err(|e|QanError::Database(e))?.is_some();
+        if !has_chain { return Ok(moved) }
+        load_version(&db)?
+    };
+    if version == CODEC_VERSION { return Ok(moved) }
+    for path in std::iter::once(&blockdb).chain(derived.iter()) {
+        if !Path::new(path).exists() { continue }
+        let to = format!("{}.v{}", path, version);
+        std::fs::rename(path, &to).map_err(|e|QanError::Io(e))?;
+        warn!("moved {} of encoding version {} to {}", path, version, to);
+    }
+    Ok(true)
+}
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn golden_vectors() {
+    let mut body = crate::transaction::TxBody::new([7;32], 1, 258, vec![0xab, 0xcd]).with_spice_price(3);
+    body.timestamp = 0x0102030405060708;
+    body.spice_limit = 1020;
+    let mut expected = vec![CODEC_VERSION];
+    expected.extend_from_slice(&[0,0,0,0,0,0,0,1]);
+    expected.extend_from_slice(&[1,2,3,4,5,6,7,8]);
+    expected.extend_from_slice(&[7;32]);
+    expected.extend_from_slice(&[0,0,0,0,0,0,1,2]);
+    expected.extend_from_slice(&[0,0,0,0,0,0,0,3]);
+    expected.extend_from_slice(&[0,0,0,0,0,0,3,0xfc]);
+    expected.extend_from_slice(&[0,0,0,2,0xab,0xcd]);
+    assert_eq!(encode(&body).unwrap(), expected);
+    assert_eq!(hex::encode(body.hash().unwrap()), "3ad1a075720764b1067456ac337bce9cee485b010abb9c34226ffb801bde2371");
+    let header = crate::block::BlockHeader{ prev_hash: [1;32], height: 2, timestamp: 3, tx_root: [4;32], state_root: [5;32], receipts_root: [6;32], proposer: [8;32] };
+    let encoded = encode(&header).unwrap();
+    assert_eq!(encoded.len(), 1 + 32*5 + 8*2);
+    assert_eq!(&encoded[33..41], &[0,0,0,0,0,0,0,2]);
+    assert_eq!(hex::encode(header.hash().unwrap()), "8d9414eb7f1592c3af4b84c943d3eea02cba0e856cd04732eddd54d9ae8eb3c7");
+    assert_eq!(encode(&Some(crate::vm::VMReturn::U32(5))).unwrap(), vec![CODEC_VERSION, 1, 0,0,0,2, 0,0,0,5]);
+    let mut map = std::collections::HashMap::new();
+    map.insert(1u8, 2u8);
+    assert!(encode(&map).is_err());
+}
+
+#[test]
+fn moves_old_genesis() {
+    let dir = std::env::temp_dir().join(format!("qan_upgrade_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let genesis = dir.join("genesis");
+    let old = r#"{"hashedblock":{"blockdata":{"timestamp":1,"merkle_root":[],"prev_hash":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],"txes":[]},"hash":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1]},"proposer_pub":[2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2],"sig":[],"height":0}"#;
+    std::fs::write(&genesis, old).unwrap();
+    let blockdb = dir.join("db.db");
+    assert!(upgrade_databases(blockdb.to_str().unwrap(), &[], genesis.to_str().unwrap()).unwrap());
+    assert!(!genesis.exists());
+    assert_eq!(std::fs::read_to_string(dir.join("genesis.stale")).unwrap(), old);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
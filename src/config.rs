@@ -15,6 +15,30 @@ pub struct Config{
     pub rpc_pass    : String,
     pub rpc_auth    : String,
     pub bootstrap   : Vec<String>,
+    #[serde(default)]
+    pub authorities : Vec<String>,
+    #[serde(default = "default_slot_duration")]
+    pub slot_duration : u64,
+    #[serde(default)]
+    pub raft        : bool,
+    #[serde(default = "default_spice_price")]
+    pub spice_price : u64,
+    #[serde(default = "default_chain_spec")]
+    pub chain_spec  : String,
+    #[serde(default)]
+    pub mempool     : crate::mempool::MempoolLimits,
+}
+
+fn default_slot_duration() -> u64 {
+    crate::conset::ConsensusSettings::default().slot_duration()
+}
+
+fn default_spice_price() -> u64 {
+    1
+}
+
+fn default_chain_spec() -> String {
+    "chainspec.toml".into()
 }
 
 impl std::default::Default for Config{
@@ -28,6 +52,12 @@ impl std::default::Default for Config{
             rpc_auth    : "Basic dW5leHBlY3RlZDpwYWNhbA==".into(),
             bootstrap   : vec!("127.0.0.1:4222".into()),
             logging     : "".to_string(),
+            authorities : Vec::new(),
+            slot_duration : default_slot_duration(),
+            raft        : false,
+            spice_price : default_spice_price(),
+            chain_spec  : default_chain_spec(),
+            mempool     : crate::mempool::MempoolLimits::default(),
         }
     }
 }
@@ -41,6 +71,17 @@ impl Config{
         Ok(toml::to_string(&self).unwrap())
     }
 
+    /// Parses the hex encoded public key hashes that are put into a newly generated genesis as authorities.
+    pub fn genesis_authorities( &self ) -> Result<Vec<[u8;32]>, QanError> {
+        let mut ret = Vec::new();
+        for a in &self.authorities {
+            let h = hex::decode(a).map_err(|_|QanError::Internal(format!("invalid authority in config: {}", a)))?;
+            if h.len() != 32 { return Err(QanError::Internal(format!("invalid authority in config: {}", a))) }
+            ret.push(crate::util::vec_to_arr(&h));
+        }
+        Ok(ret)
+    }
+
     pub fn get_config() -> Result<(Self, log4rs::Handle), QanError> {
         let mut config = if Path::new("./config.toml").exists(){
             let mut buf = String::new();
@@ -85,6 +126,11 @@ impl Config{
                 .takes_value(true)
                 .short("l")
                 .long("logging"),
+            Arg::with_name("chain")
+                .help("chain spec file the genesis block is built from")
+                .takes_value(true)
+                .short("c")
+                .long("chain"),
         ]).get_matches();
 
     
@@ -104,6 +150,7 @@ impl Config{
         if let Some(p) = matches.value_of("rpc-pwd") { config.rpc_port = p.parse::<u16>().expect("invalid port") }
         if let Some(s) = matches.value_of("spv") { config.spv =  s.parse::<u64>().expect("invalid sync depth") }
         if let Some(l) = matches.value_of("logging") { config.logging = l.into() }
+        if let Some(c) = matches.value_of("chain") { config.chain_spec = c.into() }
 
         let log_handle = crate::util::init_logging(&config.logging);
 
@@ -1,26 +1,129 @@
+use std::collections::HashMap;
+use crate::transaction::Transaction;
+use crate::mempool::Mempool;
+use crate::error::QanError;
+
 /// Demo version consensus setting.
 /// Block is generated if minimum transaction count, 
 /// and minimum pool size is achieved after a minimum time passed since the last block.
+/// Proposers take turns in slots of `slot_duration` milliseconds.
+/// Authority set changes take effect at multiples of `epoch_length` blocks.
+/// A block holds at most `max_tx` transactions of at most `max_bytes` bytes together.
 #[derive(Debug, PartialEq, Deserialize, Serialize, Eq, Hash, Clone)]
 pub struct ConsensusSettings {
-    min_tx      :   usize,
-    min_size    :   usize,
-    min_time    :   u64,
+    min_tx          :   usize,
+    min_size        :   usize,
+    min_time        :   u64,
+    slot_duration   :   u64,
+    #[serde(default = "default_epoch_length")]
+    epoch_length    :   u64,
+    #[serde(default = "default_max_tx")]
+    max_tx          :   usize,
+    #[serde(default = "default_max_bytes")]
+    max_bytes       :   usize,
+}
+
+fn default_epoch_length() -> u64 {
+    100
+}
+
+fn default_max_tx() -> usize {
+    4096
+}
+
+fn default_max_bytes() -> usize {
+    4 * 1024 * 1024
 }
 
 impl ConsensusSettings {
-    pub fn new( min_tx : usize , min_size : usize , min_time : u64 ) -> Self {
-        ConsensusSettings{ min_tx , min_size , min_time }
+    pub fn new( min_tx : usize , min_size : usize , min_time : u64 , slot_duration : u64 ) -> Self {
+        ConsensusSettings{ min_tx , min_size , min_time , slot_duration ,
+            epoch_length : default_epoch_length(), max_tx : default_max_tx(), max_bytes : default_max_bytes() }
     }
 
     pub fn default() -> Self{
         ConsensusSettings{
-            min_tx      :   10,
-            min_size    :   1000,
-            min_time    :   10000,
+            min_tx          :   10,
+            min_size        :   1000,
+            min_time        :   10000,
+            slot_duration   :   5000,
+            epoch_length    :   default_epoch_length(),
+            max_tx          :   default_max_tx(),
+            max_bytes       :   default_max_bytes(),
         }
     }
 
+    /// getter for the length of a proposer slot in milliseconds
+    pub fn slot_duration(&self) -> u64 {
+        self.slot_duration
+    }
+
+    /// setter for the length of a proposer slot in milliseconds
+    pub fn set_slot_duration(&mut self, slot_duration : u64) {
+        self.slot_duration = slot_duration;
+    }
+
+    /// getter for the number of blocks between authority set changes
+    pub fn epoch_length(&self) -> u64 {
+        self.epoch_length
+    }
+
+    /// setter for the number of blocks between authority set changes
+    pub fn set_epoch_length(&mut self, epoch_length : u64) {
+        self.epoch_length = epoch_length;
+    }
+
+    /// setter for the block capacity, the maximum transaction count and total transaction size in bytes
+    pub fn set_capacity(&mut self, max_tx : usize, max_bytes : usize) {
+        self.max_tx = max_tx;
+        self.max_bytes = max_bytes;
+    }
+
+    /// Checks whether a block of `tx_count` transactions of `bytes` size together fits the block capacity.
+    pub fn within_capacity(&self, tx_count : usize, bytes : usize) -> bool {
+        tx_count <= self.max_tx && bytes <= self.max_bytes
+    }
+
+    /// Chooses the transactions of the next block from the mempool. Every sender's transactions are taken in nonce order from
+    /// its next nonce on chain, returned by `next_nonce`, and of the senders' next transactions the one first in `mempool::by_priority` order,
+    /// until `max_tx` is reached. A sender is skipped from a nonce gap on, and a transaction that would overflow `max_bytes`
+    /// is skipped with the rest of its sender's. The returned hashes are in the order the block holds them.
+    pub fn select_transactions<F>(&self, mempool : &Mempool, mut next_nonce : F) -> Result<Vec<[u8;32]>, QanError>
+        where F : FnMut(&[u8;32]) -> Result<u64, QanError> {
+        let mut queues : HashMap<[u8;32], (u64, Vec<(&[u8;32], &Transaction)>)> = HashMap::new();
+        for (k, tx) in mempool.iter() {
+            if !queues.contains_key(&tx.pubkey) {
+                queues.insert(tx.pubkey, (next_nonce(&tx.pubkey)?, Vec::new()));
+            }
+            queues.get_mut(&tx.pubkey).map(|q|q.1.push((k, tx)));
+        }
+        //the next transaction of a sender is the last of its queue
+        for (_, q) in queues.values_mut() {
+            q.sort_by(|a, b| b.1.transaction.nonce.cmp(&a.1.transaction.nonce));
+        }
+        let mut bytes = 0;
+        let mut selected = Vec::new();
+        while selected.len() < self.max_tx {
+            for (nonce, q) in queues.values_mut() {
+                while q.last().map_or(false, |n|n.1.transaction.nonce < *nonce) { q.pop(); }
+            }
+            queues.retain(|_, (nonce, q)|q.last().map_or(false, |n|n.1.transaction.nonce == *nonce));
+            let next = queues.iter().filter_map(|(s, (_, q))|q.last().map(|n|(*s, *n))).min_by(|a, b| crate::mempool::by_priority(a.1, b.1));
+            let (sender, (k, tx)) = match next {
+                Some(n) => n,
+                None => break
+            };
+            if bytes + tx.len() > self.max_bytes {
+                queues.remove(&sender);
+                continue
+            }
+            bytes += tx.len();
+            selected.push(*k);
+            queues.get_mut(&sender).map(|(nonce, q)|{ q.pop(); *nonce += 1 });
+        }
+        Ok(selected)
+    }
+
     pub fn check_limiters(&self, tx_count : usize, pool_size : usize , prev_time : u64 )-> bool {
         if  (self.min_tx  <= tx_count) && 
             (self.min_size < pool_size) && 
@@ -31,4 +134,30 @@ impl ConsensusSettings {
         }
         false
     }
-}
\ No newline at end of file
+}
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn capacity_by_priority() {
+    let (kp, other) = (ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng), ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng));
+    let mut mempool = Mempool::new(crate::mempool::MempoolLimits::default());
+    let mut add = |kp : &ed25519_dalek::Keypair, nonce, spice_price| {
+        let body = crate::transaction::TxBody::new([0;32], nonce, 0, vec![0;10]).with_spice_price(spice_price);
+        let tx = Transaction::new(body, kp, &crate::chainspec::Domain::new("test")).unwrap();
+        let h = tx.hash().unwrap();
+        mempool.insert(h, tx);
+        h
+    };
+    //the cheap first transaction of a sender still goes before its expensive second one
+    let (a1, a0, b0) = (add(&kp, 1, 5), add(&kp, 0, 1), add(&other, 0, 3));
+    //a sender's transactions after a nonce gap wait
+    add(&other, 2, 9);
+    let mut settings = ConsensusSettings::default();
+    assert_eq!(settings.select_transactions(&mempool, |_|Ok(0)).unwrap(), vec![b0, a0, a1]);
+    assert_eq!(settings.select_transactions(&mempool, |_|Ok(1)).unwrap(), vec![a1]);
+    settings.set_capacity(2, 1000);
+    assert_eq!(settings.select_transactions(&mempool, |_|Ok(0)).unwrap(), vec![b0, a0]);
+    settings.set_capacity(10, mempool[&a0].len());
+    assert_eq!(settings.select_transactions(&mempool, |_|Ok(0)).unwrap(), vec![b0]);
+    assert!(!settings.within_capacity(2, mempool[&a0].len() + 1));
+}
@@ -12,6 +12,8 @@ pub enum QanError{
     Serde(serde_json::Error),
     // Crypto(ed25519_dalek::SignatureError),
     Database(rocksdb::Error),
+    Address(String),
+    Codec(crate::codec::Error),
     Internal(String)
 }
 
@@ -25,6 +27,8 @@ impl std::fmt::Display for QanError {
             QanError::Serde(ref err) => write!(f, "Serde error: {}", err),
             // QanError::Crypto(ref err) => write!(f, "Crypto error: {}", err),
             QanError::Database(ref err) => write!(f, "Database error: {}", err),
+            QanError::Address(ref err) => write!(f, "Invalid address: {}", err),
+            QanError::Codec(ref err) => write!(f, "Encoding error: {}", err),
             QanError::Internal(ref err)  => write!(f, "Internal error: {}", err),
         }
     }
@@ -40,6 +44,8 @@ impl std::error::Error for QanError {
             QanError::Serde(ref err) => Some(err),
             // QanError::Crypto(ref err) => Some(err),
             QanError::Database(ref err) => Some(err),
+            QanError::Address(_) => None,
+            QanError::Codec(ref err) => Some(err),
             QanError::Internal(_) => None
         }
     }
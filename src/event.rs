@@ -23,6 +23,12 @@ pub enum Event {
     GetHeight(std::sync::mpsc::SyncSender<u64>),
     VmBuild(std::string::String, std::sync::mpsc::SyncSender<String>),
     GetTx([u8;32], std::sync::mpsc::SyncSender<Vec<u8>>),
+    Tick,
+    Raft(Vec<u8>),
+    Vote(Vec<u8>),
+    SystemCall(crate::governance::SystemCall),
+    Evidence(Vec<u8>),
+    Transfer([u8;32], u64),
 }
 
 ///Enum for cross network sync transmissions.
@@ -35,4 +41,5 @@ pub enum SyncType {
     BlockHash([u8;32]),
     TransactionAtHash([u8;32]),
     BlockAtHash([u8;32]),
+    HeaderAtHash([u8;32]),
 }
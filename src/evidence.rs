@@ -0,0 +1,186 @@
+//! Equivocation evidence. A proposer signing two different blocks at the same height on the same parent is caught by the first node seeing both.
+//! Blocks at the same height on different parents aren't evidence, an honest proposer builds on the new branch after a reorg.
+//! An authority signing finality votes for two different blocks at the same height is caught the same way by the `VoteTracker`.
+//! The evidence is stored, gossiped on `EVIDENCE_SUBJECT`, and reported on chain by the authorities in a system transaction,
+//! which removes the offender from the authority set at the next epoch boundary.
+use rocksdb::DB;
+use crate::block::Block;
+use crate::finality::Vote;
+use crate::error::QanError;
+use crate::util::do_hash;
+use crate::chainspec::Domain;
+use crate::transaction::{Transaction, TxBody};
+#[cfg(not(feature = "quantum"))]
+use ed25519_dalek::{Keypair, PublicKey};
+#[cfg(feature = "quantum")]
+use glp::glp::{GlpPk, GlpSk};
+
+/// NATS subject evidence is gossiped on.
+pub const EVIDENCE_SUBJECT : &'static str = "block.evidence";
+/// Key of the stored evidence in the block database.
+pub const EVIDENCE_KEY : &'static str = "evidence";
+
+/// Two conflicting messages signed by the same authority, and the authority's public key.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum Evidence {
+    /// Two different blocks proposed at the same height on the same parent.
+    Blocks{ first : Block, second : Block, pubkey : Vec<u8> },
+    /// Finality votes for two different blocks at the same height.
+    Votes{ first : Vote, second : Vote, pubkey : Vec<u8> },
+}
+
+#[cfg(not(feature = "quantum"))]
+fn parse_key(pubkey : &[u8]) -> Option<PublicKey> {
+    PublicKey::from_bytes(pubkey).ok()
+}
+
+#[cfg(feature = "quantum")]
+fn parse_key(pubkey : &Vec<u8>) -> Option<GlpPk> {
+    Some(GlpPk::from_bytes(pubkey))
+}
+
+impl Evidence {
+    /// Evidence of two blocks. They are ordered by hash so every node builds the same evidence.
+    pub fn blocks(a : Block, b : Block, pubkey : Vec<u8>) -> Self {
+        let (first, second) = if a.hash() < b.hash() { (a, b) } else { (b, a) };
+        Evidence::Blocks{ first, second, pubkey }
+    }
+
+    /// Evidence of two votes. They are ordered by block hash so every node builds the same evidence.
+    pub fn votes(a : Vote, b : Vote, pubkey : Vec<u8>) -> Self {
+        let (first, second) = if a.vote.block_hash < b.vote.block_hash { (a, b) } else { (b, a) };
+        Evidence::Votes{ first, second, pubkey }
+    }
+
+    /// getter for the public key hash of the offending authority
+    pub fn offender(&self) -> [u8;32] {
+        match self {
+            Evidence::Blocks{ first, .. } => first.proposer(),
+            Evidence::Votes{ first, .. } => first.voter,
+        }
+    }
+
+    /// getter for the height the offender signed two blocks or votes at
+    pub fn height(&self) -> u64 {
+        match self {
+            Evidence::Blocks{ first, .. } => first.height(),
+            Evidence::Votes{ first, .. } => first.vote.height,
+        }
+    }
+
+    /// What the offender did, for the logs.
+    pub fn offence(&self) -> &'static str {
+        match self {
+            Evidence::Blocks{ .. } => "proposed two blocks",
+            Evidence::Votes{ .. } => "voted for two blocks",
+        }
+    }
+
+    /// Checks that the blocks differ and are at the same height on the same parent, or that the votes are for different blocks at the same height,
+    /// and that both are signed by the owner of `pubkey` in the signing domain of the chain.
+    pub fn verify(&self, domain : &Domain) -> Result<bool, QanError> {
+        match self {
+            Evidence::Blocks{ first, second, pubkey } => {
+                if first.proposer() != second.proposer()
+                    || first.height() != second.height()
+                    || first.prev_hash() != second.prev_hash()
+                    || first.hash() == second.hash()
+                    || do_hash(pubkey) != first.proposer() { return Ok(false) }
+                match parse_key(pubkey) {
+                    Some(pk) => Ok(first.verify(&pk, domain)? && second.verify(&pk, domain)?),
+                    None => Ok(false)
+                }
+            },
+            Evidence::Votes{ first, second, pubkey } => {
+                if first.voter != second.voter
+                    || first.vote.height != second.vote.height
+                    || first.vote.block_hash == second.vote.block_hash
+                    || do_hash(pubkey) != first.voter { return Ok(false) }
+                match parse_key(pubkey) {
+                    Some(pk) => Ok(first.verify(&pk, domain)? && second.verify(&pk, domain)?),
+                    None => Ok(false)
+                }
+            },
+        }
+    }
+}
+
+fn proposed_key(proposer : &[u8;32], height : u64, prev_hash : &[u8;32]) -> Vec<u8> {
+    let mut key = b"proposed".to_vec();
+    key.extend_from_slice(proposer);
+    key.extend_from_slice(&height.to_be_bytes());
+    key.extend_from_slice(prev_hash);
+    key
+}
+
+/// Remembers the first block seen from its proposer at its height on its parent.
+/// Returns that first block if `block` is a different one, the two of them are an equivocation.
+pub fn record(blockdb : &DB, block : &Block) -> Result<Option<Block>, QanError> {
+    let key = proposed_key(&block.proposer(), block.height(), &block.prev_hash());
+    match blockdb.get(&key).map_err(|e|QanError::Database(e))?{
+        Some(b) => {
+            let first : Block = serde_json::from_slice(&b).map_err(|e|QanError::Serde(e))?;
+            Ok(if first.hash() != block.hash() { Some(first) } else { None })
+        },
+        None => {
+            blockdb.put(&key, serde_json::to_vec(block).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
+            Ok(None)
+        }
+    }
+}
+
+/// Loads every stored evidence.
+pub fn load_all(blockdb : &DB) -> Result<Vec<Evidence>, QanError> {
+    match blockdb.get(EVIDENCE_KEY).map_err(|e|QanError::Database(e))?{
+        Some(e) => Ok(serde_json::from_slice(&e).map_err(|e|QanError::Serde(e))?),
+        None => Ok(Vec::new())
+    }
+}
+
+/// Stores verified evidence. Returns false if there already is evidence of the same kind against the offender at that height.
+pub fn store(blockdb : &DB, evidence : &Evidence) -> Result<bool, QanError> {
+    let mut all = load_all(blockdb)?;
+    if all.iter().any(|e|std::mem::discriminant(e) == std::mem::discriminant(evidence)
+        && e.offender() == evidence.offender() && e.height() == evidence.height()) { return Ok(false) }
+    all.push(evidence.clone());
+    blockdb.put(EVIDENCE_KEY, serde_json::to_vec(&all).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
+    blockdb.flush().map_err(|e|QanError::Database(e))?;
+    Ok(true)
+}
+
+/// Builds the system transaction reporting the evidence on chain, signed with the authority's ed25519 keypair.
+#[cfg(not(feature = "quantum"))]
+pub fn report_tx(evidence : Evidence, nonce : u64, spice_price : u64, kp : &Keypair, domain : &Domain) -> Result<Transaction, QanError> {
+    let call = crate::governance::SystemCall::ReportEquivocation{ evidence };
+    Transaction::new(TxBody::new(crate::governance::SYSTEM_ACCOUNT, nonce, 0, serde_json::to_vec(&call).map_err(|e|QanError::Serde(e))?).with_spice_price(spice_price), kp, domain)
+}
+
+/// Builds the system transaction reporting the evidence on chain, signed with the authority's quantum secret key.
+#[cfg(feature = "quantum")]
+pub fn report_tx(evidence : Evidence, nonce : u64, spice_price : u64, sk : &GlpSk, domain : &Domain) -> Result<Transaction, QanError> {
+    let call = crate::governance::SystemCall::ReportEquivocation{ evidence };
+    Transaction::new(TxBody::new(crate::governance::SYSTEM_ACCOUNT, nonce, 0, serde_json::to_vec(&call).map_err(|e|QanError::Serde(e))?).with_spice_price(spice_price), sk, domain)
+}
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn equivocation() {
+    let kp = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng);
+    let domain = Domain::new("test");
+    let a = Block::new([0;32], vec![[1;32]], &kp, &domain, 5).unwrap();
+    let b = Block::new([0;32], vec![[2;32]], &kp, &domain, 5).unwrap();
+    let c = Block::new([0;32], vec![[2;32]], &kp, &domain, 6).unwrap();
+    let d = Block::new([9;32], vec![[2;32]], &kp, &domain, 5).unwrap();
+    let pubkey = kp.public.to_bytes().to_vec();
+    assert!(Evidence::blocks(a.clone(), b.clone(), pubkey.clone()).verify(&domain).unwrap());
+    assert!(!Evidence::blocks(a.clone(), b.clone(), pubkey.clone()).verify(&Domain::new("other")).unwrap());
+    assert!(!Evidence::blocks(a.clone(), a.clone(), pubkey.clone()).verify(&domain).unwrap());
+    assert!(!Evidence::blocks(a.clone(), c, pubkey.clone()).verify(&domain).unwrap());
+    assert!(!Evidence::blocks(a.clone(), d, pubkey.clone()).verify(&domain).unwrap());
+    let first_of = |e : Evidence| match e { Evidence::Blocks{ first, .. } => first.hash(), _ => [0;32] };
+    assert_eq!(first_of(Evidence::blocks(a.clone(), b.clone(), pubkey.clone())), first_of(Evidence::blocks(b, a, pubkey.clone())));
+    let (x, y) = (Vote::new([1;32], 5, &kp, &domain).unwrap(), Vote::new([2;32], 5, &kp, &domain).unwrap());
+    assert!(Evidence::votes(x.clone(), y.clone(), pubkey.clone()).verify(&domain).unwrap());
+    assert!(!Evidence::votes(x.clone(), x.clone(), pubkey.clone()).verify(&domain).unwrap());
+    assert!(!Evidence::votes(x, Vote::new([2;32], 6, &kp, &domain).unwrap(), pubkey).verify(&domain).unwrap());
+}
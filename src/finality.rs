@@ -0,0 +1,189 @@
+//! Finality gadget. Every authority signs a vote for each block it imports as its new head, once per height.
+//! When more than two thirds of the authority set voted for the same block, that block and its ancestors become final,
+//! the chain is never reorganized below the finalized height.
+//! An authority voting for two different blocks at the same height equivocates, only its first vote counts and the two votes are evidence against it.
+//! Only blocks descending from the finalized block are finalized, votes for blocks off the finalized chain are ignored.
+use std::collections::{HashMap, HashSet};
+use rocksdb::DB;
+use crate::block::Block;
+use crate::error::QanError;
+use crate::util::do_hash;
+use crate::chainspec::Domain;
+#[cfg(not(feature = "quantum"))]
+use ed25519_dalek::{Keypair, PublicKey, Signature};
+#[cfg(feature = "quantum")]
+use glp::glp::{GlpSig, GlpSk, GlpPk, sign, verify, gen_pk};
+
+/// NATS subject finality votes are published on.
+pub const VOTE_SUBJECT : &'static str = "block.vote";
+/// Key of the finalized chain height in the block database.
+pub const FINALIZED_KEY : &'static str = "finalized";
+
+/// The signed part of a finality vote.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct VoteData {
+    pub block_hash  : [u8;32],
+    pub height      : u64,
+}
+
+/// Finality vote of an authority for a block.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Vote {
+    pub vote    : VoteData,
+    pub voter   : [u8;32],
+    pub sig     : Vec<u8>,
+}
+
+impl Vote {
+    #[cfg(not(feature = "quantum"))]
+    pub fn new(block_hash : [u8;32], height : u64, kp : &Keypair, domain : &Domain) -> Result<Self, QanError> {
+        let vote = VoteData{ block_hash, height };
+        let sig = kp.sign(&domain.payload(&crate::codec::encode(&vote).map_err(|e|QanError::Codec(e))?)).to_bytes().to_vec();
+        Ok(Vote{
+            vote,
+            voter: do_hash(&kp.public.to_bytes().to_vec()),
+            sig
+        })
+    }
+
+    #[cfg(feature = "quantum")]
+    pub fn new(block_hash : [u8;32], height : u64, sk : &GlpSk, domain : &Domain) -> Result<Self, QanError> {
+        let vote = VoteData{ block_hash, height };
+        let sig = sign(&sk, domain.payload(&crate::codec::encode(&vote).map_err(|e|QanError::Codec(e))?)).unwrap().to_bytes();
+        Ok(Vote{
+            vote,
+            voter: do_hash(&gen_pk(&sk).to_bytes().to_vec()),
+            sig
+        })
+    }
+
+    /// vote verification function
+    #[cfg(not(feature = "quantum"))]
+    pub fn verify(&self, pk : &PublicKey, domain : &Domain) -> Result<bool, QanError> {
+        let sig = match Signature::from_bytes(&self.sig){
+            Ok(s) => s,
+            Err(_) => return Ok(false)
+        };
+        Ok(pk.verify(&domain.payload(&crate::codec::encode(&self.vote).map_err(|e|QanError::Codec(e))?), &sig).is_ok())
+    }
+
+    /// vote verification function
+    #[cfg(feature = "quantum")]
+    pub fn verify(&self, pk : &GlpPk, domain : &Domain) -> Result<bool, QanError> {
+        Ok(verify(&pk, &GlpSig::from_bytes(&self.sig), &domain.payload(&crate::codec::encode(&self.vote).map_err(|e|QanError::Codec(e))?)))
+    }
+}
+
+/// Number of votes finalizing a block in an authority set of `authorities` members, more than two thirds.
+pub fn threshold(authorities : usize) -> usize {
+    authorities * 2 / 3 + 1
+}
+
+/// Collects the votes of the authorities for blocks that are not final yet, and the vote every authority cast at each height.
+#[derive(Default)]
+pub struct VoteTracker {
+    votes : HashMap<[u8;32], (u64, HashSet<[u8;32]>)>,
+    cast  : HashMap<([u8;32], u64), Vote>,
+}
+
+impl VoteTracker {
+    pub fn new() -> Self {
+        VoteTracker::default()
+    }
+
+    /// Records an already verified vote. Returns the number of distinct authorities that voted for the block,
+    /// or the earlier vote of the voter if it voted for another block at the same height. The conflicting vote isn't counted.
+    pub fn add(&mut self, vote : &Vote) -> Result<usize, Vote> {
+        let first = self.cast.entry((vote.voter, vote.vote.height)).or_insert(vote.clone());
+        if first.vote.block_hash != vote.vote.block_hash { return Err(first.clone()) }
+        let entry = self.votes.entry(vote.vote.block_hash).or_insert((vote.vote.height, HashSet::new()));
+        entry.1.insert(vote.voter);
+        Ok(entry.1.len())
+    }
+
+    /// Number of distinct authorities that voted for the block.
+    pub fn count(&self, block_hash : &[u8;32]) -> usize {
+        self.votes.get(block_hash).map_or(0, |(_, v)|v.len())
+    }
+
+    /// Forgets the votes for blocks at or below the finalized height.
+    pub fn prune(&mut self, finalized : u64) {
+        self.votes.retain(|_, (h, _)| *h > finalized);
+        self.cast.retain(|(_, h), _| *h > finalized);
+    }
+}
+
+/// Loads the finalized chain height. The genesis block is always final.
+pub fn load_finalized(blockdb : &DB) -> Result<u64, QanError> {
+    match blockdb.get(FINALIZED_KEY).map_err(|e|QanError::Database(e))?{
+        Some(h) => String::from_utf8_lossy(&h).parse::<u64>().map_err(|e|QanError::Internal(e.to_string())),
+        None => Ok(0)
+    }
+}
+
+/// Whether the block `hash` at `height` is the block `ancestor` at `ancestor_height` or descends from it, following the parents returned by `parent`.
+/// A block with missing ancestors doesn't descend from it.
+pub fn descends<F>(hash : [u8;32], height : u64, ancestor : &[u8;32], ancestor_height : u64, mut parent : F) -> Result<bool, QanError>
+    where F : FnMut(&[u8;32]) -> Result<Option<[u8;32]>, QanError> {
+    let mut hash = hash;
+    for _ in ancestor_height..height {
+        hash = match parent(&hash)? {
+            Some(p) => p,
+            None => return Ok(false)
+        };
+    }
+    Ok(height >= ancestor_height && hash == *ancestor)
+}
+
+/// Whether `block` descends from the canonical block at the `finalized` height.
+pub fn extends_finalized(blockdb : &DB, block : &Block, finalized : u64) -> Result<bool, QanError> {
+    let ancestor = match blockdb.get(crate::fork::height_key(finalized)).map_err(|e|QanError::Database(e))?{
+        Some(h) => crate::util::vec_to_arr(&h.to_vec()),
+        None => return Ok(false)
+    };
+    descends(block.hash(), block.height(), &ancestor, finalized, |h|Ok(crate::fork::load_block(blockdb, h)?.map(|b|b.prev_hash())))
+}
+
+/// Marks the canonical `block` and its ancestors final. Returns the new finalized height.
+pub fn finalize(blockdb : &DB, votes : &mut VoteTracker, block : &Block) -> Result<u64, QanError> {
+    blockdb.put(FINALIZED_KEY, block.height().to_string()).map_err(|e|QanError::Database(e))?;
+    blockdb.flush().map_err(|e|QanError::Database(e))?;
+    votes.prune(block.height());
+    info!("finalized block {:?} at height {}", hex::encode(block.hash()), block.height());
+    Ok(block.height())
+}
+
+#[test]
+fn supermajority() {
+    assert_eq!(threshold(1), 1);
+    assert_eq!(threshold(3), 3);
+    assert_eq!(threshold(4), 3);
+    let mut votes = VoteTracker::new();
+    let vote = |voter| Vote{ vote: VoteData{ block_hash: [7;32], height: 2 }, voter, sig: vec![] };
+    assert_eq!(votes.add(&vote([1;32])), Ok(1));
+    assert_eq!(votes.add(&vote([1;32])), Ok(1));
+    assert_eq!(votes.add(&vote([2;32])), Ok(2));
+    //a second vote at the height for another block returns the first one as evidence
+    let conflicting = Vote{ vote: VoteData{ block_hash: [8;32], height: 2 }, voter: [2;32], sig: vec![] };
+    assert_eq!(votes.add(&conflicting).err().map(|v|v.vote.block_hash), Some([7;32]));
+    assert_eq!(votes.count(&[8;32]), 0);
+    votes.prune(1);
+    assert_eq!(votes.count(&[7;32]), 2);
+    votes.prune(2);
+    assert_eq!(votes.count(&[7;32]), 0);
+}
+
+#[test]
+fn finalizes_only_descendants() {
+    //two branches on the genesis block [0;32]: a1 <- a2, and b1 <- b2 <- b3
+    let parents : HashMap<[u8;32], [u8;32]> = vec![([1;32], [0;32]), ([2;32], [1;32]), ([11;32], [0;32]), ([12;32], [11;32]), ([13;32], [12;32])].into_iter().collect();
+    let parent = |h : &[u8;32]| Ok(parents.get(h).cloned());
+    assert!(descends([2;32], 2, &[1;32], 1, parent).unwrap());
+    assert!(descends([1;32], 1, &[1;32], 1, parent).unwrap());
+    assert!(descends([13;32], 3, &[0;32], 0, parent).unwrap());
+    //once a1 is final, the competing branch can't be finalized at any height
+    assert!(!descends([13;32], 3, &[1;32], 1, parent).unwrap());
+    assert!(!descends([11;32], 1, &[1;32], 1, parent).unwrap());
+    assert!(!descends([0;32], 0, &[1;32], 1, parent).unwrap());
+    assert!(!descends([9;32], 4, &[1;32], 1, parent).unwrap());
+}
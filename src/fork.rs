@@ -0,0 +1,187 @@
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+};
+use rocksdb::DB;
+use crate::block::Block;
+use crate::error::QanError;
+
+/// Key of the canonical block hash at `height` in the block database.
+pub fn height_key(height : u64) -> String {
+    "block".to_owned() + &height.to_string()
+}
+
+/// Key of the canonical index entry of a transaction in the block database. The entry holds the hash of the including block.
+pub fn tx_index_key(txh : &[u8;32]) -> Vec<u8> {
+    let mut key = b"tx".to_vec();
+    key.extend_from_slice(txh);
+    key
+}
+
+/// Fork choice rule. The higher chain wins, on equal height the block made earlier wins, and on equal timestamp the smaller hash.
+/// Returns `Ordering::Greater` if `a` is the better head.
+pub fn compare(a : &Block, b : &Block) -> Ordering {
+    a.height().cmp(&b.height())
+        .then_with(|| b.timestamp().cmp(&a.timestamp()))
+        .then_with(|| b.hash().cmp(&a.hash()))
+}
+
+/// Checks whether `candidate` should replace `head` as the head of the chain.
+pub fn prefer(candidate : &Block, head : &Block) -> bool {
+    compare(candidate, head) == Ordering::Greater
+}
+
+/// Loads a block, canonical or not, by its hash.
+pub fn load_block(blockdb : &DB, hash : &[u8;32]) -> Result<Option<Block>, QanError> {
+    match blockdb.get(hash).map_err(|e|QanError::Database(e))?{
+        Some(b) => Ok(Some(serde_json::from_slice(&b).map_err(|e|QanError::Serde(e))?)),
+        None => Ok(None)
+    }
+}
+
+/// Hash of the canonical block including the transaction, if there is one.
+pub fn tx_block(blockdb : &DB, txh : &[u8;32]) -> Result<Option<[u8;32]>, QanError> {
+    Ok(blockdb.get(tx_index_key(txh)).map_err(|e|QanError::Database(e))?.map(|h|crate::util::vec_to_arr(&h)))
+}
+
+/// Checks whether the block is the canonical one at its height.
+pub fn is_canonical(blockdb : &DB, block : &Block) -> Result<bool, QanError> {
+    Ok(blockdb.get(height_key(block.height())).map_err(|e|QanError::Database(e))?.map_or(false, |h|h == block.hash()))
+}
+
+/// Makes the block canonical at its height, applies its transfers and contract storage writes, stores its receipts and log bloom, indexes its transactions and applies its system transactions.
+/// Every transaction of the block has to be in `txdb`. Fails without touching the chain if the block's transfers, state root or receipts root are invalid.
+pub fn index_block(blockdb : &DB, txdb : &DB, accounts : &DB, receipts : &DB, block : &Block) -> Result<(), QanError> {
+    let executed = crate::receipt::execute_block(txdb, accounts, block)?;
+    crate::state::apply_block(accounts, txdb, block, &executed)?;
+    let records = executed.records(block);
+    crate::receipt::store(receipts, block, &records)?;
+    blockdb.put(height_key(block.height()), block.hash()).map_err(|e|QanError::Database(e))?;
+    for txh in block.body.txes.iter() {
+        blockdb.put(tx_index_key(txh), block.hash()).map_err(|e|QanError::Database(e))?;
+    }
+    crate::governance::apply_block(blockdb, txdb, block)
+}
+
+/// Removes the block and its transactions from the canonical indexes, its receipts, and reverts its transfers, contract storage writes and system transactions.
+/// The block itself stays stored as a side block.
+pub fn unindex_block(blockdb : &DB, txdb : &DB, accounts : &DB, receipts : &DB, block : &Block) -> Result<(), QanError> {
+    crate::state::revert_block(accounts, txdb, receipts, block)?;
+    crate::receipt::remove(receipts, block)?;
+    blockdb.delete(height_key(block.height())).map_err(|e|QanError::Database(e))?;
+    for txh in block.body.txes.iter() {
+        blockdb.delete(tx_index_key(txh)).map_err(|e|QanError::Database(e))?;
+    }
+    crate::governance::revert_block(blockdb, block)
+}
+
+fn parent(blockdb : &DB, block : &Block) -> Result<Block, QanError> {
+    load_block(blockdb, &block.prev_hash())?
+        .ok_or(QanError::Internal(format!("missing ancestor of block {}", hex::encode(block.hash()))))
+}
+
+/// Walks back from both heads to their common ancestor.
+/// Returns the blocks of the old branch from the top down, and the blocks of the new branch from the bottom up.
+pub fn tree_route(blockdb : &DB, old_head : &Block, new_head : &Block) -> Result<(Vec<Block>, Vec<Block>), QanError> {
+    let mut old = old_head.clone();
+    let mut new = new_head.clone();
+    let mut retracted = Vec::new();
+    let mut enacted = Vec::new();
+    while old.height() > new.height() {
+        let p = parent(blockdb, &old)?;
+        retracted.push(std::mem::replace(&mut old, p));
+    }
+    while new.height() > old.height() {
+        let p = parent(blockdb, &new)?;
+        enacted.push(std::mem::replace(&mut new, p));
+    }
+    while old.hash() != new.hash() {
+        let op = parent(blockdb, &old)?;
+        let np = parent(blockdb, &new)?;
+        retracted.push(std::mem::replace(&mut old, op));
+        enacted.push(std::mem::replace(&mut new, np));
+    }
+    enacted.reverse();
+    Ok((retracted, enacted))
+}
+
+/// Chain reorganization. Rewinds the canonical chain from `old_head` to the common ancestor, and re-indexes the branch ending in `new_head`.
+/// Every block of the new branch has to be stored already, with its transactions in `txdb`.
+/// Transactions of the abandoned branch that the new branch doesn't include are returned to the mempool,
+/// the ones the new branch includes are removed from it, and the mempool is pruned back to its limits. Returns the new chain height.
+/// Fails without touching the chain if it would retract a block at or below the `finalized` height,
+/// and restores the old branch if a block of the new one is invalid.
+pub fn reorg(
+    blockdb     : &DB,
+    txdb        : &DB,
+    accounts    : &DB,
+    receipts    : &DB,
+    mempool     : &mut crate::mempool::Mempool,
+    old_head    : &Block,
+    new_head    : &Block,
+    finalized   : u64) -> Result<u64, QanError> {
+    let (retracted, enacted) = tree_route(blockdb, old_head, new_head)?;
+    if let Some(b) = retracted.last() {
+        if b.height() <= finalized {
+            return Err(QanError::Internal(format!("reorg to {} would revert finalized block {}", hex::encode(new_head.hash()), hex::encode(b.hash()))))
+        }
+    }
+    let retracted_txes : HashSet<[u8;32]> = retracted.iter().flat_map(|b|b.body.txes.iter().cloned()).collect();
+    let mut enacted_txes : HashSet<[u8;32]> = HashSet::new();
+    for b in &enacted {
+        for txh in &b.body.txes {
+            if !enacted_txes.insert(*txh) || (!retracted_txes.contains(txh) && tx_block(blockdb, txh)?.is_some()) {
+                return Err(QanError::Internal(format!("transaction {} included twice on branch of {}", hex::encode(txh), hex::encode(new_head.hash()))))
+            }
+            if txdb.get_pinned(txh).map_err(|e|QanError::Database(e))?.is_none() {
+                return Err(QanError::Internal(format!("missing transaction {} on branch of {}", hex::encode(txh), hex::encode(new_head.hash()))))
+            }
+        }
+    }
+    info!("reorg from {} to {}: {} blocks retracted, {} enacted",
+        hex::encode(old_head.hash()), hex::encode(new_head.hash()), retracted.len(), enacted.len());
+
+    for b in &retracted {
+        unindex_block(blockdb, txdb, accounts, receipts, b)?;
+    }
+    for (i, b) in enacted.iter().enumerate() {
+        if let Err(e) = index_block(blockdb, txdb, accounts, receipts, b) {
+            for a in enacted[..i].iter().rev() {
+                unindex_block(blockdb, txdb, accounts, receipts, a)?;
+            }
+            for r in retracted.iter().rev() {
+                index_block(blockdb, txdb, accounts, receipts, r)?;
+            }
+            blockdb.flush().map_err(|e|QanError::Database(e))?;
+            return Err(e)
+        }
+    }
+    blockdb.put("height", new_head.height().to_string()).map_err(|e|QanError::Database(e))?;
+    blockdb.flush().map_err(|e|QanError::Database(e))?;
+
+    for txh in retracted_txes.difference(&enacted_txes) {
+        if let Some(t) = txdb.get(txh).map_err(|e|QanError::Database(e))?{
+            mempool.insert(*txh, serde_json::from_slice(&t).map_err(|e|QanError::Serde(e))?);
+        }
+    }
+    for txh in &enacted_txes {
+        mempool.remove(txh);
+    }
+    mempool.prune(crate::util::timestamp());
+    Ok(new_head.height())
+}
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn fork_choice() {
+    let kp = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng);
+    let mut a = Block::new([0;32], vec![[1;32]], &kp, &crate::chainspec::Domain::new("test"), 5).unwrap();
+    let mut b = Block::new([0;32], vec![[2;32]], &kp, &crate::chainspec::Domain::new("test"), 5).unwrap();
+    a.signed.header.timestamp = 100;
+    b.signed.header.timestamp = 200;
+    assert!(prefer(&a, &b));
+    assert!(!prefer(&b, &a));
+    b.signed.header.height = 6;
+    assert!(prefer(&b, &a));
+    assert!(!prefer(&a, &a));
+}
@@ -11,7 +11,7 @@ use std::{
 };
 use crate::pk::{PATHNAME, PetKey};
 use crate::event::{SyncType, Event};
-use crate::block::{Block, merge};
+use crate::block::Block;
 use crate::conset::ConsensusSettings;
 use crate::util::{do_hash, vec_to_arr};
 use crate::sync::{sync, genesis_getter};
@@ -35,7 +35,13 @@ pub fn gen_data() -> Result<(), QanError>{
     pkeys.put(do_hash(&mahgenkey),&mahgenkey);
     pkeys.flush().map_err(|e|QanError::Database(e))?;
 
-    let (mut head, tx) = crate::nemezis::generate_nemezis_block(&genkeys)?;
+    let proposers : Vec<crate::pk::PetKey> = (0..1001).map(|_|crate::pk::PetKey::new()).collect();
+    let proposer_ids : Vec<[u8;32]> = proposers.iter().map(|k|do_hash(&k.get_glp_pk_bytes())).collect();
+    let (mut head, tx) = crate::nemezis::generate_nemezis_block(&genkeys, &proposer_ids, &ConsensusSettings::default())?;
+    let nemezis_hash = head.hash();
+    let nemezis_data : crate::nemezis::NemezisData = serde_json::from_slice(&tx.get_data()).map_err(|e|QanError::Serde(e))?;
+    let domain = crate::chainspec::Domain::new(&nemezis_data.chain_id);
+    let authorities = crate::authority::AuthoritySet::from_nemezis(&tx)?;
     let mut block_height = 0;
 
     blockdb.put("height", &block_height.to_string()).map_err(|e|QanError::Database(e))?;
@@ -43,19 +49,18 @@ pub fn gen_data() -> Result<(), QanError>{
     blockdb.put(&head.hash(), serde_json::to_vec(&head).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
     txdb.put(tx.hash()?, serde_json::to_vec(&tx).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
     println!("start at :{}", crate::util::timestamp());
-    for i in 0..1001{
-        let keys = crate::pk::PetKey::new();
+    for (i, keys) in proposers.iter().enumerate(){
         let mahkey = keys.get_glp_pk_bytes();
         pkeys.put(do_hash(&mahkey),&mahkey);
         pkeys.flush().map_err(|e|QanError::Database(e))?;
         let mut tx_es = Vec::new();
         for j in 0..12{
-            let tx = Transaction::new(TxBody::new([0;32], 0, crate::util::urandom(968)), &keys.glp)?;
+            let tx = Transaction::new(TxBody::new([0;32], j, 0, crate::util::urandom(968)), &keys.glp, &domain)?;
             txdb.put(tx.hash()?, serde_json::to_vec(&tx).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
             tx_es.push(tx.hash()?);
         }
         block_height+=1;
-        head = Block::new(head.hash(), tx_es, &keys.glp, block_height)?;
+        head = Block::new(head.hash(), tx_es, &keys.glp, &domain, block_height)?;
         blockdb.put("height", block_height.to_string()).map_err(|e|QanError::Database(e))?;
         blockdb.put("block".to_owned() + &block_height.to_string(), &head.hash()).map_err(|e|QanError::Database(e))?;
         blockdb.put(&head.hash(), serde_json::to_vec(&head).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
@@ -63,6 +68,12 @@ pub fn gen_data() -> Result<(), QanError>{
         txdb.flush().map_err(|e|QanError::Database(e))?;
         println!("block {} done at:{}", i, crate::util::timestamp());
     }
+    authorities.store(&blockdb)?;
+    crate::authority::AuthorityHistory::genesis(authorities, nemezis_hash).store(&blockdb)?;
+    crate::governance::ConsensusSchedule::genesis(ConsensusSettings::default(), nemezis_hash).store(&blockdb)?;
+    crate::chainspec::Domain::store_chain_id(&blockdb, &nemezis_data.chain_id)?;
+    crate::address::store_hrp(&blockdb, &crate::address::hrp_of(&nemezis_data.chain_id, &nemezis_data.address_prefix)?)?;
+    crate::codec::store_version(&blockdb)?;
     println!("done");
     Ok(())
 }
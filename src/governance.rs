@@ -0,0 +1,206 @@
+//! On-chain governance of the consensus parameters and the authority set.
+//! The genesis transaction defines the initial `ConsensusSettings`. Authorities change them with system transactions,
+//! sent to `SYSTEM_ACCOUNT` and carrying a `SystemCall`, that take effect from an activation height on.
+//! Authorities are added and removed the same way, once a majority of the active set sent the same call.
+//! The schedules follow the canonical chain, they are updated when a block is indexed and reverted when a reorg retracts it.
+use rocksdb::DB;
+use crate::block::Block;
+use crate::conset::ConsensusSettings;
+use crate::transaction::Transaction;
+use crate::authority::{AuthorityHistory, AuthorityChange};
+use crate::error::QanError;
+
+/// Recipient of system transactions.
+pub const SYSTEM_ACCOUNT : [u8;32] = [0xff;32];
+/// Key of the persisted consensus schedule in the block database.
+pub const SCHEDULE_KEY : &'static str = "consensus";
+
+/// Payload of a system transaction.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum SystemCall {
+    /// Replaces the consensus settings from block height `activation` on.
+    SetConsensus{ settings : ConsensusSettings, activation : u64 },
+    /// Approves adding the authority with the public key hash `id` at the next epoch boundary.
+    AddAuthority{ id : [u8;32] },
+    /// Approves removing the authority with the public key hash `id` at the next epoch boundary.
+    RemoveAuthority{ id : [u8;32] },
+    /// Reports an authority that proposed or voted for two blocks at the same height, it is removed at the next epoch boundary.
+    ReportEquivocation{ evidence : crate::evidence::Evidence },
+}
+
+impl SystemCall {
+    /// Reads the system call out of a transaction sent to the system account. Returns `None` for any other transaction.
+    pub fn from_transaction(tx : &Transaction) -> Option<Self> {
+        if tx.transaction.recipient != SYSTEM_ACCOUNT { return None }
+        serde_json::from_slice(&tx.get_data()).ok()
+    }
+}
+
+/// Consensus settings scheduled by the block `block`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ScheduledSettings {
+    pub activation  : u64,
+    pub block       : [u8;32],
+    pub settings    : ConsensusSettings,
+}
+
+/// Consensus settings of the canonical chain, ordered by activation height.
+/// On equal activation height the one included later wins.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ConsensusSchedule {
+    entries : Vec<ScheduledSettings>,
+}
+
+impl ConsensusSchedule {
+    /// Constructor function for ConsensusSchedule, starting with the settings of the genesis block.
+    pub fn genesis(settings : ConsensusSettings, genesis : [u8;32]) -> Self {
+        ConsensusSchedule{ entries: vec![ScheduledSettings{ activation: 0, block: genesis, settings }] }
+    }
+
+    /// Loads the persisted schedule. Returns `None` if the chain has not been initialized yet.
+    pub fn load(db : &DB) -> Result<Option<Self>, QanError> {
+        match db.get(SCHEDULE_KEY).map_err(|e|QanError::Database(e))?{
+            Some(s) => Ok(Some(serde_json::from_slice(&s).map_err(|e|QanError::Serde(e))?)),
+            None => Ok(None)
+        }
+    }
+
+    /// Persists the schedule in the block database.
+    pub fn store(&self, db : &DB) -> Result<(), QanError> {
+        db.put(SCHEDULE_KEY, serde_json::to_vec(&self).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
+        db.flush().map_err(|e|QanError::Database(e))
+    }
+
+    /// The settings in force at `height`.
+    pub fn settings_at(&self, height : u64) -> ConsensusSettings {
+        self.entries.iter().rev().find(|e|e.activation <= height)
+            .map_or(ConsensusSettings::default(), |e|e.settings.clone())
+    }
+
+    /// Adds an entry after every entry activating at the same height or before.
+    pub fn schedule(&mut self, entry : ScheduledSettings) {
+        let pos = self.entries.iter().position(|e|e.activation > entry.activation).unwrap_or(self.entries.len());
+        self.entries.insert(pos, entry);
+    }
+
+    /// Removes the entries scheduled by `block`. Returns whether anything was removed.
+    pub fn revert(&mut self, block : &[u8;32]) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|e|&e.block != block);
+        len != self.entries.len()
+    }
+
+    /// getter for the scheduled settings
+    pub fn entries(&self) -> &Vec<ScheduledSettings> {
+        &self.entries
+    }
+}
+
+/// The settings in force at `height` on the canonical chain.
+pub fn settings_at(blockdb : &DB, height : u64) -> Result<ConsensusSettings, QanError> {
+    Ok(ConsensusSchedule::load(blockdb)?.map_or(ConsensusSettings::default(), |s|s.settings_at(height)))
+}
+
+/// Applies the system transactions of `block` to `schedule` and `history`. Every transaction of the block has to be in `txdb`.
+/// Calls not signed by a member of the authority set active at the block, or activating at or below the block's height, are ignored.
+/// Returns whether the schedule and whether the history changed.
+fn apply_calls(blockdb : &DB, txdb : &DB, block : &Block, schedule : &mut ConsensusSchedule, history : &mut AuthorityHistory) -> Result<(bool, bool), QanError> {
+    let epoch_length = schedule.settings_at(block.height()).epoch_length();
+    let (mut settings_changed, mut authorities_changed) = (false, false);
+    for txh in &block.body.txes {
+        let tx : Transaction = match txdb.get(txh).map_err(|e|QanError::Database(e))?{
+            Some(t) => serde_json::from_slice(&t).map_err(|e|QanError::Serde(e))?,
+            None => return Err(QanError::Internal(format!("missing transaction {} of block {}", hex::encode(txh), hex::encode(block.hash()))))
+        };
+        let call = match SystemCall::from_transaction(&tx) {
+            Some(call) => call,
+            None => continue
+        };
+        if !history.set_at(block.height()).contains(&tx.pubkey) {
+            warn!("ignored system transaction {} of non-authority {}", hex::encode(txh), hex::encode(tx.pubkey));
+            continue
+        }
+        let change = match call {
+            SystemCall::SetConsensus{ settings, activation } => {
+                if activation <= block.height() {
+                    warn!("ignored consensus change {} in block {}", hex::encode(txh), hex::encode(block.hash()));
+                    continue
+                }
+                info!("consensus settings {:?} scheduled at height {}", settings, activation);
+                schedule.schedule(ScheduledSettings{ activation, block: block.hash(), settings });
+                settings_changed = true;
+                continue
+            },
+            SystemCall::AddAuthority{ id } => AuthorityChange::Add(id),
+            SystemCall::RemoveAuthority{ id } => AuthorityChange::Remove(id),
+            SystemCall::ReportEquivocation{ evidence } => {
+                if !evidence.verify(&crate::chainspec::Domain::load(blockdb)?)? { continue }
+                if let Some(activation) = history.exclude(evidence.offender(), block.hash(), block.height(), epoch_length) {
+                    info!("equivocating authority {} excluded at height {}", hex::encode(evidence.offender()), activation);
+                    authorities_changed = true;
+                }
+                continue
+            },
+        };
+        authorities_changed = true;
+        if let Some(activation) = history.approve(change.clone(), tx.pubkey, block.hash(), block.height(), epoch_length) {
+            info!("authority change {:?} scheduled at height {}", change, activation);
+        }
+    }
+    Ok((settings_changed, authorities_changed))
+}
+
+/// Applies the system transactions of a block becoming canonical. Every transaction of the block has to be in `txdb`.
+/// Calls not signed by a member of the authority set active at the block, or activating at or below the block's height, are ignored.
+pub fn apply_block(blockdb : &DB, txdb : &DB, block : &Block) -> Result<(), QanError> {
+    let mut history = match AuthorityHistory::load(blockdb)?{
+        Some(h) => h,
+        None => return Ok(())
+    };
+    let mut schedule = ConsensusSchedule::load(blockdb)?.unwrap_or(ConsensusSchedule::genesis(ConsensusSettings::default(), [0;32]));
+    let (settings_changed, authorities_changed) = apply_calls(blockdb, txdb, block, &mut schedule, &mut history)?;
+    if settings_changed { schedule.store(blockdb)?; }
+    if authorities_changed { history.store(blockdb)?; }
+    Ok(())
+}
+
+/// Consensus schedule and authority history of the chain ending in `tip`, which doesn't have to be canonical. They are the canonical ones
+/// with the system transactions of the canonical blocks above the common ancestor of `head` and `tip` reverted,
+/// and those of the blocks of `tip`'s branch applied. Every block of the branch has to be stored, with its transactions in `txdb`.
+pub fn on_branch(blockdb : &DB, txdb : &DB, head : &Block, tip : &Block) -> Result<(ConsensusSchedule, AuthorityHistory), QanError> {
+    let mut history = AuthorityHistory::load(blockdb)?.ok_or(QanError::Internal("no authority set, genesis is not initialized".to_string()))?;
+    let mut schedule = ConsensusSchedule::load(blockdb)?.unwrap_or(ConsensusSchedule::genesis(ConsensusSettings::default(), [0;32]));
+    let (retracted, enacted) = crate::fork::tree_route(blockdb, head, tip)?;
+    for b in &retracted {
+        schedule.revert(&b.hash());
+        history.revert(&b.hash());
+    }
+    for b in &enacted {
+        apply_calls(blockdb, txdb, b, &mut schedule, &mut history)?;
+    }
+    Ok((schedule, history))
+}
+
+/// Reverts the system transactions of a block retracted from the canonical chain.
+pub fn revert_block(blockdb : &DB, block : &Block) -> Result<(), QanError> {
+    if let Some(mut schedule) = ConsensusSchedule::load(blockdb)? {
+        if schedule.revert(&block.hash()) { schedule.store(blockdb)?; }
+    }
+    if let Some(mut history) = AuthorityHistory::load(blockdb)? {
+        if history.revert(&block.hash()) { history.store(blockdb)?; }
+    }
+    Ok(())
+}
+
+#[test]
+fn schedule_activation() {
+    let mut schedule = ConsensusSchedule::genesis(ConsensusSettings::new(1, 1, 1, 1000), [0;32]);
+    schedule.schedule(ScheduledSettings{ activation: 10, block: [2;32], settings: ConsensusSettings::new(2, 2, 2, 2000) });
+    schedule.schedule(ScheduledSettings{ activation: 5, block: [1;32], settings: ConsensusSettings::new(3, 3, 3, 3000) });
+    assert_eq!(schedule.settings_at(4).slot_duration(), 1000);
+    assert_eq!(schedule.settings_at(5).slot_duration(), 3000);
+    assert_eq!(schedule.settings_at(10).slot_duration(), 2000);
+    assert!(schedule.revert(&[2;32]));
+    assert_eq!(schedule.settings_at(10).slot_duration(), 3000);
+    assert!(!schedule.revert(&[2;32]));
+}
@@ -7,6 +7,8 @@ extern crate rocksdb;
 extern crate base64;
 extern crate log4rs;
 extern crate wasmi;
+extern crate parity_wasm;
+extern crate pwasm_utils;
 extern crate clap;
 extern crate toml;
 extern crate rand;
@@ -15,6 +17,7 @@ extern crate log;
 #[cfg(feature = "quantum")]
 extern crate glp;
 extern crate hex;
+extern crate rayon;
 
 pub mod user_client;
 pub mod transaction;
@@ -32,7 +35,24 @@ pub mod util;
 pub mod sync;
 pub mod rpc;
 pub mod pk;
+pub mod authority;
+pub mod slot;
+pub mod fork;
+pub mod raft;
+pub mod finality;
+pub mod governance;
+pub mod evidence;
+pub mod state;
+pub mod smt;
+pub mod spice;
+pub mod receipt;
+pub mod mempool;
 pub mod vm;
+pub mod address;
+pub mod chainspec;
+pub mod validation;
+pub mod sigverify;
+pub mod codec;
 
 #[cfg(feature = "quantum")]
 pub mod qmain;
@@ -0,0 +1,241 @@
+//! Memory pool of the transactions waiting for a block. The pool is bounded in transaction count and bytes,
+//! every sender may only have a limited number of transactions waiting, and transactions older than the TTL, by their own timestamp, expire.
+//! When the pool is full a new transaction evicts the lowest priority ones, if it has a higher priority than them.
+//! Transactions are prioritized by fee rate, their `spice_price`. A sender replaces its waiting transaction by sending another one
+//! with the same nonce and a spice price at least `MIN_PRICE_BUMP` percent higher, so replacements can't flood the network for free.
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use crate::transaction::Transaction;
+
+/// Percent a replacement has to raise the spice price of the transaction it replaces by.
+pub const MIN_PRICE_BUMP : u64 = 10;
+
+/// Bounds of the mempool, set in the `[mempool]` table of `config.toml`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MempoolLimits {
+    pub max_tx          : usize,
+    pub max_bytes       : usize,
+    pub max_per_sender  : usize,
+    /// Milliseconds a transaction may wait after its timestamp.
+    pub ttl             : u64,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        MempoolLimits{
+            max_tx          : 10_000,
+            max_bytes       : 32 * 1024 * 1024,
+            max_per_sender  : 64,
+            ttl             : 60 * 60 * 1000,
+        }
+    }
+}
+
+/// Reason a transaction was not added to the mempool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    Duplicate,
+    Expired,
+    SenderQuota,
+    Full,
+    Underpriced,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::Duplicate => write!(f, "already in the mempool"),
+            Rejection::Expired => write!(f, "expired"),
+            Rejection::SenderQuota => write!(f, "sender has too many waiting transactions"),
+            Rejection::Full => write!(f, "mempool is full of higher priority transactions"),
+            Rejection::Underpriced => write!(f, "replacement doesn't raise the spice price by {}%", MIN_PRICE_BUMP),
+        }
+    }
+}
+
+/// Priority order of waiting transactions, the highest priority first: the higher spice price, on equal price the older,
+/// and on equal timestamp the smaller hash.
+pub fn by_priority(a : (&[u8;32], &Transaction), b : (&[u8;32], &Transaction)) -> Ordering {
+    b.1.transaction.spice_price.cmp(&a.1.transaction.spice_price)
+        .then_with(|| a.1.transaction.timestamp.cmp(&b.1.transaction.timestamp))
+        .then_with(|| a.0.cmp(b.0))
+}
+
+/// Whether a replacement paying `new` per spice pays enough over the `old` price.
+fn bumps_price(old : u64, new : u64) -> bool {
+    new > old && new as u128 * 100 >= old as u128 * (100 + MIN_PRICE_BUMP as u128)
+}
+
+/// The waiting transactions by hash, with their size kept up to date.
+pub struct Mempool {
+    txes    : HashMap<[u8;32], Transaction>,
+    bytes   : usize,
+    limits  : MempoolLimits,
+}
+
+impl Mempool {
+    pub fn new(limits : MempoolLimits) -> Mempool {
+        Mempool{ txes: HashMap::new(), bytes: 0, limits }
+    }
+
+    pub fn get(&self, txh : &[u8;32]) -> Option<&Transaction> {
+        self.txes.get(txh)
+    }
+
+    pub fn get_key_value(&self, txh : &[u8;32]) -> Option<(&[u8;32], &Transaction)> {
+        self.txes.get_key_value(txh)
+    }
+
+    pub fn contains_key(&self, txh : &[u8;32]) -> bool {
+        self.txes.contains_key(txh)
+    }
+
+    pub fn iter(&self) -> std::collections::hash_map::Iter<'_, [u8;32], Transaction> {
+        self.txes.iter()
+    }
+
+    pub fn values(&self) -> std::collections::hash_map::Values<'_, [u8;32], Transaction> {
+        self.txes.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.txes.len()
+    }
+
+    /// Size of the waiting transactions together.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Inserts a transaction without checking the limits, for the transactions of blocks being imported or retracted.
+    pub fn insert(&mut self, txh : [u8;32], tx : Transaction) -> Option<Transaction> {
+        self.bytes += tx.len();
+        let old = self.txes.insert(txh, tx);
+        if let Some(o) = &old { self.bytes = self.bytes.saturating_sub(o.len()) }
+        old
+    }
+
+    pub fn remove(&mut self, txh : &[u8;32]) -> Option<Transaction> {
+        let tx = self.txes.remove(txh);
+        if let Some(t) = &tx { self.bytes = self.bytes.saturating_sub(t.len()) }
+        tx
+    }
+
+    fn expired(&self, tx : &Transaction, now : u64) -> bool {
+        tx.transaction.timestamp.saturating_add(self.limits.ttl) < now
+    }
+
+    fn over_limits(&self) -> bool {
+        self.txes.len() > self.limits.max_tx || self.bytes > self.limits.max_bytes
+    }
+
+    /// The lowest priority transaction.
+    fn lowest(&self) -> Option<[u8;32]> {
+        self.txes.iter().max_by(|a, b|by_priority((a.0, a.1), (b.0, b.1))).map(|(h, _)|*h)
+    }
+
+    /// The waiting transaction of `sender` with `nonce`.
+    pub fn by_nonce(&self, sender : &[u8;32], nonce : u64) -> Option<[u8;32]> {
+        self.txes.iter().find(|(_, t)|&t.pubkey == sender && t.transaction.nonce == nonce).map(|(h, _)|*h)
+    }
+
+    /// Admits a new transaction within the limits. A transaction with the nonce of a waiting one of the same sender replaces it
+    /// if it raises the spice price by `MIN_PRICE_BUMP` percent, and at least by one. Returns the replaced transaction and the lower priority ones evicted to fit.
+    pub fn add(&mut self, txh : [u8;32], tx : Transaction, now : u64) -> Result<Vec<Transaction>, Rejection> {
+        if self.txes.contains_key(&txh) { return Err(Rejection::Duplicate) }
+        if self.expired(&tx, now) { return Err(Rejection::Expired) }
+        let replaced = self.by_nonce(&tx.pubkey, tx.transaction.nonce);
+        if let Some(r) = &replaced {
+            if !bumps_price(self.txes[r].transaction.spice_price, tx.transaction.spice_price) { return Err(Rejection::Underpriced) }
+        }else if self.txes.values().filter(|t|t.pubkey == tx.pubkey).count() >= self.limits.max_per_sender {
+            return Err(Rejection::SenderQuota)
+        }
+        if tx.len() > self.limits.max_bytes { return Err(Rejection::Full) }
+        let mut evict : Vec<[u8;32]> = replaced.iter().cloned().collect();
+        let (mut count, mut bytes) = (self.txes.len(), self.bytes);
+        if let Some(r) = &replaced {
+            count -= 1;
+            bytes -= self.txes[r].len();
+        }
+        let mut candidates : Vec<(&[u8;32], &Transaction)> = self.txes.iter().filter(|(h, _)|Some(**h) != replaced).collect();
+        candidates.sort_by(|a, b|by_priority(*b, *a));
+        for (h, t) in candidates {
+            if count + 1 <= self.limits.max_tx && bytes + tx.len() <= self.limits.max_bytes { break }
+            if by_priority((h, t), (&txh, &tx)) != Ordering::Greater { return Err(Rejection::Full) }
+            evict.push(*h);
+            count -= 1;
+            bytes -= t.len();
+        }
+        let evicted = evict.iter().filter_map(|h|self.remove(h)).collect();
+        self.insert(txh, tx);
+        Ok(evicted)
+    }
+
+    /// Removes the expired transactions, then the lowest priority ones while the pool is over its limits.
+    /// Returns the number of removed transactions.
+    pub fn prune(&mut self, now : u64) -> usize {
+        let expired : Vec<[u8;32]> = self.txes.iter().filter(|(_, t)|self.expired(t, now)).map(|(h, _)|*h).collect();
+        let mut removed = expired.iter().filter_map(|h|self.remove(h)).count();
+        while self.over_limits() {
+            match self.lowest() {
+                Some(h) => { self.remove(&h); removed += 1 },
+                None => break
+            }
+        }
+        removed
+    }
+}
+
+impl std::ops::Index<&[u8;32]> for Mempool {
+    type Output = Transaction;
+
+    fn index(&self, txh : &[u8;32]) -> &Transaction {
+        &self.txes[txh]
+    }
+}
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn limits_and_eviction() {
+    let domain = crate::chainspec::Domain::new("test");
+    let (kp, other) = (ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng), ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng));
+    let tx = |kp : &ed25519_dalek::Keypair, nonce, timestamp| {
+        let mut body = crate::transaction::TxBody::new([0;32], nonce, 0, vec![]);
+        body.timestamp = timestamp;
+        let tx = Transaction::new(body, kp, &domain).unwrap();
+        (tx.hash().unwrap(), tx)
+    };
+    let mut pool = Mempool::new(MempoolLimits{ max_tx: 2, max_bytes: 1_000_000, max_per_sender: 2, ttl: 100 });
+    let (h0, t0) = tx(&kp, 0, 1000);
+    assert!(pool.add(h0, t0.clone(), 1050).unwrap().is_empty());
+    assert_eq!(pool.add(h0, t0.clone(), 1050).err(), Some(Rejection::Duplicate));
+    assert_eq!(pool.add(tx(&kp, 1, 900).0, tx(&kp, 1, 900).1, 1050).err(), Some(Rejection::Expired));
+    let (h1, t1) = tx(&kp, 1, 1020);
+    pool.add(h1, t1, 1050).unwrap();
+    assert_eq!(pool.bytes(), pool.values().map(|t|t.len()).sum::<usize>());
+    assert_eq!(pool.add(tx(&kp, 2, 1010).0, tx(&kp, 2, 1010).1, 1050).err(), Some(Rejection::SenderQuota));
+    //full: a newer transaction is rejected, an older one evicts the newest
+    assert_eq!(pool.add(tx(&other, 0, 1030).0, tx(&other, 0, 1030).1, 1050).err(), Some(Rejection::Full));
+    let evicted = pool.add(tx(&other, 0, 1010).0, tx(&other, 0, 1010).1, 1050).unwrap();
+    assert_eq!(evicted.len(), 1);
+    assert_eq!(evicted[0].hash().unwrap(), h1);
+    assert_eq!(pool.prune(1105), 1);
+    assert!(!pool.contains_key(&h0));
+    //replace-by-fee needs a high enough bump of the spice price, and doesn't count against the sender quota
+    let h2 = tx(&other, 0, 1010).0;
+    let pricier = |price| {
+        let mut body = crate::transaction::TxBody::new([0;32], 0, 0, vec![]).with_spice_price(price);
+        body.timestamp = 1100;
+        let tx = Transaction::new(body, &other, &domain).unwrap();
+        (tx.hash().unwrap(), tx)
+    };
+    assert_eq!(pool.add(pricier(0).0, pricier(0).1, 1105).err(), Some(Rejection::Underpriced));
+    let replaced = pool.add(pricier(2).0, pricier(2).1, 1105).unwrap();
+    assert_eq!(replaced[0].hash().unwrap(), h2);
+    assert!(!pool.contains_key(&h2) && pool.contains_key(&pricier(2).0));
+    assert_eq!(pool.len(), 1);
+    pool.add(pricier(20).0, pricier(20).1, 1105).unwrap();
+    assert_eq!(pool.add(pricier(21).0, pricier(21).1, 1105).err(), Some(Rejection::Underpriced));
+    assert_eq!(pool.add(pricier(22).0, pricier(22).1, 1105).unwrap()[0].transaction.spice_price, 20);
+}
@@ -1,32 +1,58 @@
 use std::io::Write;
 use crate::error::QanError;
+use crate::conset::ConsensusSettings;
 
-/// Generator function for genesis block. Default genesis transaction is the default settings for making consensus in this preconsensus version.
+/// Content of the genesis transaction. Every node reads the chain defining parameters out of it.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NemezisData {
+    pub authorities : Vec<[u8;32]>,
+    #[serde(default = "ConsensusSettings::default")]
+    pub consensus   : ConsensusSettings,
+    #[serde(default)]
+    pub balances    : Vec<([u8;32], u64)>,
+    #[serde(default)]
+    pub chain_id    : String,
+    #[serde(default)]
+    pub address_prefix : String,
+}
+
+/// Random chain id of a generated genesis, so chains generated without a chain spec never share a signing domain.
+pub fn new_chain_id() -> String {
+    format!("qan-{}", hex::encode(crate::util::urandom(8)))
+}
+
+/// Generator function for genesis block. The genesis transaction carries the initial authority set, the generating node is always part of it,
+/// the consensus settings the chain starts with, `GENESIS_BALANCE` for every authority, and a new chain id.
 #[cfg(not(feature = "quantum"))]
-pub fn generate_nemezis_block(keys: &crate::pk::PetKey) -> Result<(crate::block::Block, crate::transaction::Transaction), QanError>{
-    // let ConsensusSettings = crate::conset::ConsensusSettings::default();
-    // let nemezis_body = crate::transaction::TxBody::new([0;32], serde_json::to_vec(&ConsensusSettings).map_err(|e|QanError::Serde(e))?);
-    let nemezis_body = crate::transaction::TxBody::new([0;32], 0, "woopwoop".as_bytes().to_vec()); 
-    let nemesis_tx = crate::transaction::Transaction::new(nemezis_body, &keys.ec)?;
+pub fn generate_nemezis_block(keys: &crate::pk::PetKey, authorities: &[[u8;32]], consensus: &ConsensusSettings) -> Result<(crate::block::Block, crate::transaction::Transaction), QanError>{
+    let mut nemezis_data = NemezisData{ authorities: authorities.to_vec(), consensus: consensus.clone(), balances: Vec::new(), chain_id: new_chain_id(), address_prefix: String::new() };
+    nemezis_data.authorities.push(crate::util::do_hash(&keys.ec.public.to_bytes().to_vec()));
+    nemezis_data.balances = nemezis_data.authorities.iter().map(|a|(*a, crate::state::GENESIS_BALANCE)).collect();
+    let nemezis_body = crate::transaction::TxBody::new([0;32], 0, 0, serde_json::to_vec(&nemezis_data).map_err(|e|QanError::Serde(e))?);
+    let nemesis_tx = crate::transaction::Transaction::new(nemezis_body, &keys.ec, &crate::chainspec::Domain::new(&nemezis_data.chain_id))?;
     let mut nemezis_vec : Vec<[u8;32]> = Vec::new();
     nemezis_vec.push(nemesis_tx.hash()?);
-    let block = crate::block::Block::new([0;32], nemezis_vec, &keys.ec, 0)?;
+    let state_root = crate::state::genesis_root(&nemezis_data.balances)?;
+    let block = crate::block::Block::with_roots([0;32], nemezis_vec, state_root, crate::block::EMPTY_ROOT, &keys.ec, &crate::chainspec::Domain::new(&nemezis_data.chain_id), 0)?;
     let mut pemf = std::fs::File::create(std::path::Path::new("NEMEZIS")).map_err(|e|QanError::Io(e))?;
     pemf.write_all(&serde_json::to_vec(&block).map_err(|e|QanError::Serde(e))?);
     Ok((block, nemesis_tx))
 }
 
-/// Generator function for genesis block. Default genesis transaction is the default settings for making consensus in this preconsensus version.
+/// Generator function for genesis block. The genesis transaction carries the initial authority set, the generating node is always part of it,
+/// the consensus settings the chain starts with, `GENESIS_BALANCE` for every authority, and a new chain id.
 #[cfg(feature = "quantum")]
-pub fn generate_nemezis_block(keys: &crate::pk::PetKey) -> Result<(crate::block::Block, crate::transaction::Transaction), QanError>{
-//     let ConsensusSettings = crate::conset::ConsensusSettings::default();
-    // let nemezis_body = crate::transaction::TxBody::new([0;32], serde_json::to_vec(&ConsensusSettings).map_err(|e|QanError::Serde(e))?);
-    let nemezis_body = crate::transaction::TxBody::new([0;32], 0,  "woopwoop".as_bytes().to_vec());  
-    let nemesis_tx = crate::transaction::Transaction::new(nemezis_body, &keys.glp)?;
+pub fn generate_nemezis_block(keys: &crate::pk::PetKey, authorities: &[[u8;32]], consensus: &ConsensusSettings) -> Result<(crate::block::Block, crate::transaction::Transaction), QanError>{
+    let mut nemezis_data = NemezisData{ authorities: authorities.to_vec(), consensus: consensus.clone(), balances: Vec::new(), chain_id: new_chain_id(), address_prefix: String::new() };
+    nemezis_data.authorities.push(crate::util::do_hash(&keys.get_glp_pk_bytes()));
+    nemezis_data.balances = nemezis_data.authorities.iter().map(|a|(*a, crate::state::GENESIS_BALANCE)).collect();
+    let nemezis_body = crate::transaction::TxBody::new([0;32], 0, 0, serde_json::to_vec(&nemezis_data).map_err(|e|QanError::Serde(e))?);
+    let nemesis_tx = crate::transaction::Transaction::new(nemezis_body, &keys.glp, &crate::chainspec::Domain::new(&nemezis_data.chain_id))?;
     let mut nemezis_vec = Vec::new();
     nemezis_vec.push(nemesis_tx.hash()?);
-    let block = crate::block::Block::new([0;32], nemezis_vec, &keys.glp, 0)?;
+    let state_root = crate::state::genesis_root(&nemezis_data.balances)?;
+    let block = crate::block::Block::with_roots([0;32], nemezis_vec, state_root, crate::block::EMPTY_ROOT, &keys.glp, &crate::chainspec::Domain::new(&nemezis_data.chain_id), 0)?;
     let mut pemf = std::fs::File::create(std::path::Path::new("qNEMEZIS")).map_err(|e|QanError::Io(e))?;
     pemf.write_all(&serde_json::to_vec(&block).map_err(|e|QanError::Serde(e))?);
     Ok((block, nemesis_tx))
-}
\ No newline at end of file
+}
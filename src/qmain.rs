@@ -1,21 +1,26 @@
-use crate::user_client::{start_client, start_stdin_handler};
+use crate::user_client::{start_client, start_stdin_handler, start_slot_ticker};
 use crate::transaction::{Transaction, TxBody};
 use natsclient::{self, ClientOptions};
 use std::{
     time::Duration,
-    sync::{Arc, RwLock},
+    sync::{Arc, RwLock, atomic::{AtomicU64, Ordering}},
     path::Path,
     fs::File,
     io::Read,
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
 };
 use crate::pk::{PATHNAME, PetKey};
 use crate::event::{SyncType, Event};
-use crate::block::{Block, merge};
+use crate::block::{Block, EMPTY_ROOT};
 use crate::conset::ConsensusSettings;
 use crate::util::{do_hash, vec_to_arr};
-use crate::sync::{sync, genesis_getter};
+use crate::sync::{sync, genesis_getter, fetch_transaction, get_pubkey};
 use crate::error::QanError;
+use crate::address::Address;
+use crate::mempool::{Mempool, Rejection};
+use crate::raft::{Raft, NatsTransport, Envelope};
+use crate::finality::{Vote, VoteTracker, VOTE_SUBJECT};
+use crate::evidence::{Evidence, EVIDENCE_SUBJECT};
 #[cfg(feature = "quantum")]
 use glp::glp::{GlpPk, gen_pk};
 use rocksdb::DB;
@@ -25,6 +30,8 @@ pub fn qmain() -> Result<(), Box<dyn std::error::Error>> {
     // crate::gendata::gen_data();
 
     let (config, log_handle) = crate::config::Config::get_config()?;
+    let genesis_authorities = config.genesis_authorities()?;
+    let spec = crate::chainspec::ChainSpec::load(&config.chain_spec)?;
     let opts = ClientOptions::builder()
         .cluster_uris(config.bootstrap)
         .connect_timeout(Duration::from_secs(10))
@@ -43,143 +50,263 @@ pub fn qmain() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut client = start_client(opts, &sndr)?;
     
-    let mut head : Block = genesis_getter("qNEMEZIS", &keys, &client)?;
+    let mut genesis_settings = ConsensusSettings::default();
+    genesis_settings.set_slot_duration(config.slot_duration);
+    if crate::codec::upgrade_databases("qdb.db", &["qtx.db", "qaccounts.db", "qreceipts.db"], "genesis")? {
+        warn!("the databases were written with an older encoding, starting over from the genesis");
+    }
+    let mut head : Block = genesis_getter("qNEMEZIS", &keys, &client, &genesis_authorities, &genesis_settings, spec.as_ref())?;
     let nemezis_hash = head.hash();
-    let mut block_height = sync(&client, config.spv, &mut head)?;
+    let mut block_height = sync(&client, config.spv, !config.raft, &mut head)?;
     info!("genezis hash: {:?}", hex::encode(&nemezis_hash));
-    let consensus_settings = ConsensusSettings::default();
 
     let mut txdb = DB::open_default("qtx.db").map_err(|e|QanError::Database(e))?;
     let mut blockdb = DB::open_default("qdb.db").map_err(|e|QanError::Database(e))?;
     let mut accounts = DB::open_default("qaccounts.db").map_err(|e|QanError::Database(e))?;
+    let mut receipts = DB::open_default("qreceipts.db").map_err(|e|QanError::Database(e))?;
     let mut pubkeys = DB::open_default("qpubkeys.db").map_err(|e|QanError::Database(e))?;
+    let domain = crate::chainspec::Domain::load(&blockdb)?;
     pubkeys.put(mypk_hash, &keys.get_glp_pk_bytes()).map_err(|e|QanError::Database(e))?;
-    let mut mempool : HashMap<[u8;32], Transaction> = HashMap::new();
+    let mut authority_cache = crate::authority::AuthorityCache::load(&blockdb, &head)?;
+    let authorities = authority_cache.set_at(block_height+1).clone();
+    info!("my address: {}", Address(mypk_hash));
+    info!("I am {}an authority", if authorities.contains(&mypk_hash) {""} else {"not "});
+    //the raft cluster is made of the authorities of the next block, the main loop starts it
+    let mut raft : Option<Raft> = None;
+    let mut committed : VecDeque<Vec<u8>> = VecDeque::new();
+    let mut finalized = crate::finality::load_finalized(&blockdb)?;
+    let mut votes = VoteTracker::new();
+    let mut last_vote = block_height;
+    let mut mempool = Mempool::new(config.mempool.clone());
 
     let mut vm = Arc::new(RwLock::new(crate::vm::VM::new()));
-    let mut pool_size : usize = 0;
+    let mut own_nonce : u64 = 0;
 
     client.publish("PubKey", &keys.get_glp_pk_bytes(), None).map_err(|e|QanError::Nats(e))?;
     start_stdin_handler(&sndr);
+    let slot_duration = Arc::new(AtomicU64::new(crate::governance::settings_at(&blockdb, block_height+1)?.slot_duration()));
+    start_slot_ticker(&sndr, Arc::clone(&slot_duration));
     let mut txdb = Arc::new(txdb);
     let mut blockdb = Arc::new(blockdb);
     let mut accounts = Arc::new(accounts);
-    crate::rpc::start_rpc(sndr, Arc::clone(&blockdb), Arc::clone(&txdb), Arc::clone(&accounts), config.rpc_auth, Arc::clone(&vm));
+    let mut receipts = Arc::new(receipts);
+    crate::rpc::start_rpc(sndr, Arc::clone(&blockdb), Arc::clone(&txdb), Arc::clone(&accounts), Arc::clone(&receipts), config.rpc_auth, Arc::clone(&vm));
 
     println!("main functionality starting");
     'main:loop{
-        let ev = recv.recv().expect("internal channel failed on receive");
+        authority_cache.update(&blockdb, &head)?;
+        let authorities = authority_cache.set_at(head.height());
+        if authorities.contains(&mypk_hash) && head.height() > last_vote {
+            //vote once per height for the new head
+            last_vote = head.height();
+            let vote = Vote::new(head.hash(), head.height(), &keys.glp, &domain)?;
+            if votes.add(&vote).is_ok() {
+                client.publish(VOTE_SUBJECT, &serde_json::to_vec(&vote).map_err(|e|QanError::Serde(e))?, None).map_err(|e|QanError::Nats(e))?;
+            }
+        }
+        if head.height() > finalized && votes.count(&head.hash()) >= crate::finality::threshold(authorities.len())
+            && crate::finality::extends_finalized(&blockdb, &head, finalized)? {
+            finalized = crate::finality::finalize(&blockdb, &mut votes, &head)?;
+        }
+        if config.raft {
+            //the cluster follows the authority set, and the entries of final blocks are dropped from the log
+            let members = authority_cache.set_at(head.height()+1).members().clone();
+            if !members.contains(&mypk_hash) { raft = None }
+            else if let Some(r) = raft.as_mut() { r.set_peers(members) }
+            else { raft = Some(Raft::new(mypk_hash, members)) }
+            if let Some(r) = raft.as_mut() {
+                r.compact(|data| serde_json::from_slice::<Block>(data).map_or(true, |b|b.height() <= finalized));
+            }
+        }
+        let (ev, replicated) = match committed.pop_front(){
+            Some(bl) => (Event::Block(bl), true),
+            None => (recv.recv().expect("internal channel failed on receive"), false)
+        };
         match ev {
             Event::Block(bl)=>{
                 let b : Block = serde_json::from_slice(&bl).map_err(|e|QanError::Serde(e))?;
                 info!("my_head: {:?} \nincoming_head: {:?}", hex::encode(&head.hash()), hex::encode(b.hash()));
-                let pubkey : GlpPk = if b.proposer_pub == mypk_hash { keys.get_glp_pk() }else{
-                     match pubkeys.get(&b.proposer_pub).map_err(|e|QanError::Database(e))?{
+                //authorities running raft only import blocks their log committed
+                if raft.is_some() && !replicated { continue'main }
+                let authorities = authority_cache.set_at(b.height());
+                if !authorities.contains(&b.proposer()) {
+                    warn!("rejected block {:?} from non-authority {}", hex::encode(b.hash()), Address(b.proposer()));
+                    continue'main
+                }
+                let pubkey : GlpPk = if b.proposer() == mypk_hash { keys.get_glp_pk() }else{
+                     match pubkeys.get(&b.proposer()).map_err(|e|QanError::Database(e))?{
                         Some(pk) => {
                             GlpPk::from_bytes(&pk)
                         }, None => {
-                            let pubkey_vec : Vec<u8> = match client.request("PubKey", &b.proposer_pub, std::time::Duration::new(4,0)){
+                            let pubkey_vec : Vec<u8> = match client.request("PubKey", &b.proposer(), std::time::Duration::new(4,0)){
                                 Ok(pk) => pk.payload,
                                 Err(_) => continue'main
                             };
-                            pubkeys.put(&b.proposer_pub ,&pubkey_vec).map_err(|e|QanError::Database(e))?;
+                            pubkeys.put(&b.proposer() ,&pubkey_vec).map_err(|e|QanError::Database(e))?;
                             GlpPk::from_bytes(&pubkey_vec)
                         }
                     }
                 };
-                if !b.verify(&pubkey)? || b.hash() == head.hash() { continue'main }
-                if b.height > block_height+1{
-                    block_height = sync(&client, config.spv, &mut head)?;
-                }else if b.height == block_height+1 {
-                    if b.prev_hash() != head.hash() { continue'main }
-                }else {
-                    continue'main
-                }
-                match blockdb.get_pinned(&b.hash()) {
-                    Err(_)      =>{panic!("db failure")}
-                    Ok(Some(_)) =>{
-                        //TODO consensus check
-                        if b.hash() == head.hash() && b.sig[0] < head.sig[0]{
-                            head = b;
-                            blockdb.put("block".to_owned()+&block_height.to_string(), &head.hash()).map_err(|e|QanError::Database(e))?;
-                            blockdb.put(head.hash(), bl).map_err(|e|QanError::Database(e))?;
-                            blockdb.flush().map_err(|e|QanError::Database(e))?;
-                            info!("new head accepted: {:?}", hex::encode(&head.hash()));
+                if !b.verify(&pubkey, &domain)? || b.hash() == head.hash() { continue'main }
+                if let Some(first) = crate::evidence::record(&blockdb, &b)? {
+                    let evidence = Evidence::blocks(first, b.clone(), pubkey.to_bytes());
+                    if crate::evidence::store(&blockdb, &evidence)? {
+                        warn!("{} proposed two blocks at height {}", Address(b.proposer()), b.height());
+                        client.publish(EVIDENCE_SUBJECT, &serde_json::to_vec(&evidence).map_err(|e|QanError::Serde(e))?, None).map_err(|e|QanError::Nats(e))?;
+                        if authorities.contains(&mypk_hash) {
+                            let nonce = crate::state::next_nonce(&accounts, &mempool, &mypk_hash, own_nonce)?;
+                            own_nonce = nonce+1;
+                            let tx = crate::evidence::report_tx(evidence, nonce, config.spice_price, &keys.glp, &domain)?;
+                            client.publish("tx.broadcast", &serde_json::to_vec(&tx).map_err(|e|QanError::Serde(e))?, None).map_err(|e|QanError::Nats(e))?;
                         }
-                        continue'main
                     }
-                    Ok(None) => {
-                        if b.height == head.height && b.merkle() == head.merkle() && head.timestamp() < b.timestamp(){
-                            blockdb.delete(head.hash()).map_err(|e|QanError::Database(e))?;
-                            head = b;
-                            blockdb.put("block".to_owned()+&head.height.to_string(), head.hash()).map_err(|e|QanError::Database(e))?;
-                            blockdb.put(head.hash(), bl).map_err(|e|QanError::Database(e))?;
-                            blockdb.flush().map_err(|e|QanError::Database(e))?;
-                            info!("new head accepted: {:?}", hex::encode(&head.hash()));
-                            continue'main
-                        }
-                        let tree = static_merkle_tree::Tree::from_hashes(b.hashedblock.blockdata.txes.clone(),merge);
-                        let merkle_root : Vec<u8> = tree.get_root_hash().expect("couldn't get root while building merkle tree on received block").to_vec();
-                        if merkle_root!=b.hashedblock.blockdata.merkle_root { continue'main }
-                        for k in b.hashedblock.blockdata.txes.iter() {
-                            if !mempool.contains_key(k){
-                                if txdb.get_pinned(&k).map_err(|e|QanError::Database(e))?.is_some(){continue'main}
-                                let req_tx = match client.request(
-                                    "Synchronize", 
-                                    &serde_json::to_vec(&SyncType::TransactionAtHash(k.clone())).map_err(|e|QanError::Serde(e))?,
-                                    std::time::Duration::new(4,0)){
-                                        Ok(h)=>h.payload,
-                                        Err(e)=>{ error!("{}",e); continue'main }
-                                };
-                                let tx : Transaction = serde_json::from_slice(&req_tx).map_err(|e|QanError::Serde(e))?;
-                                let pubkey = if b.proposer_pub == mypk_hash { keys.get_glp_pk() }else{
-                                    match pubkeys.get(&b.proposer_pub).map_err(|e|QanError::Database(e))?{
-                                        Some(pk) => {
-                                            GlpPk::from_bytes(&pk) 
-                                        }, None => {
-                                            let pubkey_vec : Vec<u8> = match client.request("PubKey", &b.proposer_pub, std::time::Duration::new(1,0)){
-                                                Ok(pk) => pk.payload,
-                                                Err(_) => continue'main
-                                            };
-                                            pubkeys.put(&b.proposer_pub ,&pubkey_vec).map_err(|e|QanError::Database(e))?;
-                                            GlpPk::from_bytes(&pubkey_vec)
-                                        }
+                }
+                if blockdb.get_pinned(&b.hash()).map_err(|e|QanError::Database(e))?.is_some() { continue'main }
+                let consensus_settings = crate::governance::settings_at(&blockdb, b.height())?;
+                if b.prev_hash() != head.hash() {
+                    match crate::fork::load_block(&blockdb, &b.prev_hash())?{
+                        Some(parent) => {
+                            //block on a side chain, store it and switch to it if fork choice prefers it
+                            //its slot and limits come from the settings and authorities of its own ancestry
+                            let (schedule, history) = crate::governance::on_branch(&blockdb, &txdb, &head, &parent)?;
+                            let consensus_settings = schedule.settings_at(b.height());
+                            let authorities = history.set_at(b.height()).clone();
+                            if b.height() != parent.height()+1 || b.height() <= finalized
+                                || (!config.raft && !crate::slot::is_proposer(&authorities, consensus_settings.slot_duration(), b.height(), parent.timestamp(), b.timestamp(), &b.proposer()))
+                                || b.timestamp() > crate::util::timestamp() + consensus_settings.slot_duration() {
+                                warn!("rejected side block {:?}", hex::encode(b.hash()));
+                                continue'main
+                            }
+                            if !b.check_body()
+                                || !consensus_settings.within_capacity(b.body.txes.len(), 0) { continue'main }
+                            let mut bytes = 0;
+                            let mut fetched = Vec::new();
+                            for k in b.body.txes.iter() {
+                                if let Some(t) = txdb.get(&k).map_err(|e|QanError::Database(e))? {
+                                    bytes += serde_json::from_slice::<Transaction>(&t).map_err(|e|QanError::Serde(e))?.len();
+                                    continue
+                                }
+                                match mempool.get(k){
+                                    Some(x) => {
+                                        bytes += x.len();
+                                        txdb.put(k, serde_json::to_vec(&x).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?
+                                    },
+                                    None => match fetch_transaction(&client, &pubkeys, k)?{
+                                        Some((tx, raw)) => fetched.push((*k, tx, raw)),
+                                        None => continue'main
                                     }
-                                };
-                                if tx.verify(&pubkey)?{
-                                    mempool.insert(*k, tx);
-                                }else{
-                                    panic!("tx invalid in chain");
                                 }
                             }
-                        }
-
-                        for k in b.hashedblock.blockdata.txes.iter(){
-                            match mempool.remove(k){
-                                Some(x)=>{
-                                    txdb.put(k, serde_json::to_vec(&x).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
-                                },
-                                None=>{
-                                    panic!("memory pool didn't hold a transaction i already ask for and supposedly received");
+                            let txes : Vec<&Transaction> = fetched.iter().map(|(_, tx, _)|tx).collect();
+                            if let Some(i) = crate::sigverify::first_invalid(&pubkeys, &txes, &domain)? {
+                                warn!("rejected side block {:?}, transaction {:?} has an invalid signature", hex::encode(b.hash()), hex::encode(fetched[i].0));
+                                continue'main
+                            }
+                            for (k, tx, raw) in fetched {
+                                bytes += tx.len();
+                                txdb.put(k, raw).map_err(|e|QanError::Database(e))?
+                            }
+                            txdb.flush().map_err(|e|QanError::Database(e))?;
+                            if !consensus_settings.within_capacity(b.body.txes.len(), bytes) {
+                                warn!("rejected side block {:?} over capacity", hex::encode(b.hash()));
+                                continue'main
+                            }
+                            blockdb.put(b.hash(), &bl).map_err(|e|QanError::Database(e))?;
+                            blockdb.flush().map_err(|e|QanError::Database(e))?;
+                            info!("stored side block {:?} at height {}", hex::encode(b.hash()), b.height());
+                            if crate::fork::prefer(&b, &head) {
+                                match crate::fork::reorg(&blockdb, &txdb, &accounts, &receipts, &mut mempool, &head, &b, finalized){
+                                    Ok(h) => {
+                                        block_height = h;
+                                        head = b;
+                                        info!("new head accepted: {:?}", hex::encode(&head.hash()));
+                                    },
+                                    Err(e) => error!("reorg failed: {}", e)
                                 }
                             }
+                        },
+                        None => {
+                            if b.height() > block_height+1 {
+                                block_height = sync(&client, config.spv, !config.raft, &mut head)?;
+                            }
+                        }
+                    }
+                    continue'main
+                }
+                if b.height() != block_height+1 { continue'main }
+                if (!config.raft && !crate::slot::is_proposer(&authorities, consensus_settings.slot_duration(), b.height(), head.timestamp(), b.timestamp(), &b.proposer()))
+                    || b.timestamp() > crate::util::timestamp() + consensus_settings.slot_duration() {
+                    warn!("rejected block {:?}, {} does not own its slot", hex::encode(b.hash()), Address(b.proposer()));
+                    continue'main
+                }
+                if !b.check_body()
+                    || !consensus_settings.within_capacity(b.body.txes.len(), 0) { continue'main }
+                let mut fetched = Vec::new();
+                for k in b.body.txes.iter() {
+                    if crate::fork::tx_block(&blockdb, k)?.is_some() { continue'main }
+                    if mempool.contains_key(k) { continue }
+                    match txdb.get(k).map_err(|e|QanError::Database(e))?{
+                        Some(x) => { mempool.insert(*k, serde_json::from_slice(&x).map_err(|e|QanError::Serde(e))?); },
+                        None => match fetch_transaction(&client, &pubkeys, k)?{
+                            Some((tx, _)) => fetched.push((*k, tx)),
+                            None => continue'main
                         }
-                        block_height+=1;
-                        head = b;
-                        let head_hash = &head.hash();
-                        blockdb.put("height", block_height.to_string()).map_err(|e|QanError::Database(e))?;
-                        blockdb.put("block".to_owned() + &block_height.to_string(), &head_hash).map_err(|e|QanError::Database(e))?;
-                        blockdb.put(&head_hash, bl).map_err(|e|QanError::Database(e))?;
-                        blockdb.flush().map_err(|e|QanError::Database(e))?;
-                        txdb.flush().map_err(|e|QanError::Database(e))?;
-                        info!("at height {} is block {:?}", block_height, hex::encode(head_hash));
-                        pool_size = 0;
                     }
                 }
+                //transactions we didn't have yet are verified together on the worker pool
+                let txes : Vec<&Transaction> = fetched.iter().map(|(_, tx)|tx).collect();
+                if let Some(i) = crate::sigverify::first_invalid(&pubkeys, &txes, &domain)? {
+                    warn!("rejected block {:?}, transaction {:?} has an invalid signature", hex::encode(b.hash()), hex::encode(fetched[i].0));
+                    continue'main
+                }
+                for (k, tx) in fetched { mempool.insert(k, tx); }
+                let bytes : usize = b.body.txes.iter().map(|k|mempool[k].len()).sum();
+                if !consensus_settings.within_capacity(b.body.txes.len(), bytes) {
+                    warn!("rejected block {:?} over capacity", hex::encode(b.hash()));
+                    continue'main
+                }
+                let txes : Vec<&Transaction> = b.body.txes.iter().map(|k|&mempool[k]).collect();
+                let executed = crate::receipt::execute(&accounts, &txes)?;
+                if crate::state::root_after(&accounts, &txes, &executed, &b.proposer())? != Some(b.signed.header.state_root)
+                    || crate::receipt::receipts_root(&executed.receipts)? != b.signed.header.receipts_root {
+                    warn!("rejected block {:?}, its transfers are invalid or its roots don't match", hex::encode(b.hash()));
+                    continue'main
+                }
+
+                for k in b.body.txes.iter(){
+                    match mempool.remove(k){
+                        Some(x)=>{
+                            txdb.put(k, serde_json::to_vec(&x).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
+                        },
+                        None=>{
+                            panic!("memory pool didn't hold a transaction i already ask for and supposedly received");
+                        }
+                    }
+                }
+                block_height+=1;
+                head = b;
+                let head_hash = &head.hash();
+                blockdb.put("height", block_height.to_string()).map_err(|e|QanError::Database(e))?;
+                crate::fork::index_block(&blockdb, &txdb, &accounts, &receipts, &head)?;
+                blockdb.put(&head_hash, &bl).map_err(|e|QanError::Database(e))?;
+                blockdb.flush().map_err(|e|QanError::Database(e))?;
+                txdb.flush().map_err(|e|QanError::Database(e))?;
+                info!("at height {} is block {:?}", block_height, hex::encode(head_hash));
+                crate::state::drop_stale(&accounts, &mut mempool)?;
+                if replicated && raft.as_ref().map_or(false, |r|r.is_leader()) {
+                    //nodes outside of the raft cluster learn committed blocks the usual way
+                    client.publish("block.propose", &bl, None).map_err(|e|QanError::Nats(e))?;
+                }
             },
             Event::Transaction(trax)=>{
                 //handle incoming transaction
-                let tx : Transaction = serde_json::from_slice(&trax).map_err(|e|QanError::Serde(e))?;
+                let tx = match crate::validation::pre_validate(&trax, crate::util::timestamp()){
+                    Ok(t) => t,
+                    Err(r) => {
+                        warn!("dropped transaction: {}", r);
+                        continue'main
+                    }
+                };
                 let pubkey = if tx.pubkey == mypk_hash { keys.get_glp_pk() }else{
                      match pubkeys.get(&tx.pubkey).map_err(|e|QanError::Database(e))?{
                         Some(pk) => {
@@ -194,51 +321,190 @@ pub fn qmain() -> Result<(), Box<dyn std::error::Error>> {
                         }
                     }
                 };
-                if tx.verify(&pubkey)?{
-                    pool_size += tx.len();
+                if tx.verify(&pubkey, &domain)?{
+                    if !crate::state::can_admit(&accounts, &mempool, &tx)? {
+                        warn!("rejected transaction of {}, nonce used or balance too low", Address(tx.pubkey));
+                        //our own nonces continue from the chain and the mempool again
+                        if tx.pubkey == mypk_hash { own_nonce = 0 }
+                        continue'main
+                    }
                     let txh = tx.hash()?;
+                    let sender = tx.pubkey;
                     let recipient = tx.transaction.recipient;
                     if recipient == [0u8;32] {debug!("{:?}", String::from_utf8_lossy(&tx.get_data()))};
-                    match mempool.insert(txh, tx){
-                        Some(_)=>{continue'main},
-                        None=>{
-                            match accounts.get(&recipient){
-                                Ok(Some(value))=>{
-                                    accounts.put(recipient, 
-                                        (String::from_utf8(value).expect("couldn't read stored account tx count").parse::<u64>()
-                                            .expect("couldn't parse account tx count")+1).to_string())
-                                                .map_err(|e|QanError::Database(e))?;
-                                },
-                                Ok(None)=>{accounts.put(recipient,1.to_string()).map_err(|e|QanError::Database(e))?;},
-                                Err(_)=>{panic!("account db error")}
+                    match mempool.add(txh, tx, crate::util::timestamp()){
+                        Err(r)=>{
+                            warn!("rejected transaction {:?} of {}: {}", hex::encode(txh), Address(sender), r);
+                            if sender == mypk_hash && r != Rejection::Duplicate { own_nonce = 0 }
+                            continue'main
+                        },
+                        Ok(evicted)=>{
+                            for e in evicted {
+                                debug!("evicted transaction of {} from the full mempool", Address(e.pubkey));
+                                if e.pubkey == mypk_hash { own_nonce = 0 }
                             }
                         }
                     }     
                 }
-                if consensus_settings.check_limiters(mempool.len(),pool_size,head.timestamp()){
-                    let mut txhashese: Vec<[u8;32]> = mempool.iter().map(|(k, v)| {
-                        txdb.put(k, serde_json::to_vec(&v).unwrap()).unwrap();
-                        k.to_owned()
-                    } ).collect();
-                    txhashese.sort();
+            },
+            Event::Tick=>{
+                mempool.prune(crate::util::timestamp());
+                let consensus_settings = crate::governance::settings_at(&blockdb, block_height+1)?;
+                slot_duration.store(consensus_settings.slot_duration(), Ordering::Relaxed);
+                let authorities = authority_cache.set_at(block_height+1);
+                if let Some(r) = raft.as_mut() {
+                    r.tick(&NatsTransport::new(&client, &keys.glp, &domain))?;
+                    committed.extend(r.take_committed());
+                    //the leader proposes once everything before is committed and imported
+                    if r.is_leader() && !r.has_uncommitted() && committed.is_empty()
+                        && consensus_settings.check_limiters(mempool.len(),mempool.bytes(),head.timestamp()){
+                        let txhashese = crate::state::select_executable(&accounts, &mempool, consensus_settings.select_transactions(&mempool, |id|Ok(crate::state::AccountState::load(&accounts, id)?.nonce))?, &mypk_hash)?;
+                        let txes : Vec<&Transaction> = txhashese.iter().map(|k|&mempool[k]).collect();
+                        let executed = crate::receipt::execute(&accounts, &txes)?;
+                        let state_root = crate::state::root_after(&accounts, &txes, &executed, &mypk_hash)?.unwrap_or(EMPTY_ROOT);
+                        let receipts_root = crate::receipt::receipts_root(&executed.receipts)?;
+                        let new = Block::with_roots(head.hash(), txhashese, state_root, receipts_root, &keys.glp, &domain, block_height+1)?;
+                        r.propose(serde_json::to_vec(&new).map_err(|e|QanError::Serde(e))?, &NatsTransport::new(&client, &keys.glp, &domain))?;
+                        info!("proposed block {:?} to raft", hex::encode(new.hash()));
+                    }
+                    continue'main
+                }
+                //propose a block if this node owns the current slot
+                let slot = crate::slot::slot(head.timestamp(), crate::util::timestamp(), consensus_settings.slot_duration());
+                if crate::slot::expected_proposer(&authorities, block_height+1, slot) != mypk_hash { continue'main }
+                if consensus_settings.check_limiters(mempool.len(),mempool.bytes(),head.timestamp()){
+                    let txhashese = crate::state::select_executable(&accounts, &mempool, consensus_settings.select_transactions(&mempool, |id|Ok(crate::state::AccountState::load(&accounts, id)?.nonce))?, &mypk_hash)?;
+                    let txes : Vec<&Transaction> = txhashese.iter().map(|k|&mempool[k]).collect();
+                    let executed = crate::receipt::execute(&accounts, &txes)?;
+                    let state_root = crate::state::root_after(&accounts, &txes, &executed, &mypk_hash)?.unwrap_or(EMPTY_ROOT);
+                    let receipts_root = crate::receipt::receipts_root(&executed.receipts)?;
                     for k in &txhashese{
                         trace!("{}", hex::encode(k));
-                        mempool.remove(k).unwrap();
+                        let tx = mempool.remove(k).unwrap();
+                        txdb.put(k, serde_json::to_vec(&tx).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
                     }
-                    pool_size = 0;
                     block_height +=1;
-                    let new = Block::new(head.hash(), txhashese, &keys.glp, block_height)?;
+                    let new = Block::with_roots(head.hash(), txhashese, state_root, receipts_root, &keys.glp, &domain, block_height)?;
                     debug!("{} chains on top of {}",hex::encode(&new.hash()),hex::encode(&head.hash()));
                     head = new;
                     let head_hash = head.hash();
                     let serde_head = serde_json::to_vec(&head).map_err(|e|QanError::Serde(e))?;
                     blockdb.put("height", block_height.to_string()).map_err(|e|QanError::Database(e))?;
-                    blockdb.put("block".to_owned()+&block_height.to_string(), &head_hash).map_err(|e|QanError::Database(e))?;
+                    crate::fork::index_block(&blockdb, &txdb, &accounts, &receipts, &head)?;
                     blockdb.put(&head_hash, &serde_head).map_err(|e|QanError::Database(e))?;
                     info!("at height {} is block {:?}", block_height, hex::encode(&head_hash));
+                    crate::state::drop_stale(&accounts, &mut mempool)?;
                     client.publish("block.propose", &serde_head, None).map_err(|e|QanError::Nats(e))?;
                 }
             },
+            Event::Raft(msg)=>{
+                let env : Envelope = match serde_json::from_slice(&msg){
+                    Ok(e) => e,
+                    Err(_) => { warn!("malformed raft message"); continue'main }
+                };
+                if env.to != mypk_hash { continue'main }
+                if raft.is_none() { continue'main }
+                let pubkey = match get_pubkey(&client, &pubkeys, &env.from)?{
+                    Some(pk) => GlpPk::from_bytes(&pk),
+                    None => continue'main
+                };
+                if !env.verify(&pubkey, &domain)? {
+                    warn!("raft message with an invalid signature from {}", Address(env.from));
+                    continue'main
+                }
+                if let Some(r) = raft.as_mut() {
+                    r.step(env.from, env.msg, &NatsTransport::new(&client, &keys.glp, &domain))?;
+                    committed.extend(r.take_committed());
+                }
+            },
+            Event::Vote(v)=>{
+                let vote : Vote = match serde_json::from_slice(&v){
+                    Ok(v) => v,
+                    Err(_) => { warn!("malformed finality vote"); continue'main }
+                };
+                if vote.vote.height <= finalized { continue'main }
+                //votes for blocks off the finalized chain are ignored, the ones for blocks we don't have yet are checked once we do
+                if let Some(b) = crate::fork::load_block(&blockdb, &vote.vote.block_hash)? {
+                    if !crate::finality::extends_finalized(&blockdb, &b, finalized)? { continue'main }
+                }
+                let authorities = authority_cache.set_at(vote.vote.height);
+                if !authorities.contains(&vote.voter) { continue'main }
+                let pubkey = if vote.voter == mypk_hash { keys.get_glp_pk() }else{
+                    match get_pubkey(&client, &pubkeys, &vote.voter)?{
+                        Some(pk) => GlpPk::from_bytes(&pk),
+                        None => continue'main
+                    }
+                };
+                if !vote.verify(&pubkey, &domain)? {
+                    warn!("invalid finality vote from {}", Address(vote.voter));
+                    continue'main
+                }
+                let count = match votes.add(&vote) {
+                    Ok(c) => c,
+                    Err(first) => {
+                        let evidence = Evidence::votes(first, vote, pubkey.to_bytes());
+                        if crate::evidence::store(&blockdb, &evidence)? {
+                            warn!("{} voted for two blocks at height {}", Address(evidence.offender()), evidence.height());
+                            client.publish(EVIDENCE_SUBJECT, &serde_json::to_vec(&evidence).map_err(|e|QanError::Serde(e))?, None).map_err(|e|QanError::Nats(e))?;
+                            if authorities.contains(&mypk_hash) {
+                                let nonce = crate::state::next_nonce(&accounts, &mempool, &mypk_hash, own_nonce)?;
+                                own_nonce = nonce+1;
+                                let tx = crate::evidence::report_tx(evidence, nonce, config.spice_price, &keys.glp, &domain)?;
+                                client.publish("tx.broadcast", &serde_json::to_vec(&tx).map_err(|e|QanError::Serde(e))?, None).map_err(|e|QanError::Nats(e))?;
+                            }
+                        }
+                        continue'main
+                    }
+                };
+                if count < crate::finality::threshold(authorities.len()) { continue'main }
+                //blocks we don't have yet are finalized once they become the head
+                let b = match crate::fork::load_block(&blockdb, &vote.vote.block_hash)?{
+                    Some(b) => b,
+                    None => continue'main
+                };
+                if !crate::finality::extends_finalized(&blockdb, &b, finalized)? {
+                    warn!("block {:?} got enough votes but doesn't descend from the finalized block", hex::encode(b.hash()));
+                    continue'main
+                }
+                if !crate::fork::is_canonical(&blockdb, &b)? {
+                    //the authorities finalized a side chain, follow them
+                    match crate::fork::reorg(&blockdb, &txdb, &accounts, &receipts, &mut mempool, &head, &b, finalized){
+                        Ok(h) => {
+                            block_height = h;
+                            head = b.clone();
+                            info!("new head accepted: {:?}", hex::encode(&head.hash()));
+                        },
+                        Err(e) => { error!("couldn't switch to finalized block {:?}: {}", hex::encode(b.hash()), e); continue'main }
+                    }
+                }
+                finalized = crate::finality::finalize(&blockdb, &mut votes, &b)?;
+            },
+            Event::SystemCall(call)=>{
+                let nonce = crate::state::next_nonce(&accounts, &mempool, &mypk_hash, own_nonce)?;
+                own_nonce = nonce+1;
+                let tx = Transaction::new(TxBody::new(crate::governance::SYSTEM_ACCOUNT, nonce, 0, serde_json::to_vec(&call).map_err(|e|QanError::Serde(e))?).with_spice_price(config.spice_price), &keys.glp, &domain)?;
+                client.publish("tx.broadcast", &serde_json::to_vec(&tx).map_err(|e|QanError::Serde(e))?, None).map_err(|e|QanError::Nats(e))?;
+            },
+            Event::Transfer(to, amount)=>{
+                let nonce = crate::state::next_nonce(&accounts, &mempool, &mypk_hash, own_nonce)?;
+                own_nonce = nonce+1;
+                let tx = Transaction::new(TxBody::new(to, nonce, amount, Vec::new()).with_spice_price(config.spice_price), &keys.glp, &domain)?;
+                client.publish("tx.broadcast", &serde_json::to_vec(&tx).map_err(|e|QanError::Serde(e))?, None).map_err(|e|QanError::Nats(e))?;
+            },
+            Event::Evidence(e)=>{
+                let evidence : Evidence = match serde_json::from_slice(&e){
+                    Ok(e) => e,
+                    Err(_) => { warn!("malformed evidence"); continue'main }
+                };
+                if !evidence.verify(&domain)? || !crate::evidence::store(&blockdb, &evidence)? { continue'main }
+                warn!("{} {} at height {}", Address(evidence.offender()), evidence.offence(), evidence.height());
+                if authorities.contains(&mypk_hash) {
+                    let nonce = crate::state::next_nonce(&accounts, &mempool, &mypk_hash, own_nonce)?;
+                    own_nonce = nonce+1;
+                    let tx = crate::evidence::report_tx(evidence, nonce, config.spice_price, &keys.glp, &domain)?;
+                    client.publish("tx.broadcast", &serde_json::to_vec(&tx).map_err(|e|QanError::Serde(e))?, None).map_err(|e|QanError::Nats(e))?;
+                }
+            },
             Event::RawTransaction(tx)=>{
                 client.publish("tx.broadcast", &tx, None).map_err(|e|QanError::Nats(e))?;
             },
@@ -247,7 +513,9 @@ pub fn qmain() -> Result<(), Box<dyn std::error::Error>> {
             //     client.publish("tx.broadcast", &serde_json::to_vec(&tx).map_err(|e|QanError::Serde(e))?, None).map_err(|e|QanError::Nats(e))?;
             // },
             Event::Chat(msg)=>{
-                let tx = Transaction::new(TxBody::new([0u8;32], 0, msg), &keys.glp)?;
+                let nonce = crate::state::next_nonce(&accounts, &mempool, &mypk_hash, own_nonce)?;
+                own_nonce = nonce+1;
+                let tx = Transaction::new(TxBody::new([0u8;32], nonce, 0, msg).with_spice_price(config.spice_price), &keys.glp, &domain)?;
                 client.publish("tx.broadcast", &serde_json::to_vec(&tx).map_err(|e|QanError::Serde(e))?, None).map_err(|e|QanError::Nats(e))?;
             }
             Event::GetHeight(sendr)=>{
@@ -330,6 +598,13 @@ pub fn qmain() -> Result<(), Box<dyn std::error::Error>> {
                             None => {println!("someone asked for a block i don't have: {}", hex::encode(&hash)); continue'main}
                         }
                     },
+                    SyncType::HeaderAtHash(hash) => {
+                        //get the signed header of a block, without its body
+                        match crate::fork::load_block(&blockdb, &hash)?{
+                            Some(b) => serde_json::to_vec(b.header()).map_err(|e|QanError::Serde(e))?,
+                            None => continue'main
+                        }
+                    },
 
                     _ => { error!("wrong SyncMessage");continue'main }
                 }, 
@@ -0,0 +1,617 @@
+//! Raft replicated block log among the authorities.
+//! The state machine is driven by the main loop through `tick` and `step`, messages go out through a `Transport`.
+//! Log entries are serialized blocks, they are handed out for import only after a majority of the authorities stored them.
+//! The log is kept in memory, a restarting node gets it replicated again by the leader. Entries of finalized blocks are compacted away,
+//! a follower behind the compacted part is told to skip it and gets those blocks through the usual sync.
+//! The cluster is the authority set active at the next block, it changes with the set at epoch boundaries.
+//! Every message is signed by the sender's authority key over the signing domain, the blocks in the entries are verified on import.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+use natsclient::Client;
+use rand::Rng;
+use crate::error::QanError;
+use crate::chainspec::Domain;
+#[cfg(not(feature = "quantum"))]
+use ed25519_dalek::{Keypair, PublicKey, Signature};
+#[cfg(feature = "quantum")]
+use glp::glp::{GlpSig, GlpSk, GlpPk, sign, verify};
+
+/// Authorities are identified by the hash of their public key.
+pub type NodeId = [u8;32];
+
+/// NATS subject of the raft messages.
+pub const RAFT_SUBJECT : &'static str = "raft";
+/// Minimal number of ticks without hearing from a leader before a follower starts an election.
+pub const ELECTION_TICKS : u64 = 10;
+/// Number of ticks between two heartbeats of the leader.
+pub const HEARTBEAT_TICKS : u64 = 1;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Entry {
+    pub term : u64,
+    pub data : Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum RaftMessage {
+    RequestVote { term : u64, last_log_index : u64, last_log_term : u64 },
+    Vote { term : u64, granted : bool },
+    AppendEntries { term : u64, prev_log_index : u64, prev_log_term : u64, entries : Vec<Entry>, leader_commit : u64 },
+    AppendResponse { term : u64, success : bool, match_index : u64 },
+    /// Sent instead of entries the leader already compacted, the follower skips the log up to `last_index`.
+    InstallSnapshot { term : u64, last_index : u64, last_term : u64 },
+}
+
+impl RaftMessage {
+    pub fn term(&self) -> u64 {
+        match self {
+            RaftMessage::RequestVote{ term, .. } => *term,
+            RaftMessage::Vote{ term, .. } => *term,
+            RaftMessage::AppendEntries{ term, .. } => *term,
+            RaftMessage::AppendResponse{ term, .. } => *term,
+            RaftMessage::InstallSnapshot{ term, .. } => *term,
+        }
+    }
+}
+
+/// Addressed raft message as it travels on the network, signed by the sender.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Envelope {
+    pub from : NodeId,
+    pub to   : NodeId,
+    pub msg  : RaftMessage,
+    pub sig  : Vec<u8>,
+}
+
+impl Envelope {
+    #[cfg(not(feature = "quantum"))]
+    pub fn new(to : NodeId, msg : RaftMessage, kp : &Keypair, domain : &Domain) -> Result<Self, QanError> {
+        let mut env = Envelope{ from: crate::util::do_hash(&kp.public.to_bytes().to_vec()), to, msg, sig: Vec::new() };
+        env.sig = kp.sign(&env.payload(domain)?).to_bytes().to_vec();
+        Ok(env)
+    }
+
+    #[cfg(feature = "quantum")]
+    pub fn new(to : NodeId, msg : RaftMessage, sk : &GlpSk, domain : &Domain) -> Result<Self, QanError> {
+        let mut env = Envelope{ from: crate::util::do_hash(&glp::glp::gen_pk(&sk).to_bytes().to_vec()), to, msg, sig: Vec::new() };
+        env.sig = sign(&sk, env.payload(domain)?).unwrap().to_bytes();
+        Ok(env)
+    }
+
+    /// The signed bytes, the signing domain followed by the encoded sender, recipient and message.
+    fn payload(&self, domain : &Domain) -> Result<Vec<u8>, QanError> {
+        Ok(domain.payload(&crate::codec::encode(&(&self.from, &self.to, &self.msg)).map_err(|e|QanError::Codec(e))?))
+    }
+
+    /// envelope verification function, `pk` has to be the key of `from`
+    #[cfg(not(feature = "quantum"))]
+    pub fn verify(&self, pk : &PublicKey, domain : &Domain) -> Result<bool, QanError> {
+        let sig = match Signature::from_bytes(&self.sig){
+            Ok(s) => s,
+            Err(_) => return Ok(false)
+        };
+        Ok(pk.verify(&self.payload(domain)?, &sig).is_ok())
+    }
+
+    /// envelope verification function, `pk` has to be the key of `from`
+    #[cfg(feature = "quantum")]
+    pub fn verify(&self, pk : &GlpPk, domain : &Domain) -> Result<bool, QanError> {
+        Ok(verify(&pk, &GlpSig::from_bytes(&self.sig), &self.payload(domain)?))
+    }
+}
+
+pub trait Transport {
+    fn send(&self, from : &NodeId, to : &NodeId, msg : RaftMessage) -> Result<(), QanError>;
+}
+
+/// Transport signing the messages with the node's key and publishing them on the `raft` NATS subject.
+/// Every node receives every message and drops the ones not addressed to it.
+pub struct NatsTransport<'a> {
+    client  : &'a Client,
+    #[cfg(not(feature = "quantum"))]
+    kp      : &'a Keypair,
+    #[cfg(feature = "quantum")]
+    sk      : &'a GlpSk,
+    domain  : &'a Domain,
+}
+
+impl<'a> NatsTransport<'a> {
+    #[cfg(not(feature = "quantum"))]
+    pub fn new(client : &'a Client, kp : &'a Keypair, domain : &'a Domain) -> Self {
+        NatsTransport{ client, kp, domain }
+    }
+
+    #[cfg(feature = "quantum")]
+    pub fn new(client : &'a Client, sk : &'a GlpSk, domain : &'a Domain) -> Self {
+        NatsTransport{ client, sk, domain }
+    }
+}
+
+impl<'a> Transport for NatsTransport<'a> {
+    fn send(&self, _from : &NodeId, to : &NodeId, msg : RaftMessage) -> Result<(), QanError> {
+        #[cfg(not(feature = "quantum"))]
+        let env = Envelope::new(*to, msg, self.kp, self.domain)?;
+        #[cfg(feature = "quantum")]
+        let env = Envelope::new(*to, msg, self.sk, self.domain)?;
+        self.client.publish(RAFT_SUBJECT, &serde_json::to_vec(&env).map_err(|e|QanError::Serde(e))?, None).map_err(|e|QanError::Nats(e))
+    }
+}
+
+/// In-process transport. Messages are queued until the owner delivers them.
+#[derive(Clone, Default)]
+pub struct LocalTransport {
+    queue : Arc<Mutex<VecDeque<Envelope>>>,
+}
+
+impl LocalTransport {
+    pub fn new() -> Self {
+        LocalTransport::default()
+    }
+
+    /// Takes the next message off the queue.
+    pub fn pop(&self) -> Option<Envelope> {
+        self.queue.lock().expect("raft queue poisoned").pop_front()
+    }
+}
+
+impl Transport for LocalTransport {
+    fn send(&self, from : &NodeId, to : &NodeId, msg : RaftMessage) -> Result<(), QanError> {
+        self.queue.lock().expect("raft queue poisoned").push_back(Envelope{ from : *from, to : *to, msg, sig : Vec::new() });
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+pub struct Raft {
+    id              : NodeId,
+    peers           : Vec<NodeId>,
+    role            : Role,
+    term            : u64,
+    voted_for       : Option<NodeId>,
+    leader          : Option<NodeId>,
+    log             : Vec<Entry>,
+    offset          : u64,
+    offset_term     : u64,
+    commit_index    : u64,
+    last_applied    : u64,
+    votes           : HashSet<NodeId>,
+    next_index      : HashMap<NodeId, u64>,
+    match_index     : HashMap<NodeId, u64>,
+    elapsed         : u64,
+    timeout         : u64,
+}
+
+fn random_timeout() -> u64 {
+    ELECTION_TICKS + rand::thread_rng().gen_range(0, ELECTION_TICKS)
+}
+
+impl Raft {
+    /// Constructor function for Raft. `peers` is the whole cluster, this node included. Log indexes start at 1.
+    /// `log` holds the entries after index `offset`, the compacted ones.
+    pub fn new(id : NodeId, peers : Vec<NodeId>) -> Self {
+        Raft{
+            id,
+            peers,
+            role            : Role::Follower,
+            term            : 0,
+            voted_for       : None,
+            leader          : None,
+            log             : Vec::new(),
+            offset          : 0,
+            offset_term     : 0,
+            commit_index    : 0,
+            last_applied    : 0,
+            votes           : HashSet::new(),
+            next_index      : HashMap::new(),
+            match_index     : HashMap::new(),
+            elapsed         : 0,
+            timeout         : random_timeout(),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.offset + self.log.len() as u64
+    }
+
+    /// Index of the last compacted entry, 0 if nothing was compacted.
+    pub fn compacted(&self) -> u64 {
+        self.offset
+    }
+
+    /// Checks whether the log holds entries not yet replicated to a majority.
+    pub fn has_uncommitted(&self) -> bool {
+        self.last_index() > self.commit_index
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map(|e|e.term).unwrap_or(self.offset_term)
+    }
+
+    /// Term of the entry at `index`, entries before the compacted one count as matching anything.
+    fn term_at(&self, index : u64) -> u64 {
+        if index == self.offset { return self.offset_term }
+        if index < self.offset { return 0 }
+        self.log.get((index - self.offset) as usize - 1).map(|e|e.term).unwrap_or(0)
+    }
+
+    /// Replaces the cluster with `peers`, this node included, when the authority set changes.
+    pub fn set_peers(&mut self, peers : Vec<NodeId>) {
+        if peers == self.peers { return }
+        info!("raft: cluster changed to {} members", peers.len());
+        self.next_index.retain(|p, _| peers.contains(p));
+        self.match_index.retain(|p, _| peers.contains(p));
+        self.votes.retain(|p| peers.contains(p));
+        if self.is_leader() {
+            let (id, next) = (self.id, self.last_index() + 1);
+            for p in peers.iter().filter(|p| **p != id) {
+                self.next_index.entry(*p).or_insert(next);
+                self.match_index.entry(*p).or_insert(0);
+            }
+        }
+        if self.leader.map_or(false, |l| !peers.contains(&l)) { self.leader = None; }
+        self.peers = peers;
+        if self.is_leader() { self.advance_commit(); }
+    }
+
+    /// Drops the applied entries from the front of the log while `compact` allows it for their data,
+    /// `compact` is asked whether the block of an entry is final. Returns the index of the last compacted entry.
+    pub fn compact<F: Fn(&[u8]) -> bool>(&mut self, compact : F) -> u64 {
+        let mut n = 0;
+        while self.offset + (n as u64) < self.last_applied && n < self.log.len() && compact(&self.log[n].data) {
+            n += 1;
+        }
+        if n > 0 {
+            self.offset_term = self.log[n-1].term;
+            self.offset += n as u64;
+            self.log.drain(..n);
+        }
+        self.offset
+    }
+
+    fn quorum(&self) -> usize {
+        self.peers.len()/2 + 1
+    }
+
+    fn others(&self) -> Vec<NodeId> {
+        self.peers.iter().filter(|p| **p != self.id).cloned().collect()
+    }
+
+    /// Advances the logical clock. Followers start an election after the timeout, the leader sends heartbeats.
+    pub fn tick<T: Transport>(&mut self, t : &T) -> Result<(), QanError> {
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader => if self.elapsed >= HEARTBEAT_TICKS {
+                self.elapsed = 0;
+                self.broadcast_append(t)?;
+            },
+            _ => if self.elapsed >= self.timeout {
+                self.start_election(t)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends data to the log. Only the leader can propose, returns the log index of the new entry.
+    pub fn propose<T: Transport>(&mut self, data : Vec<u8>, t : &T) -> Result<u64, QanError> {
+        if !self.is_leader() { return Err(QanError::Internal("not the raft leader".to_string())) }
+        self.log.push(Entry{ term : self.term, data });
+        self.advance_commit();
+        self.broadcast_append(t)?;
+        Ok(self.last_index())
+    }
+
+    /// Returns the data of the entries committed since the last call, in log order.
+    pub fn take_committed(&mut self) -> Vec<Vec<u8>> {
+        let mut ret = Vec::new();
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let e = &self.log[(self.last_applied - self.offset) as usize - 1];
+            // empty entries are the no-ops of new leaders
+            if !e.data.is_empty() { ret.push(e.data.clone()) }
+        }
+        ret
+    }
+
+    /// Handles a message of another member of the cluster.
+    pub fn step<T: Transport>(&mut self, from : NodeId, msg : RaftMessage, t : &T) -> Result<(), QanError> {
+        if from == self.id || !self.peers.contains(&from) { return Ok(()) }
+        if msg.term() > self.term { self.become_follower(msg.term(), None); }
+        match msg {
+            RaftMessage::RequestVote{ term, last_log_index, last_log_term } => {
+                let up_to_date = last_log_term > self.last_term() || (last_log_term == self.last_term() && last_log_index >= self.last_index());
+                let granted = term == self.term && up_to_date && self.voted_for.map_or(true, |v| v == from);
+                if granted {
+                    self.voted_for = Some(from);
+                    self.elapsed = 0;
+                }
+                t.send(&self.id, &from, RaftMessage::Vote{ term : self.term, granted })?;
+            },
+            RaftMessage::Vote{ term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() { self.become_leader(t)?; }
+                }
+            },
+            RaftMessage::AppendEntries{ term, prev_log_index, prev_log_term, entries, leader_commit } => {
+                if term < self.term {
+                    return t.send(&self.id, &from, RaftMessage::AppendResponse{ term : self.term, success : false, match_index : 0 })
+                }
+                self.become_follower(term, Some(from));
+                if prev_log_index > self.last_index() || (prev_log_index >= self.offset && self.term_at(prev_log_index) != prev_log_term) {
+                    let hint = self.last_index().min(prev_log_index.saturating_sub(1));
+                    return t.send(&self.id, &from, RaftMessage::AppendResponse{ term : self.term, success : false, match_index : hint })
+                }
+                let mut index = prev_log_index;
+                for e in entries {
+                    index += 1;
+                    //compacted entries are committed, so they match the leader's
+                    if index <= self.offset { continue }
+                    if index <= self.last_index() {
+                        if self.term_at(index) == e.term { continue }
+                        self.log.truncate((index - self.offset) as usize - 1);
+                    }
+                    self.log.push(e);
+                }
+                if leader_commit > self.commit_index {
+                    self.commit_index = leader_commit.min(index);
+                }
+                t.send(&self.id, &from, RaftMessage::AppendResponse{ term : self.term, success : true, match_index : index })?;
+            },
+            RaftMessage::AppendResponse{ term, success, match_index } => {
+                if self.role != Role::Leader || term != self.term { return Ok(()) }
+                if success {
+                    let m = self.match_index.entry(from).or_insert(0);
+                    if match_index > *m { *m = match_index; }
+                    self.next_index.insert(from, match_index + 1);
+                    self.advance_commit();
+                } else {
+                    let next = self.next_index.get(&from).cloned().unwrap_or(1);
+                    self.next_index.insert(from, (match_index + 1).min(next.saturating_sub(1)).max(1));
+                    self.send_append(&from, t)?;
+                }
+            },
+            RaftMessage::InstallSnapshot{ term, last_index, last_term } => {
+                if term < self.term {
+                    return t.send(&self.id, &from, RaftMessage::AppendResponse{ term : self.term, success : false, match_index : 0 })
+                }
+                self.become_follower(term, Some(from));
+                if last_index > self.commit_index {
+                    //the skipped blocks are final, the node gets them through the usual sync
+                    if last_index < self.last_index() && self.term_at(last_index) == last_term {
+                        self.log.drain(..(last_index - self.offset) as usize);
+                    } else {
+                        self.log.clear();
+                    }
+                    self.offset = last_index;
+                    self.offset_term = last_term;
+                    self.commit_index = last_index;
+                    self.last_applied = last_index;
+                }
+                t.send(&self.id, &from, RaftMessage::AppendResponse{ term : self.term, success : true, match_index : last_index.max(self.commit_index) })?;
+            },
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term : u64, leader : Option<NodeId>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        if self.role != Role::Follower { info!("raft: following in term {}", self.term); }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.elapsed = 0;
+    }
+
+    fn start_election<T: Transport>(&mut self, t : &T) -> Result<(), QanError> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.votes.clear();
+        self.votes.insert(self.id);
+        self.elapsed = 0;
+        self.timeout = random_timeout();
+        info!("raft: starting election for term {}", self.term);
+        if self.votes.len() >= self.quorum() { return self.become_leader(t) }
+        let msg = RaftMessage::RequestVote{ term : self.term, last_log_index : self.last_index(), last_log_term : self.last_term() };
+        for p in self.others() {
+            t.send(&self.id, &p, msg.clone())?;
+        }
+        Ok(())
+    }
+
+    fn become_leader<T: Transport>(&mut self, t : &T) -> Result<(), QanError> {
+        info!("raft: leading in term {}", self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        self.next_index.clear();
+        self.match_index.clear();
+        for p in self.others() {
+            self.next_index.insert(p, self.last_index() + 1);
+            self.match_index.insert(p, 0);
+        }
+        // a leader may only count replicas of entries from its own term, the no-op commits everything before it
+        self.log.push(Entry{ term : self.term, data : Vec::new() });
+        self.advance_commit();
+        self.broadcast_append(t)
+    }
+
+    fn advance_commit(&mut self) {
+        let mut n = self.last_index();
+        while n > self.commit_index {
+            if self.term_at(n) == self.term {
+                let replicated = 1 + self.match_index.values().filter(|m| **m >= n).count();
+                if replicated >= self.quorum() {
+                    self.commit_index = n;
+                    break
+                }
+            }
+            n -= 1;
+        }
+    }
+
+    fn send_append<T: Transport>(&self, to : &NodeId, t : &T) -> Result<(), QanError> {
+        let next = self.next_index.get(to).cloned().unwrap_or(self.last_index() + 1).max(1);
+        if next <= self.offset {
+            return t.send(&self.id, to, RaftMessage::InstallSnapshot{ term : self.term, last_index : self.offset, last_term : self.offset_term })
+        }
+        let prev = next - 1;
+        let entries = self.log[((prev - self.offset) as usize).min(self.log.len())..].to_vec();
+        t.send(&self.id, to, RaftMessage::AppendEntries{
+            term            : self.term,
+            prev_log_index  : prev,
+            prev_log_term   : self.term_at(prev),
+            entries,
+            leader_commit   : self.commit_index,
+        })
+    }
+
+    fn broadcast_append<T: Transport>(&self, t : &T) -> Result<(), QanError> {
+        for p in self.others() {
+            self.send_append(&p, t)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn deliver(nodes : &mut Vec<Raft>, t : &LocalTransport, down : Option<NodeId>) {
+    while let Some(env) = t.pop() {
+        if Some(env.to) == down || Some(env.from) == down { continue }
+        if let Some(n) = nodes.iter_mut().find(|n| n.id == env.to) {
+            n.step(env.from, env.msg, t).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+fn run_until_leader(nodes : &mut Vec<Raft>, t : &LocalTransport, down : Option<NodeId>) -> NodeId {
+    for _ in 0..1000 {
+        for n in nodes.iter_mut().filter(|n| Some(n.id) != down) {
+            n.tick(t).unwrap();
+        }
+        deliver(nodes, t, down);
+        let leaders : Vec<NodeId> = nodes.iter().filter(|n| n.is_leader() && Some(n.id) != down).map(|n|n.id).collect();
+        if leaders.len() == 1 { return leaders[0] }
+    }
+    panic!("no leader elected");
+}
+
+#[test]
+fn raft_replicates_through_leader_changes() {
+    let ids : Vec<NodeId> = vec![[1;32], [2;32], [3;32]];
+    let t = LocalTransport::new();
+    let mut nodes : Vec<Raft> = ids.iter().map(|id| Raft::new(*id, ids.clone())).collect();
+
+    let leader = run_until_leader(&mut nodes, &t, None);
+    nodes.iter_mut().find(|n| n.id == leader).unwrap().propose(b"block1".to_vec(), &t).unwrap();
+    deliver(&mut nodes, &t, None);
+    for n in nodes.iter_mut() { n.tick(&t).unwrap(); }
+    deliver(&mut nodes, &t, None);
+    for n in nodes.iter_mut() {
+        assert_eq!(n.take_committed(), vec![b"block1".to_vec()]);
+    }
+
+    // the leader goes down, the remaining majority elects a new one and keeps committing
+    let new_leader = run_until_leader(&mut nodes, &t, Some(leader));
+    assert!(new_leader != leader);
+    nodes.iter_mut().find(|n| n.id == new_leader).unwrap().propose(b"block2".to_vec(), &t).unwrap();
+    deliver(&mut nodes, &t, Some(leader));
+    for n in nodes.iter_mut().filter(|n| n.id != leader) { n.tick(&t).unwrap(); }
+    deliver(&mut nodes, &t, Some(leader));
+    for n in nodes.iter_mut().filter(|n| n.id != leader) {
+        assert_eq!(n.take_committed(), vec![b"block2".to_vec()]);
+    }
+
+    // an entry the old leader can't replicate alone never commits, and it catches up once back
+    let old = nodes.iter_mut().find(|n| n.id == leader).unwrap();
+    assert!(old.propose(b"lost".to_vec(), &t).is_ok());
+    while t.pop().is_some() {}
+    assert!(old.take_committed().is_empty());
+    for _ in 0..3 {
+        for n in nodes.iter_mut().filter(|n| n.is_leader() && n.id != leader) { n.tick(&t).unwrap(); }
+        deliver(&mut nodes, &t, None);
+    }
+    let old = nodes.iter_mut().find(|n| n.id == leader).unwrap();
+    assert!(!old.is_leader());
+    assert_eq!(old.take_committed(), vec![b"block2".to_vec()]);
+}
+
+#[test]
+fn raft_compacts_and_skips_followers_ahead() {
+    let ids : Vec<NodeId> = vec![[1;32], [2;32], [3;32]];
+    let t = LocalTransport::new();
+    let mut nodes : Vec<Raft> = ids.iter().map(|id| Raft::new(*id, ids.clone())).collect();
+    let leader = run_until_leader(&mut nodes, &t, None);
+    let behind = *ids.iter().find(|id| **id != leader).unwrap();
+    for data in vec![b"block1".to_vec(), b"block2".to_vec()] {
+        nodes.iter_mut().find(|n| n.id == leader).unwrap().propose(data, &t).unwrap();
+        deliver(&mut nodes, &t, Some(behind));
+        for n in nodes.iter_mut().filter(|n| n.is_leader()) { n.tick(&t).unwrap(); }
+        deliver(&mut nodes, &t, Some(behind));
+    }
+    // the leader drops the entries up to the final block1, the lagging follower skips them
+    let l = nodes.iter_mut().find(|n| n.id == leader).unwrap();
+    assert_eq!(l.take_committed(), vec![b"block1".to_vec(), b"block2".to_vec()]);
+    assert_eq!(l.compact(|d| d != b"block2"), 2);
+    assert_eq!(l.last_index(), 3);
+    for n in nodes.iter_mut().filter(|n| n.is_leader()) { n.tick(&t).unwrap(); }
+    deliver(&mut nodes, &t, None);
+    for n in nodes.iter_mut().filter(|n| n.is_leader()) { n.tick(&t).unwrap(); }
+    deliver(&mut nodes, &t, None);
+    let f = nodes.iter_mut().find(|n| n.id == behind).unwrap();
+    assert_eq!(f.compacted(), 2);
+    assert_eq!(f.commit_index(), 3);
+    assert_eq!(f.take_committed(), vec![b"block2".to_vec()]);
+
+    // a member leaving the authority set leaves the cluster
+    let l = nodes.iter_mut().find(|n| n.id == leader).unwrap();
+    l.set_peers(vec![leader, behind]);
+    assert_eq!(l.quorum(), 2);
+    assert!(l.others() == vec![behind]);
+}
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn raft_envelope_signature() {
+    let kp = Keypair::generate(&mut rand::rngs::OsRng);
+    let domain = Domain::new("qan-test");
+    let mut env = Envelope::new([2;32], RaftMessage::Vote{ term: 1, granted: true }, &kp, &domain).unwrap();
+    assert_eq!(env.from, crate::util::do_hash(&kp.public.to_bytes().to_vec()));
+    assert!(env.verify(&kp.public, &domain).unwrap());
+    assert!(!env.verify(&kp.public, &Domain::new("qan-other")).unwrap());
+    // a forged term breaks the signature
+    env.msg = RaftMessage::Vote{ term: 2, granted: true };
+    assert!(!env.verify(&kp.public, &domain).unwrap());
+}
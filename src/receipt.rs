@@ -0,0 +1,439 @@
+//! Transaction receipts. Executing a block produces a receipt for each of its transactions, with the outcome, the spice used and the fee,
+//! the return value of a contract call and the logs it emitted. The header commits to them in `receipts_root`,
+//! and the receipts database keeps them by transaction hash while the including block is canonical.
+//! Next to the receipts of a canonical block it keeps a bloom filter of the addresses and topics of its logs, so log queries can skip blocks.
+use std::collections::HashMap;
+use rocksdb::DB;
+use crate::block::Block;
+use crate::transaction::Transaction;
+use crate::vm::{VM, VMReturn};
+use crate::error::QanError;
+use crate::util::do_hash;
+
+/// Entry a contract appends to the log of a transaction.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Log {
+    pub address : [u8;32],
+    pub topics  : Vec<[u8;32]>,
+    pub data    : Vec<u8>,
+}
+
+/// Outcome of a transaction. This is the part the block commits to.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Receipt {
+    pub tx_hash         : [u8;32],
+    pub success         : bool,
+    pub error           : Option<String>,
+    pub spice_used      : u64,
+    pub fee             : u64,
+    pub return_value    : Option<VMReturn>,
+    pub logs            : Vec<Log>,
+}
+
+impl Receipt {
+    pub fn hash(&self) -> Result<[u8;32], QanError> {
+        Ok(do_hash(&crate::codec::encode(&self).map_err(|e|QanError::Codec(e))?))
+    }
+}
+
+/// A receipt with the position of its transaction in the canonical chain.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ReceiptRecord {
+    pub receipt     : Receipt,
+    pub block_hash  : [u8;32],
+    pub height      : u64,
+    pub index       : usize,
+}
+
+/// Bytes of a log bloom filter.
+pub const BLOOM_BYTES : usize = 256;
+
+/// Bloom filter of the contract addresses and topics of the logs of a block.
+/// Every item sets three bits of the filter picked from its hash.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Bloom(pub Vec<u8>);
+
+impl Bloom {
+    pub fn new() -> Bloom {
+        Bloom(vec![0; BLOOM_BYTES])
+    }
+
+    fn bits(item : &[u8;32]) -> Vec<usize> {
+        let h = do_hash(&item.to_vec());
+        (0..3).map(|i|(((h[2*i] as usize) << 8) | h[2*i+1] as usize) % (8 * BLOOM_BYTES)).collect()
+    }
+
+    pub fn accrue(&mut self, item : &[u8;32]) {
+        for b in Bloom::bits(item) {
+            self.0[b / 8] |= 1 << (b % 8);
+        }
+    }
+
+    /// Whether the item may have been added. False positives are possible, false negatives aren't.
+    pub fn contains(&self, item : &[u8;32]) -> bool {
+        Bloom::bits(item).iter().all(|b|self.0[b / 8] & (1 << (b % 8)) != 0)
+    }
+
+    /// Bloom of the addresses and topics of the logs of the receipts.
+    pub fn of(receipts : &[ReceiptRecord]) -> Bloom {
+        let mut bloom = Bloom::new();
+        for log in receipts.iter().flat_map(|r|r.receipt.logs.iter()) {
+            bloom.accrue(&log.address);
+            for t in &log.topics {
+                bloom.accrue(t);
+            }
+        }
+        bloom
+    }
+}
+
+fn bloom_key(block_hash : &[u8;32]) -> Vec<u8> {
+    let mut key = b"bloom".to_vec();
+    key.extend_from_slice(block_hash);
+    key
+}
+
+/// Receipts of executed transactions, and the contract storage they wrote by storage key.
+#[derive(Debug, Clone, Default)]
+pub struct Execution {
+    pub receipts    : Vec<Receipt>,
+    pub storage     : HashMap<[u8;32], [u8;32]>,
+}
+
+impl Execution {
+    /// The receipts with the position of their transactions in `block`.
+    pub fn records(&self, block : &Block) -> Vec<ReceiptRecord> {
+        self.receipts.iter().cloned().enumerate().map(|(index, receipt)|ReceiptRecord{ receipt, block_hash: block.hash(), height: block.height(), index }).collect()
+    }
+}
+
+/// Executes `txes` in order into their receipts, running contract deployments and calls on top of the contract code returned by `code`
+/// and the contract storage returned by `storage`.
+/// A deployment or call that fails gets a failed receipt with the reason, it is still charged but transfers nothing and writes no storage.
+/// Calls are metered, a transaction uses its intrinsic spice and the spice of its call, and pays for it up to its spice limit.
+pub fn execute_with<F, S>(txes : &[&Transaction], mut code : F, mut storage : S) -> Result<Execution, QanError>
+    where F : FnMut(&[u8;32]) -> Result<Option<Vec<u8>>, QanError>, S : FnMut(&[u8;32]) -> Result<Option<[u8;32]>, QanError> {
+    let mut deployed : HashMap<[u8;32], Vec<u8>> = HashMap::new();
+    let mut written : HashMap<[u8;32], [u8;32]> = HashMap::new();
+    let mut receipts = Vec::new();
+    for tx in txes {
+        let intrinsic = crate::spice::consumed(&tx.transaction);
+        let mut metered = 0;
+        let mut logs = Vec::new();
+        let outcome = if let Some(address) = tx.transaction.deployed_contract() {
+            if deployed.contains_key(&address) || code(&address)?.is_some() {
+                Err(format!("contract {} is deployed already", hex::encode(address)))
+            }else{
+                VM::check_module(&tx.transaction.data).map(|_|{ deployed.insert(address, tx.transaction.data.clone()); None })
+            }
+        }else if let Some(call) = tx.transaction.vm_call() {
+            let contract = match deployed.get(&call.sc_hash) { Some(c) => Some(c.clone()), None => code(&call.sc_hash)? };
+            match contract {
+                Some(contract) => {
+                    let mut vm = VM::new();
+                    vm.insert_contract(hex::encode(call.sc_hash), contract);
+                    match crate::vm::parse_values(call.params.clone()) {
+                        Ok(params) => {
+                            let called = {
+                                let mut load = |key : &[u8;32]| match written.get(key) { Some(v) => Ok(Some(*v)), None => storage(key) };
+                                vm.call_fun(hex::encode(call.sc_hash), call.func.clone(), params,
+                                    tx.transaction.spice_limit.saturating_sub(intrinsic), &mut load)?
+                            };
+                            metered = called.spice;
+                            logs = called.logs;
+                            written.extend(called.storage);
+                            called.result
+                        },
+                        Err(e) => Err(e)
+                    }
+                },
+                None => Err(format!("unknown contract {}", hex::encode(call.sc_hash)))
+            }
+        }else{
+            Ok(None)
+        };
+        let (success, error, return_value) = match outcome {
+            Ok(r) => (true, None, r),
+            Err(e) => (false, Some(e), None)
+        };
+        let spice_used = intrinsic + metered;
+        receipts.push(Receipt{
+            tx_hash: tx.hash()?,
+            success,
+            error,
+            spice_used,
+            fee: crate::spice::charge(&tx.transaction, spice_used).unwrap_or(0),
+            return_value,
+            logs,
+        });
+    }
+    Ok(Execution{ receipts, storage: written })
+}
+
+/// Executes `txes` in order into their receipts on top of the contracts deployed in `accounts` and their storage.
+pub fn execute(accounts : &DB, txes : &[&Transaction]) -> Result<Execution, QanError> {
+    execute_with(txes, |address|crate::state::contract_code(accounts, address), |key|crate::state::contract_storage(accounts, key))
+}
+
+/// Receipt of `tx` succeeding at the highest fee it may be charged, to check that its sender can pay for it without executing it.
+/// Contract calls may use their whole spice limit.
+pub fn estimate(tx : &Transaction) -> Result<Receipt, QanError> {
+    let spice_used = if tx.transaction.vm_call().is_some() { tx.transaction.spice_limit } else { crate::spice::consumed(&tx.transaction) };
+    Ok(Receipt{
+        tx_hash: tx.hash()?,
+        success: true,
+        error: None,
+        spice_used,
+        fee: crate::spice::charge(&tx.transaction, spice_used).unwrap_or(0),
+        return_value: None,
+        logs: Vec::new(),
+    })
+}
+
+/// Merkle root of the receipts, in the order of the transactions.
+pub fn receipts_root(receipts : &[Receipt]) -> Result<[u8;32], QanError> {
+    let mut hashes = Vec::new();
+    for r in receipts {
+        hashes.push(r.hash()?);
+    }
+    Ok(crate::block::tx_root(&hashes))
+}
+
+/// Executes the transactions of a block on top of the stored contracts and checks the result against the header's receipts root.
+/// Every transaction of the block has to be in `txdb`. Returns the receipts and storage writes to store once the block is canonical.
+pub fn execute_block(txdb : &DB, accounts : &DB, block : &Block) -> Result<Execution, QanError> {
+    let mut txes : Vec<Transaction> = Vec::new();
+    for txh in &block.body.txes {
+        match txdb.get(txh).map_err(|e|QanError::Database(e))?{
+            Some(t) => txes.push(serde_json::from_slice(&t).map_err(|e|QanError::Serde(e))?),
+            None => return Err(QanError::Internal(format!("missing transaction {} of block {}", hex::encode(txh), hex::encode(block.hash()))))
+        }
+    }
+    let executed = execute(accounts, &txes.iter().collect::<Vec<&Transaction>>())?;
+    if receipts_root(&executed.receipts)? != block.signed.header.receipts_root {
+        return Err(QanError::Internal(format!("receipts root mismatch in block {}", hex::encode(block.hash()))))
+    }
+    Ok(executed)
+}
+
+/// Stores the receipts and the log bloom of a block becoming canonical.
+pub fn store(receipts_db : &DB, block : &Block, records : &[ReceiptRecord]) -> Result<(), QanError> {
+    for r in records {
+        receipts_db.put(r.receipt.tx_hash, serde_json::to_vec(r).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
+    }
+    receipts_db.put(bloom_key(&block.hash()), Bloom::of(records).0).map_err(|e|QanError::Database(e))?;
+    receipts_db.flush().map_err(|e|QanError::Database(e))
+}
+
+/// Removes the receipts and the log bloom of a block retracted from the canonical chain.
+pub fn remove(receipts_db : &DB, block : &Block) -> Result<(), QanError> {
+    for txh in &block.body.txes {
+        receipts_db.delete(txh).map_err(|e|QanError::Database(e))?;
+    }
+    receipts_db.delete(bloom_key(&block.hash())).map_err(|e|QanError::Database(e))?;
+    receipts_db.flush().map_err(|e|QanError::Database(e))
+}
+
+/// Loads the receipt of a transaction of the canonical chain.
+pub fn load(receipts_db : &DB, txh : &[u8;32]) -> Result<Option<ReceiptRecord>, QanError> {
+    match receipts_db.get(txh).map_err(|e|QanError::Database(e))?{
+        Some(r) => Ok(Some(serde_json::from_slice(&r).map_err(|e|QanError::Serde(e))?)),
+        None => Ok(None)
+    }
+}
+
+/// Log bloom of a canonical block, `None` if the block isn't canonical.
+pub fn load_bloom(receipts_db : &DB, block_hash : &[u8;32]) -> Result<Option<Bloom>, QanError> {
+    Ok(receipts_db.get(bloom_key(block_hash)).map_err(|e|QanError::Database(e))?.map(|b|Bloom(b.to_vec())))
+}
+
+/// A log with the position of its transaction in the canonical chain, and its position in the logs of the transaction.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct LogEntry {
+    pub block_hash  : [u8;32],
+    pub height      : u64,
+    pub tx_hash     : [u8;32],
+    pub tx_index    : usize,
+    pub log_index   : usize,
+    pub log         : Log,
+}
+
+/// Filter of a log query. Unset fields match every log.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct LogFilter {
+    pub address : Option<[u8;32]>,
+    pub topic   : Option<[u8;32]>,
+}
+
+impl LogFilter {
+    pub fn matches(&self, log : &Log) -> bool {
+        self.address.map_or(true, |a|a == log.address) && self.topic.map_or(true, |t|log.topics.contains(&t))
+    }
+
+    /// The matching logs of the receipts, in order.
+    pub fn select(&self, records : &[ReceiptRecord]) -> Vec<LogEntry> {
+        let mut entries = Vec::new();
+        for record in records {
+            for (log_index, log) in record.receipt.logs.iter().enumerate() {
+                if !self.matches(log) { continue }
+                entries.push(LogEntry{
+                    block_hash: record.block_hash,
+                    height: record.height,
+                    tx_hash: record.receipt.tx_hash,
+                    tx_index: record.index,
+                    log_index,
+                    log: log.clone(),
+                });
+            }
+        }
+        entries
+    }
+
+    /// Whether a block with the bloom may hold matching logs.
+    pub fn may_match(&self, bloom : &Bloom) -> bool {
+        self.address.map_or(true, |a|bloom.contains(&a)) && self.topic.map_or(true, |t|bloom.contains(&t))
+    }
+}
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn receipts_commit_to_order() {
+    let kp = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng);
+    let a = Transaction::new(crate::transaction::TxBody::new([1;32], 0, 5, vec![]), &kp, &crate::chainspec::Domain::new("test")).unwrap();
+    let b = Transaction::new(crate::transaction::TxBody::new([1;32], 1, 5, vec![0;4]), &kp, &crate::chainspec::Domain::new("test")).unwrap();
+    let receipts = execute_with(&[&a, &b], |_|Ok(None), |_|Ok(None)).unwrap().receipts;
+    assert_eq!(receipts[1].spice_used, crate::spice::consumed(&b.transaction));
+    assert_eq!(receipts[1].tx_hash, b.hash().unwrap());
+    let root_of = |txes : &[&Transaction]| receipts_root(&execute_with(txes, |_|Ok(None), |_|Ok(None)).unwrap().receipts).unwrap();
+    assert_ne!(root_of(&[&a, &b]), root_of(&[&b, &a]));
+    assert_eq!(root_of(&[]), crate::block::EMPTY_ROOT);
+}
+
+/// Contract exporting `get`, returning 42, `emit`, logging the topic `[7;32]` with the data "data", and `fail`, which traps.
+#[cfg(test)]
+const TEST_CONTRACT : [u8;165] = [
+    0,97,115,109,1,0,0,0,1,15,3,96,4,127,127,127,127,0,96,0,1,126,96,0,0,2,11,1,3,101,110,118,3,108,111,103,0,0,3,4,3,1,2,2,5,3,1,0,1,7,30,4,
+    6,109,101,109,111,114,121,2,0,3,103,101,116,0,1,4,101,109,105,116,0,2,4,102,97,105,108,0,3,10,23,3,4,0,66,42,11,12,0,65,0,65,1,65,32,65,4,
+    16,0,11,3,0,0,11,11,42,1,0,65,0,11,36,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,100,97,116,97,0,13,4,110,97,109,101,
+    1,6,1,0,3,108,111,103
+];
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn contract_calls() {
+    use crate::transaction::{TxBody, VmCall};
+    let kp = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng);
+    let address = do_hash(&TEST_CONTRACT.to_vec());
+    let sign = |body| Transaction::new(body, &kp, &crate::chainspec::Domain::new("test")).unwrap();
+    let call = |nonce, func : &str, spice| {
+        let mut body = TxBody::new(address, nonce, 0, serde_json::to_vec(&VmCall{
+            prev_root: [0;32], res_root: [0;32], sc_hash: address, func: func.to_string(), params: vec![]
+        }).unwrap()).with_spice_price(2);
+        body.spice_limit += spice;
+        sign(body)
+    };
+    let deploy = sign(TxBody::new(crate::transaction::DEPLOY_ACCOUNT, 0, 0, TEST_CONTRACT.to_vec()));
+    let (get, fail, starved) = (call(1, "get", 1000), call(2, "fail", 1000), call(3, "get", 0));
+    let receipts = execute_with(&[&deploy, &get, &fail, &starved], |_|Ok(None), |_|Ok(None)).unwrap().receipts;
+    assert!(receipts[0].success && receipts[1].success);
+    assert!(match receipts[1].return_value { Some(VMReturn::U64(42)) => true, _ => false });
+    //the execution is metered and paid for
+    assert!(receipts[1].spice_used > crate::spice::consumed(&get.transaction) && receipts[1].spice_used < get.transaction.spice_limit);
+    assert_eq!(receipts[1].fee, 2 * receipts[1].spice_used);
+    //a failed call is still charged, running out of spice costs the whole limit
+    assert!(!receipts[2].success && receipts[2].error.is_some());
+    assert!(receipts[2].fee >= crate::spice::fee(&fail.transaction).unwrap());
+    assert_eq!((receipts[3].success, receipts[3].fee), (false, crate::spice::max_fee(&starved.transaction).unwrap()));
+    //calls of contracts that aren't deployed and deployments of deployed code fail
+    assert!(!execute_with(&[&get], |_|Ok(None), |_|Ok(None)).unwrap().receipts[0].success);
+    let receipts = execute_with(&[&deploy, &get], |_|Ok(Some(TEST_CONTRACT.to_vec())), |_|Ok(None)).unwrap().receipts;
+    assert!(!receipts[0].success && receipts[1].success);
+    //parameters that don't fit the VM fail the call, it is still charged
+    let mut body = get.transaction.clone();
+    body.data = serde_json::to_vec(&VmCall{ prev_root: [0;32], res_root: [0;32], sc_hash: address, func: "get".to_string(), params: vec![serde_json::json!(std::u64::MAX)] }).unwrap();
+    let overflowing = sign(body);
+    let receipts = execute_with(&[&overflowing], |_|Ok(Some(TEST_CONTRACT.to_vec())), |_|Ok(None)).unwrap().receipts;
+    assert!(!receipts[0].success && receipts[0].error.is_some());
+    assert_eq!(receipts[0].fee, crate::spice::fee(&overflowing.transaction).unwrap());
+    //data sent to the zero address, like chat messages, isn't executed
+    let chat = sign(TxBody::new([0;32], 0, 0, serde_json::to_vec(&VmCall{ prev_root: [0;32], res_root: [0;32], sc_hash: address, func: "fail".to_string(), params: vec![] }).unwrap()));
+    let receipts = execute_with(&[&chat], |_|Ok(Some(TEST_CONTRACT.to_vec())), |_|Ok(None)).unwrap().receipts;
+    assert!(receipts[0].success && receipts[0].return_value.is_none());
+}
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn contract_logs() {
+    use crate::transaction::{TxBody, VmCall};
+    let kp = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng);
+    let address = do_hash(&TEST_CONTRACT.to_vec());
+    let deploy = Transaction::new(TxBody::new(crate::transaction::DEPLOY_ACCOUNT, 0, 0, TEST_CONTRACT.to_vec()), &kp, &crate::chainspec::Domain::new("test")).unwrap();
+    let mut body = TxBody::new(address, 1, 0, serde_json::to_vec(&VmCall{
+        prev_root: [0;32], res_root: [0;32], sc_hash: address, func: "emit".to_string(), params: vec![]
+    }).unwrap());
+    body.spice_limit += 1000;
+    let emit = Transaction::new(body, &kp, &crate::chainspec::Domain::new("test")).unwrap();
+    let receipts = execute_with(&[&deploy, &emit], |_|Ok(None), |_|Ok(None)).unwrap().receipts;
+    let log = Log{ address, topics: vec![[7;32]], data: b"data".to_vec() };
+    assert_eq!(receipts[1].logs, vec![log.clone()]);
+    //the log is found the way get_logs looks for it, through the bloom of the block and then its receipts
+    let records : Vec<ReceiptRecord> = receipts.into_iter().enumerate().map(|(index, receipt)|ReceiptRecord{ receipt, block_hash: [9;32], height: 3, index }).collect();
+    let bloom = Bloom::of(&records);
+    let filter = LogFilter{ address: Some(address), topic: Some([7;32]) };
+    assert!(filter.may_match(&bloom));
+    assert_eq!(filter.select(&records), vec![LogEntry{ block_hash: [9;32], height: 3, tx_hash: emit.hash().unwrap(), tx_index: 1, log_index: 0, log }]);
+    assert!(LogFilter{ address: Some(address), topic: Some([8;32]) }.select(&records).is_empty());
+}
+
+#[test]
+fn bloom_filters_logs() {
+    let log = Log{ address: [1;32], topics: vec![[2;32]], data: vec![] };
+    let receipt = Receipt{ tx_hash: [0;32], success: true, error: None, spice_used: 0, fee: 0, return_value: None, logs: vec![log.clone()] };
+    let bloom = Bloom::of(&[ReceiptRecord{ receipt, block_hash: [0;32], height: 1, index: 0 }]);
+    assert!(bloom.contains(&[1;32]) && bloom.contains(&[2;32]));
+    assert!(!Bloom::new().contains(&[1;32]));
+    let filter = LogFilter{ address: Some([1;32]), topic: Some([3;32]) };
+    assert!(!filter.matches(&log));
+    assert!(LogFilter{ address: None, topic: Some([2;32]) }.matches(&log));
+    assert!(LogFilter::default().may_match(&Bloom::new()));
+}
+
+/// Contract exporting `store`, writing its argument under the storage key `[1,0,..]`, and `load`, reading it back.
+#[cfg(test)]
+const STORAGE_CONTRACT : [u8;164] = [
+    0,97,115,109,1,0,0,0,1,14,3,96,2,127,127,0,96,1,126,0,96,0,1,126,2,37,2,3,101,110,118,11,115,116,111,114,97,103,101,95,103,101,116,0,0,3,101,
+    110,118,11,115,116,111,114,97,103,101,95,115,101,116,0,0,3,3,2,1,2,5,3,1,0,1,7,25,3,6,109,101,109,111,114,121,2,0,5,115,116,111,114,101,0,2,
+    4,108,111,97,100,0,3,10,33,2,15,0,65,32,32,0,55,3,0,65,0,65,32,16,1,11,15,0,65,0,65,192,0,16,0,65,192,0,41,3,0,11,11,7,1,0,65,0,11,1,1,0,18,
+    4,110,97,109,101,1,11,2,0,3,103,101,116,1,3,115,101,116
+];
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn contract_storage() {
+    use crate::transaction::{TxBody, VmCall};
+    let kp = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng);
+    let address = do_hash(&STORAGE_CONTRACT.to_vec());
+    let call = |nonce, func : &str, params| {
+        let mut body = TxBody::new(address, nonce, 0, serde_json::to_vec(&VmCall{
+            prev_root: [0;32], res_root: [0;32], sc_hash: address, func: func.to_string(), params
+        }).unwrap());
+        body.spice_limit += 20000;
+        Transaction::new(body, &kp, &crate::chainspec::Domain::new("test")).unwrap()
+    };
+    let deploy = Transaction::new(TxBody::new(crate::transaction::DEPLOY_ACCOUNT, 0, 0, STORAGE_CONTRACT.to_vec()), &kp, &crate::chainspec::Domain::new("test")).unwrap();
+    let (store, load) = (call(1, "store", vec![serde_json::json!(5)]), call(2, "load", vec![]));
+    let executed = execute_with(&[&deploy, &store, &load], |_|Ok(None), |_|Ok(None)).unwrap();
+    //later calls of the block read the writes of the earlier ones
+    assert!(match executed.receipts[2].return_value { Some(VMReturn::U64(5)) => true, _ => false });
+    let mut key = [0;32];
+    key[0] = 1;
+    let mut value = [0;32];
+    value[0] = 5;
+    assert_eq!(executed.storage.len(), 1);
+    assert_eq!(executed.storage[&crate::state::storage_key(&address, &key)], value);
+    //and later blocks read the stored ones
+    let code = |_ : &[u8;32]| Ok(Some(STORAGE_CONTRACT.to_vec()));
+    let stored = execute_with(&[&load], code, |k|Ok(if *k == crate::state::storage_key(&address, &key) { Some(value) } else { None })).unwrap();
+    assert!(match stored.receipts[0].return_value { Some(VMReturn::U64(5)) => true, _ => false });
+    assert!(stored.storage.is_empty());
+}
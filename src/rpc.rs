@@ -2,6 +2,7 @@ use serde_json::json;
 use serde_derive::Deserialize;
 
 use crate::block::Block;
+use crate::address::Address;
 use crate::event::Event;
 use crate::error::QanError;
 
@@ -18,10 +19,38 @@ struct HashGetter {
 	hash: [u8;32],
 }
 #[derive(Deserialize)]
+struct AddressGetter {
+    #[serde(alias = "hash")]
+	address: Address,
+}
+#[derive(Deserialize)]
 struct IntGetter {
 	height: u64,
 }
 #[derive(Deserialize)]
+struct ConsensusChange {
+    settings    : crate::conset::ConsensusSettings,
+    activation  : u64,
+}
+#[derive(Deserialize)]
+struct AuthorityChange {
+    action  : String,
+    #[serde(alias = "address")]
+    hash    : Address,
+}
+#[derive(Deserialize)]
+struct Transfer {
+    to      : Address,
+    amount  : u64,
+}
+#[derive(Deserialize)]
+struct LogQuery {
+    from    : u64,
+    to      : u64,
+    #[serde(flatten)]
+    filter  : crate::receipt::LogFilter,
+}
+#[derive(Deserialize)]
 struct RawTransaction {
     tx: Vec<u8>
 	// tx: crate::transaction::Transaction,
@@ -54,12 +83,18 @@ impl Meta{
     }
 }
 
+/// Most blocks a `get_logs` query may span.
+const MAX_LOG_RANGE : u64 = 10_000;
+/// Most spice a `callVm` call may use. It runs off chain and isn't paid for.
+const CALL_SPICE_LIMIT : u64 = 100_000_000;
+
 /// Starter function for the JSON-RPC. Methods are explained and exampled separately.
 pub fn start_rpc(
     sendr           : std::sync::mpsc::SyncSender<Event>, 
     blocks_db       : Arc<DB>, 
     tx_db           : Arc<DB>, 
     accounts        : Arc<DB>,
+    receipts        : Arc<DB>,
     auth_token      : String,
     tvm             : Arc<RwLock<crate::vm::VM>>
 ){
@@ -85,12 +120,69 @@ pub fn start_rpc(
         io.add_method_with_meta("publish_raw_transaction", move |params: Params, meta: Meta| {
             if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
             let parsed : RawTransaction = params.parse().expect("76: cant parse rawtransaction");
+            if let Err(r) = crate::validation::pre_validate(&parsed.tx, crate::util::timestamp()) {
+                return Err(jsonrpc_core::Error::invalid_params(r.to_string()))
+            }
             match rawtxpub_sender.clone().send(Event::RawTransaction(parsed.tx)){
                 Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
                 Ok(_) => return Ok(Value::String("transaction_sent".to_string())),
             }
         });
 
+        let transfer_sender = sendr.clone();
+        io.add_method_with_meta("transfer", move |params: Params, meta: Meta| {
+            if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
+            let parsed : Transfer = params.parse()?;
+            match transfer_sender.send(Event::Transfer(parsed.to.0, parsed.amount)){
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+                Ok(_) => return Ok(Value::String("transaction_sent".to_string())),
+            }
+        });
+
+        let syscall_sender = sendr.clone();
+        io.add_method_with_meta("set_consensus_settings", move |params: Params, meta: Meta| {
+            if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
+            let parsed : ConsensusChange = params.parse()?;
+            match syscall_sender.send(Event::SystemCall(crate::governance::SystemCall::SetConsensus{ settings: parsed.settings, activation: parsed.activation })){
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+                Ok(_) => return Ok(Value::String("transaction_sent".to_string())),
+            }
+        });
+
+        let auth_sender = sendr.clone();
+        io.add_method_with_meta("change_authority", move |params: Params, meta: Meta| {
+            if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
+            let parsed : AuthorityChange = params.parse()?;
+            let call = match parsed.action.as_str() {
+                "add" => crate::governance::SystemCall::AddAuthority{ id: parsed.hash.0 },
+                "remove" => crate::governance::SystemCall::RemoveAuthority{ id: parsed.hash.0 },
+                _ => return Err(jsonrpc_core::Error::invalid_params("action has to be \"add\" or \"remove\"")),
+            };
+            match auth_sender.send(Event::SystemCall(call)){
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+                Ok(_) => return Ok(Value::String("transaction_sent".to_string())),
+            }
+        });
+
+        let ev_blocks_db = blocks_db.clone();
+        io.add_method_with_meta("get_evidence", move | _params: Params, meta: Meta| {
+            if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
+            match crate::evidence::load_all(&ev_blocks_db) {
+                Ok(value) => return Ok(json![value]),
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+            };
+        });
+
+        let sched_blocks_db = blocks_db.clone();
+        io.add_method_with_meta("get_consensus_schedule", move | _params: Params, meta: Meta| {
+            if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
+            match crate::governance::ConsensusSchedule::load(&sched_blocks_db) {
+                Ok(Some(value)) => return Ok(json![value.entries()]),
+                Ok(None) => return Err(jsonrpc_core::Error::internal_error()),
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+            };
+        });
+
         let byh_blocks_db = blocks_db.clone();
         io.add_method_with_meta("block_by_height", move |params: Params, meta: Meta| {
             if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
@@ -111,16 +203,80 @@ pub fn start_rpc(
             };
         });
 
-        io.add_method_with_meta("get_account", move |params: Params, meta: Meta| {
+        let hbyh_blocks_db = blocks_db.clone();
+        io.add_method_with_meta("header_by_height", move |params: Params, meta: Meta| {
             if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
-            let parsed : HashGetter = params.parse().expect("104: cant parse hashgetter");
-            let bh = match accounts.get(&parsed.hash) {
-                Ok(Some(value)) => return Ok(json![String::from_utf8(value).unwrap()]),
+            let parsed : IntGetter = params.parse()?;
+            let bh = match hbyh_blocks_db.get(crate::fork::height_key(parsed.height)) {
+                Ok(Some(value)) => crate::util::vec_to_arr(&value),
+                Ok(None) => return Err(jsonrpc_core::Error::internal_error()),
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+            };
+            match crate::fork::load_block(&hbyh_blocks_db, &bh) {
+                Ok(Some(value)) => return Ok(json![value.header()]),
                 Ok(None) => return Err(jsonrpc_core::Error::internal_error()),
                 Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
             };
         });
 
+        let vm_accounts = accounts.clone();
+        io.add_method_with_meta("get_account", move |params: Params, meta: Meta| {
+            if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
+            let parsed : AddressGetter = params.parse()?;
+            match crate::state::AccountState::load(&accounts, &parsed.address.0) {
+                Ok(account) => return Ok(json!({"address": parsed.address, "balance": account.balance, "nonce": account.nonce})),
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+            };
+        });
+
+        let logs_blocks_db = blocks_db.clone();
+        let logs_receipts = receipts.clone();
+        io.add_method_with_meta("get_logs", move |params: Params, meta: Meta| {
+            if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
+            let parsed : LogQuery = params.parse()?;
+            if parsed.from > parsed.to || parsed.to - parsed.from >= MAX_LOG_RANGE {
+                return Err(jsonrpc_core::Error::invalid_params(format!("block range has to be ordered and span at most {} blocks", MAX_LOG_RANGE)))
+            }
+            let mut logs = Vec::new();
+            for height in parsed.from..=parsed.to {
+                let bh = match logs_blocks_db.get(crate::fork::height_key(height)) {
+                    Ok(Some(value)) => crate::util::vec_to_arr(&value),
+                    Ok(None) => break,
+                    Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+                };
+                match crate::receipt::load_bloom(&logs_receipts, &bh) {
+                    Ok(Some(bloom)) => if !parsed.filter.may_match(&bloom) { continue },
+                    Ok(None) => (),
+                    Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+                };
+                let block = match crate::fork::load_block(&logs_blocks_db, &bh) {
+                    Ok(Some(b)) => b,
+                    Ok(None) => return Err(jsonrpc_core::Error::internal_error()),
+                    Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+                };
+                let mut records = Vec::new();
+                for txh in &block.body.txes {
+                    match crate::receipt::load(&logs_receipts, txh) {
+                        Ok(Some(r)) => records.push(r),
+                        Ok(None) => continue,
+                        Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+                    };
+                }
+                logs.extend(parsed.filter.select(&records));
+            }
+            return Ok(json![logs]);
+        });
+
+        io.add_method_with_meta("get_transaction_receipt", move |params: Params, meta: Meta| {
+            if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
+            let parsed : HashGetter = params.parse()?;
+            match crate::receipt::load(&receipts, &parsed.hash) {
+                Ok(Some(value)) => return Ok(json![value]),
+                Ok(None) => return Err(jsonrpc_core::Error::invalid_params("transaction is not on the canonical chain")),
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+            };
+        });
+
         let gettx_sender = sendr.clone();
         io.add_method_with_meta("get_transaction", move |params: Params, meta: Meta| {
             if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
@@ -131,6 +287,62 @@ pub fn start_rpc(
             return Ok(json![&ret]);
         });
 
+        let chain_blocks_db = blocks_db.clone();
+        io.add_method_with_meta("get_chain_id", move | _params: Params, meta: Meta| {
+            if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
+            match crate::chainspec::Domain::load_chain_id(&chain_blocks_db) {
+                Ok(id) => return Ok(json!({"chain_id": id, "domain": crate::chainspec::Domain::new(&id).0, "address_prefix": crate::address::hrp()})),
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+            };
+        });
+
+        let auth_blocks_db = blocks_db.clone();
+        io.add_method_with_meta("get_authorities", move | _params: Params, meta: Meta| {
+            if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
+            match crate::authority::AuthorityHistory::load(&auth_blocks_db) {
+                Ok(Some(value)) => return Ok(json![{"sets": value.sets(), "pending": value.pending()}]),
+                Ok(None) => return Err(jsonrpc_core::Error::internal_error()),
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+            };
+        });
+
+        let fin_blocks_db = blocks_db.clone();
+        io.add_method_with_meta("get_finalized_block", move | _params: Params, meta: Meta| {
+            if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
+            let height = match crate::finality::load_finalized(&fin_blocks_db) {
+                Ok(h) => h,
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+            };
+            match crate::fork::load_block(&fin_blocks_db, &match fin_blocks_db.get(crate::fork::height_key(height)) {
+                Ok(Some(value)) => crate::util::vec_to_arr(&value),
+                Ok(None) => return Err(jsonrpc_core::Error::internal_error()),
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+            }) {
+                Ok(Some(value)) => return Ok(json![value]),
+                Ok(None) => return Err(jsonrpc_core::Error::internal_error()),
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+            };
+        });
+
+        let proof_blocks_db = blocks_db.clone();
+        io.add_method_with_meta("get_transaction_proof", move | params: Params, meta: Meta| {
+            if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
+            let parsed : HashGetter = params.parse()?;
+            let block = match crate::fork::tx_block(&proof_blocks_db, &parsed.hash) {
+                Ok(Some(bh)) => match crate::fork::load_block(&proof_blocks_db, &bh) {
+                    Ok(Some(b)) => b,
+                    Ok(None) => return Err(jsonrpc_core::Error::internal_error()),
+                    Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+                },
+                Ok(None) => return Err(jsonrpc_core::Error::invalid_params("transaction is not on the canonical chain")),
+                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+            };
+            match block.prove(&parsed.hash) {
+                Some(proof) => return Ok(json!({"block_hash": block.hash(), "height": block.height(), "tx_root": block.merkle(), "proof": proof})),
+                None => return Err(jsonrpc_core::Error::internal_error()),
+            };
+        });
+
         io.add_method_with_meta("block_by_hash", move | params: Params, meta: Meta| {
             if !meta.check(){return Err(jsonrpc_core::Error::new(jsonrpc_core::ErrorCode::ServerError(403)))}
             let parsed: HashGetter = params.parse().expect("137: cant parse hashgetter");
@@ -170,9 +382,18 @@ pub fn start_rpc(
                     match arr.len(){
                         0 | 1=> return Err(jsonrpc_core::Error::invalid_request()),
                         _ => {
-                            let (sc, fun, arr) = crate::vm::VM::handle_rpc_in(arr).expect("none from rpc");
-                            let ret = tvm.read().unwrap().call_fun(sc, fun, arr);
-                            return Ok(json![serde_json::to_string(&ret).unwrap()])
+                            let (sc, fun, arr) = match crate::vm::VM::handle_rpc_in(arr) {
+                                Some(call) => call,
+                                None => return Err(jsonrpc_core::Error::invalid_params("expected a contract, a function and integer parameters")),
+                            };
+                            let called = match tvm.read().unwrap().call_fun(sc, fun, arr, CALL_SPICE_LIMIT, &mut |key|crate::state::contract_storage(&vm_accounts, key)) {
+                                Ok(c) => c,
+                                Err(_e) => return Err(jsonrpc_core::Error::internal_error()),
+                            };
+                            return match called.result {
+                                Ok(ret) => Ok(json!({"result": serde_json::to_string(&ret).unwrap(), "logs": called.logs, "spice": called.spice})),
+                                Err(e) => Err(jsonrpc_core::Error::invalid_params(e))
+                            }
                         }
                     }
                 },
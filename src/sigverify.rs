@@ -0,0 +1,86 @@
+//! Signature verification of the transactions of imported blocks, spread over the rayon worker pool.
+//! On the ed25519 network chunks of `BATCH_SIZE` transactions are batch verified, and the transactions of a failing chunk
+//! are checked one by one to find the invalid signature. GLP signatures are verified one by one on the workers.
+use rayon::prelude::*;
+use rocksdb::DB;
+#[cfg(not(feature = "quantum"))]
+use ed25519_dalek::{PublicKey, Signature};
+#[cfg(feature = "quantum")]
+use glp::glp::GlpPk;
+use crate::transaction::Transaction;
+use crate::chainspec::Domain;
+use crate::error::QanError;
+
+/// Number of signatures verified in one batch.
+pub const BATCH_SIZE : usize = 64;
+
+#[cfg(not(feature = "quantum"))]
+fn parse_key(pk : &[u8]) -> Option<PublicKey> {
+    PublicKey::from_bytes(pk).ok()
+}
+
+#[cfg(feature = "quantum")]
+fn parse_key(pk : &Vec<u8>) -> Option<GlpPk> {
+    Some(GlpPk::from_bytes(pk))
+}
+
+/// Verifies the signatures of `txes` with their senders' public keys, which have to be in `pubkeys` already.
+/// Returns the index of the first transaction with an invalid signature or without a valid key, `None` if all of them are valid.
+pub fn first_invalid(pubkeys : &DB, txes : &[&Transaction], domain : &Domain) -> Result<Option<usize>, QanError> {
+    let mut keys = Vec::with_capacity(txes.len());
+    for (i, tx) in txes.iter().enumerate() {
+        match pubkeys.get(&tx.pubkey).map_err(|e|QanError::Database(e))?.and_then(|pk|parse_key(&pk)) {
+            Some(k) => keys.push(k),
+            None => return Ok(Some(i))
+        }
+    }
+    verify_with(txes, &keys, domain)
+}
+
+#[cfg(not(feature = "quantum"))]
+fn verify_with(txes : &[&Transaction], keys : &[PublicKey], domain : &Domain) -> Result<Option<usize>, QanError> {
+    let payloads = txes.par_iter().map(|t|crate::codec::encode(&t.transaction).map(|b|domain.payload(&b)))
+        .collect::<Result<Vec<Vec<u8>>, crate::codec::Error>>().map_err(|e|QanError::Codec(e))?;
+    let verify_one = |i : usize| Signature::from_bytes(&txes[i].sig).map_or(false, |s|keys[i].verify(&payloads[i], &s).is_ok());
+    let chunks : Vec<usize> = (0..txes.len()).step_by(BATCH_SIZE).collect();
+    Ok(chunks.par_iter().filter_map(|start| {
+        let end = (start + BATCH_SIZE).min(txes.len());
+        let sigs : Option<Vec<Signature>> = txes[*start..end].iter().map(|t|Signature::from_bytes(&t.sig).ok()).collect();
+        let messages : Vec<&[u8]> = payloads[*start..end].iter().map(|p|p.as_slice()).collect();
+        if let Some(s) = sigs {
+            if ed25519_dalek::verify_batch(&messages, &s, &keys[*start..end]).is_ok() { return None }
+        }
+        //the individual checks decide which transaction of a failed batch is invalid
+        (*start..end).find(|i|!verify_one(*i))
+    }).min())
+}
+
+#[cfg(feature = "quantum")]
+fn verify_with(txes : &[&Transaction], keys : &[GlpPk], domain : &Domain) -> Result<Option<usize>, QanError> {
+    //verification only fails on encoding the transaction, which counts as invalid
+    Ok(txes.par_iter().zip(keys.par_iter()).enumerate()
+        .filter_map(|(i, (t, k))| if t.verify(k, domain).unwrap_or(false) { None } else { Some(i) })
+        .min())
+}
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn finds_invalid_in_batch() {
+    let domain = Domain::new("test");
+    let senders : Vec<ed25519_dalek::Keypair> = (0..3).map(|_|ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng)).collect();
+    let mut txes = Vec::new();
+    let mut keys = Vec::new();
+    for i in 0..BATCH_SIZE as u64 * 2 + 5 {
+        let kp = &senders[i as usize % 3];
+        txes.push(Transaction::new(crate::transaction::TxBody::new([1;32], i, 0, vec![]), kp, &domain).unwrap());
+        keys.push(kp.public);
+    }
+    let refs : Vec<&Transaction> = txes.iter().collect();
+    assert_eq!(verify_with(&refs, &keys, &domain).unwrap(), None);
+    assert_eq!(verify_with(&refs, &keys, &Domain::new("other")).unwrap(), Some(0));
+    let mut forged = txes.clone();
+    forged[BATCH_SIZE + 3].transaction.balance = 1;
+    forged[BATCH_SIZE * 2 + 1].sig = vec![0;64];
+    let refs : Vec<&Transaction> = forged.iter().collect();
+    assert_eq!(verify_with(&refs, &keys, &domain).unwrap(), Some(BATCH_SIZE + 3));
+}
@@ -0,0 +1,40 @@
+//! Aura-style proposer schedule.
+//! The time after a block is split into slots of `slot_duration` milliseconds.
+//! The first slot on top of the block at height N-1 belongs to the authority at index `N % n`,
+//! every slot the proposer misses hands the right to propose over to the next authority in the set.
+use crate::authority::AuthoritySet;
+
+/// Number of the slot `timestamp` falls into, counted from the parent block's timestamp.
+pub fn slot(parent_timestamp : u64, timestamp : u64, slot_duration : u64) -> u64 {
+    timestamp.saturating_sub(parent_timestamp) / slot_duration.max(1)
+}
+
+/// The authority allowed to propose the block at `height` in `slot`.
+pub fn expected_proposer(authorities : &AuthoritySet, height : u64, slot : u64) -> [u8;32] {
+    let members = authorities.members();
+    members[(height.wrapping_add(slot) % members.len() as u64) as usize]
+}
+
+/// Checks whether `proposer` owns the slot of `timestamp` for the block at `height`, built on a parent made at `parent_timestamp`.
+pub fn is_proposer(
+    authorities         : &AuthoritySet,
+    slot_duration       : u64,
+    height              : u64,
+    parent_timestamp    : u64,
+    timestamp           : u64,
+    proposer            : &[u8;32]) -> bool {
+    if timestamp <= parent_timestamp { return false }
+    &expected_proposer(authorities, height, slot(parent_timestamp, timestamp, slot_duration)) == proposer
+}
+
+#[test]
+fn round_robin_with_fallback() {
+    let set = AuthoritySet::new(vec![[0;32], [1;32], [2;32]]);
+    assert_eq!(expected_proposer(&set, 1, slot(1000, 1500, 1000)), [1;32]);
+    assert_eq!(expected_proposer(&set, 2, slot(1000, 1500, 1000)), [2;32]);
+    // primary missed its slot, next authority takes over
+    assert_eq!(expected_proposer(&set, 1, slot(1000, 2500, 1000)), [2;32]);
+    assert_eq!(expected_proposer(&set, 1, slot(1000, 3500, 1000)), [0;32]);
+    assert!(is_proposer(&set, 1000, 1, 1000, 1999, &[1;32]));
+    assert!(!is_proposer(&set, 1000, 1, 1000, 1000, &[1;32]));
+}
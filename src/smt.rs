@@ -0,0 +1,190 @@
+//! Sparse Merkle tree over 256 bit keys, authenticating the account state.
+//! A leaf commits to its key and value. An empty subtree hashes to `EMPTY_ROOT`, and a subtree holding a single leaf
+//! hashes to the leaf itself, so the root only depends on the set of leaves and not on the order they were written in.
+//! Inner nodes are combined with `block::merge`, the left child holding the keys with a 0 bit at the node's depth.
+//! `Tree` updates a persisted tree one leaf at a time, touching only the nodes on the path of the key.
+use std::collections::HashMap;
+use crate::block::{merge, EMPTY_ROOT};
+use crate::error::QanError;
+use crate::util::do_hash;
+
+/// Hash of the leaf of `key` holding `value`.
+pub fn leaf_hash(key : &[u8;32], value : &[u8]) -> [u8;32] {
+    let mut buf = b"leaf".to_vec();
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    do_hash(&buf)
+}
+
+fn bit(key : &[u8;32], depth : usize) -> bool {
+    key[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+fn subtree(leaves : &[([u8;32], [u8;32])], depth : usize) -> [u8;32] {
+    match leaves.len() {
+        0 => EMPTY_ROOT,
+        1 => leaves[0].1,
+        _ => {
+            let split = leaves.iter().position(|(k, _)|bit(k, depth)).unwrap_or(leaves.len());
+            merge(&subtree(&leaves[..split], depth+1), &subtree(&leaves[split..], depth+1))
+        }
+    }
+}
+
+/// Root of the tree holding `leaves`, pairs of a key and its leaf hash. Of leaves with the same key the first one is kept.
+pub fn root(mut leaves : Vec<([u8;32], [u8;32])>) -> [u8;32] {
+    leaves.sort_by(|a, b|a.0.cmp(&b.0));
+    leaves.dedup_by(|a, b|a.0 == b.0);
+    subtree(&leaves, 0)
+}
+
+/// Node of a persisted tree, stored under its hash. A leaf records its key, an inner node its children.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Node {
+    Leaf([u8;32]),
+    Inner([u8;32], [u8;32]),
+}
+
+impl Node {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Node::Leaf(key) => key.to_vec(),
+            Node::Inner(left, right) => [&left[..], &right[..]].concat(),
+        }
+    }
+
+    pub fn from_bytes(b : &[u8]) -> Option<Node> {
+        match b.len() {
+            32 => Some(Node::Leaf(crate::util::vec_to_arr(&b.to_vec()))),
+            64 => Some(Node::Inner(crate::util::vec_to_arr(&b[..32].to_vec()), crate::util::vec_to_arr(&b[32..].to_vec()))),
+            _ => None
+        }
+    }
+}
+
+/// Sparse Merkle tree updated one leaf at a time, with the same root as `root` over the same leaves.
+/// Existing nodes are looked up by hash with `load`, the nodes created by the updates are kept in memory until the owner persists them.
+pub struct Tree<F> {
+    root    : [u8;32],
+    nodes   : HashMap<[u8;32], Node>,
+    load    : F,
+}
+
+impl<F> Tree<F> where F : Fn(&[u8;32]) -> Result<Option<Node>, QanError> {
+    pub fn new(root : [u8;32], load : F) -> Self {
+        Tree{ root, nodes: HashMap::new(), load }
+    }
+
+    pub fn root(&self) -> [u8;32] {
+        self.root
+    }
+
+    /// The nodes created since the tree was loaded.
+    pub fn nodes(&self) -> &HashMap<[u8;32], Node> {
+        &self.nodes
+    }
+
+    /// Sets the leaf hash of `key`, or removes the key with `None`.
+    pub fn update(&mut self, key : &[u8;32], leaf : Option<[u8;32]>) -> Result<(), QanError> {
+        self.root = self.update_at(self.root, 0, key, leaf)?;
+        Ok(())
+    }
+
+    fn node(&self, hash : &[u8;32]) -> Result<Node, QanError> {
+        match self.nodes.get(hash) {
+            Some(n) => Ok(*n),
+            None => (self.load)(hash)?.ok_or(QanError::Internal(format!("missing state tree node {}", hex::encode(hash))))
+        }
+    }
+
+    fn is_leaf(&self, hash : &[u8;32]) -> Result<bool, QanError> {
+        Ok(match self.node(hash)? { Node::Leaf(_) => true, Node::Inner(..) => false })
+    }
+
+    fn leaf(&mut self, key : &[u8;32], leaf : Option<[u8;32]>) -> [u8;32] {
+        match leaf {
+            Some(l) => { self.nodes.insert(l, Node::Leaf(*key)); l },
+            None => EMPTY_ROOT
+        }
+    }
+
+    fn inner(&mut self, left : [u8;32], right : [u8;32]) -> [u8;32] {
+        let hash = merge(&left, &right);
+        self.nodes.insert(hash, Node::Inner(left, right));
+        hash
+    }
+
+    fn update_at(&mut self, node : [u8;32], depth : usize, key : &[u8;32], leaf : Option<[u8;32]>) -> Result<[u8;32], QanError> {
+        if node == EMPTY_ROOT { return Ok(self.leaf(key, leaf)) }
+        match self.node(&node)? {
+            Node::Leaf(k) if k == *key => Ok(self.leaf(key, leaf)),
+            Node::Leaf(k) => match leaf {
+                Some(l) => {
+                    self.nodes.insert(l, Node::Leaf(*key));
+                    Ok(self.pair(&k, node, key, l, depth))
+                },
+                None => Ok(node)
+            },
+            Node::Inner(left, right) => {
+                let (left, right) = if bit(key, depth) {
+                    (left, self.update_at(right, depth+1, key, leaf)?)
+                } else {
+                    (self.update_at(left, depth+1, key, leaf)?, right)
+                };
+                //a subtree left with a single leaf is the leaf itself
+                if left == EMPTY_ROOT && (right == EMPTY_ROOT || self.is_leaf(&right)?) { return Ok(right) }
+                if right == EMPTY_ROOT && self.is_leaf(&left)? { return Ok(left) }
+                Ok(self.inner(left, right))
+            }
+        }
+    }
+
+    /// Subtree at `depth` holding two leaves.
+    fn pair(&mut self, k1 : &[u8;32], l1 : [u8;32], k2 : &[u8;32], l2 : [u8;32], depth : usize) -> [u8;32] {
+        let (b1, b2) = (bit(k1, depth), bit(k2, depth));
+        let (left, right) = if b1 == b2 {
+            let sub = self.pair(k1, l1, k2, l2, depth+1);
+            if b1 { (EMPTY_ROOT, sub) } else { (sub, EMPTY_ROOT) }
+        } else if b1 { (l2, l1) } else { (l1, l2) };
+        self.inner(left, right)
+    }
+}
+
+#[test]
+fn order_independent_root() {
+    let leaf = |k : u8| { let mut key = [0;32]; key[0] = k; (key, leaf_hash(&key, &[k])) };
+    assert_eq!(root(Vec::new()), EMPTY_ROOT);
+    assert_eq!(root(vec![leaf(1)]), leaf(1).1);
+    let a = root(vec![leaf(1), leaf(0x80), leaf(0x81)]);
+    assert_eq!(a, root(vec![leaf(0x81), leaf(1), leaf(0x80)]));
+    //0x80 and 0x81 share the bits from depth 1 to 6, and split at depth 7
+    let mut shared = merge(&leaf(0x80).1, &leaf(0x81).1);
+    for _ in 1..7 { shared = merge(&shared, &EMPTY_ROOT); }
+    assert_eq!(a, merge(&leaf(1).1, &shared));
+    assert_ne!(a, root(vec![leaf(1), leaf(0x80)]));
+}
+
+#[test]
+fn incremental_updates() {
+    let leaf = |k : u8, v : u8| { let mut key = [0;32]; key[0] = k; (key, leaf_hash(&key, &[v])) };
+    let mut stored : HashMap<[u8;32], Node> = HashMap::new();
+    let mut current = EMPTY_ROOT;
+    let mut apply = |changes : Vec<([u8;32], Option<[u8;32]>)>| {
+        let (r, nodes) = {
+            let mut tree = Tree::new(current, |h : &[u8;32]| Ok(stored.get(h).cloned()));
+            for (k, l) in changes { tree.update(&k, l).unwrap(); }
+            (tree.root(), tree.nodes().clone())
+        };
+        stored.extend(nodes);
+        current = r;
+        r
+    };
+    let (a, b, c) = (leaf(1, 1), leaf(0x80, 1), leaf(0x81, 1));
+    assert_eq!(apply(vec![(a.0, Some(a.1))]), root(vec![a]));
+    assert_eq!(apply(vec![(c.0, Some(c.1)), (b.0, Some(b.1))]), root(vec![a, b, c]));
+    let b2 = leaf(0x80, 2);
+    assert_eq!(apply(vec![(b2.0, Some(b2.1))]), root(vec![a, b2, c]));
+    assert_eq!(apply(vec![(a.0, None)]), root(vec![b2, c]));
+    assert_eq!(apply(vec![(c.0, None), (leaf(7, 0).0, None)]), b2.1);
+    assert_eq!(apply(vec![(b2.0, None)]), EMPTY_ROOT);
+}
@@ -0,0 +1,55 @@
+//! Transaction fees. Every transaction sets the price it pays per unit of "spice" and the most spice it may consume.
+//! The fee is the consumed spice times the price. It is debited from the sender and credited to the proposer of the including block,
+//! transactions whose intrinsic spice is over their limit or whose sender can't pay the fee are invalid.
+//! Contract calls also consume the spice of their metered execution, a call running out of its limit fails and pays the whole limit.
+use crate::transaction::TxBody;
+
+/// Spice consumed by every transaction.
+pub const TX_SPICE : u64 = 1000;
+/// Spice consumed per byte of transaction data.
+pub const BYTE_SPICE : u64 = 10;
+/// Additional spice consumed by a contract call.
+pub const CALL_SPICE : u64 = 5000;
+/// Spice a contract call consumes reading a value of its storage.
+pub const LOAD_SPICE : u64 = 200;
+/// Spice a contract call consumes writing a value of its storage.
+pub const STORE_SPICE : u64 = 5000;
+
+/// Intrinsic spice of the transaction, consumed before executing a contract call.
+pub fn consumed(body : &TxBody) -> u64 {
+    let mut spice = TX_SPICE + BYTE_SPICE * body.data.len() as u64;
+    if let Some(call) = body.vm_call() {
+        spice += CALL_SPICE + BYTE_SPICE * call.len() as u64;
+    }
+    spice
+}
+
+/// Fee of the intrinsic spice of the transaction, `None` if it is over the limit or the fee overflows.
+pub fn fee(body : &TxBody) -> Option<u64> {
+    let spice = consumed(body);
+    if spice > body.spice_limit { return None }
+    spice.checked_mul(body.spice_price)
+}
+
+/// Fee charged for the transaction using `used` spice, at most its limit.
+pub fn charge(body : &TxBody, used : u64) -> Option<u64> {
+    used.min(body.spice_limit).checked_mul(body.spice_price)
+}
+
+/// The highest fee the transaction may be charged, the limit times the price.
+pub fn max_fee(body : &TxBody) -> Option<u64> {
+    body.spice_limit.checked_mul(body.spice_price)
+}
+
+#[test]
+fn fee_of_consumed_spice() {
+    let mut body = TxBody::new([0;32], 0, 5, vec![0;10]);
+    assert_eq!(consumed(&body), TX_SPICE + 10 * BYTE_SPICE);
+    body.spice_price = 2;
+    body.spice_limit = consumed(&body) - 1;
+    assert_eq!(fee(&body), None);
+    body.spice_limit = 2 * consumed(&body);
+    assert_eq!(fee(&body), Some(2 * consumed(&body)));
+    assert_eq!(max_fee(&body), Some(4 * consumed(&body)));
+    assert_eq!(charge(&body, 3 * consumed(&body)), max_fee(&body));
+}
@@ -0,0 +1,472 @@
+//! Account state. Every account has a balance and the number of transactions it sent, which is the nonce of its next transaction.
+//! The `balance` of a transaction is the amount transferred, debited from the sender and credited to the recipient
+//! when the block including it becomes canonical, and reverted when a reorg retracts the block.
+//! Transactions of a sender are applied in nonce order without gaps, so a signed transaction can't be replayed or reordered.
+//! The sender also pays the fee of the transaction to the block's proposer, see `spice`.
+//! The genesis transaction allocates the initial balances.
+//! Every block header commits to the account states after executing the block in `state_root`, the root of a sparse Merkle tree
+//! with a leaf for every non-empty account, and importing nodes check it.
+//! The tree is kept in the accounts database next to the accounts and updated with the accounts a block changes.
+//! Contracts deployed by a block are stored in the accounts database too, and the tree holds a leaf committing to the code of each,
+//! and a leaf for every non-zero value of contract storage.
+use std::collections::HashMap;
+use crate::mempool::Mempool;
+use rocksdb::{DB, IteratorMode};
+use crate::block::{Block, EMPTY_ROOT};
+use crate::smt::{Node, Tree};
+use crate::transaction::Transaction;
+use crate::receipt::{Execution, Receipt};
+use crate::error::QanError;
+use crate::util::do_hash;
+
+/// Balance of every genesis authority on a newly generated chain.
+pub const GENESIS_BALANCE : u64 = 1_000_000_000;
+/// Key of the root of the persisted state tree in the accounts database.
+pub const TREE_ROOT_KEY : &'static str = "state_root";
+
+/// State of an account, stored in the accounts database under the public key hash.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct AccountState {
+    pub balance : u64,
+    pub nonce   : u64,
+}
+
+impl AccountState {
+    /// Loads the state of the account `id`, accounts never seen are empty.
+    pub fn load(accounts : &DB, id : &[u8;32]) -> Result<Self, QanError> {
+        match accounts.get(id).map_err(|e|QanError::Database(e))?{
+            Some(a) => Ok(serde_json::from_slice(&a).map_err(|e|QanError::Serde(e))?),
+            None => Ok(AccountState::default())
+        }
+    }
+
+    /// Persists the state of the account `id`.
+    pub fn store(&self, accounts : &DB, id : &[u8;32]) -> Result<(), QanError> {
+        accounts.put(id, serde_json::to_vec(&self).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))
+    }
+}
+
+/// Executes the transfers of `txes` in order on top of the states returned by `load`, paying the fees to `proposer`.
+/// The fees come from the `receipts` of the transactions, and the amount of a failed transaction isn't transferred.
+/// Returns the changed account states, or `None` if a nonce is out of order, a transaction exceeds its spice limit,
+/// a sender can't pay the amount and the fee, or a balance would overflow.
+pub fn apply_transfers<F>(txes : &[&Transaction], receipts : &[Receipt], proposer : &[u8;32], mut load : F) -> Result<Option<HashMap<[u8;32], AccountState>>, QanError>
+    where F : FnMut(&[u8;32]) -> Result<AccountState, QanError> {
+    let mut changed : HashMap<[u8;32], AccountState> = HashMap::new();
+    if txes.len() != receipts.len() { return Ok(None) }
+    for (tx, receipt) in txes.iter().zip(receipts) {
+        if crate::spice::fee(&tx.transaction).is_none() || crate::spice::max_fee(&tx.transaction).is_none() { return Ok(None) }
+        let amount = if receipt.success { tx.transaction.balance } else { 0 };
+        let fee = receipt.fee;
+        let mut sender = match changed.get(&tx.pubkey) { Some(a) => *a, None => load(&tx.pubkey)? };
+        if tx.transaction.nonce != sender.nonce { return Ok(None) }
+        sender.balance = match sender.balance.checked_sub(amount).and_then(|b|b.checked_sub(fee)) {
+            Some(b) => b,
+            None => return Ok(None)
+        };
+        sender.nonce += 1;
+        changed.insert(tx.pubkey, sender);
+        let recipient_id = tx.transaction.recipient;
+        let mut recipient = match changed.get(&recipient_id) { Some(a) => *a, None => load(&recipient_id)? };
+        recipient.balance = match recipient.balance.checked_add(amount) {
+            Some(b) => b,
+            None => return Ok(None)
+        };
+        changed.insert(recipient_id, recipient);
+        let mut fee_recipient = match changed.get(proposer) { Some(a) => *a, None => load(proposer)? };
+        fee_recipient.balance = match fee_recipient.balance.checked_add(fee) {
+            Some(b) => b,
+            None => return Ok(None)
+        };
+        changed.insert(*proposer, fee_recipient);
+    }
+    Ok(Some(changed))
+}
+
+fn account_leaf(id : &[u8;32], account : &AccountState) -> Result<([u8;32], [u8;32]), QanError> {
+    Ok((*id, crate::smt::leaf_hash(id, &crate::codec::encode(account).map_err(|e|QanError::Codec(e))?)))
+}
+
+fn code_key(address : &[u8;32]) -> Vec<u8> {
+    let mut key = b"code".to_vec();
+    key.extend_from_slice(address);
+    key
+}
+
+/// Leaf of the contract at `address`, under the hash of its code key. Contracts are deployed at the hash of their code.
+fn code_leaf(address : &[u8;32]) -> ([u8;32], [u8;32]) {
+    let key = do_hash(&code_key(address));
+    (key, crate::smt::leaf_hash(&key, address))
+}
+
+/// Code of the contract deployed at `address`.
+pub fn contract_code(accounts : &DB, address : &[u8;32]) -> Result<Option<Vec<u8>>, QanError> {
+    Ok(accounts.get(code_key(address)).map_err(|e|QanError::Database(e))?.map(|c|c.to_vec()))
+}
+
+/// Contracts deployed by the successful transactions of `txes`, with their code.
+fn deployments<'a>(txes : &[&'a Transaction], receipts : &[Receipt]) -> Vec<([u8;32], &'a Vec<u8>)> {
+    txes.iter().zip(receipts).filter(|(_, r)|r.success)
+        .filter_map(|(tx, _)|tx.transaction.deployed_contract().map(|a|(a, &tx.transaction.data))).collect()
+}
+
+/// Key of the value `contract` stores under `key`, in the state tree and the accounts database.
+pub fn storage_key(contract : &[u8;32], key : &[u8;32]) -> [u8;32] {
+    let mut both = contract.to_vec();
+    both.extend_from_slice(key);
+    do_hash(&both)
+}
+
+fn storage_db_key(key : &[u8;32]) -> Vec<u8> {
+    let mut db_key = b"storage".to_vec();
+    db_key.extend_from_slice(key);
+    db_key
+}
+
+/// Contract storage value under the storage key `key`, `None` if it is unset.
+pub fn contract_storage(accounts : &DB, key : &[u8;32]) -> Result<Option<[u8;32]>, QanError> {
+    match accounts.get(storage_db_key(key)).map_err(|e|QanError::Database(e))?{
+        Some(v) if v.len() == 32 => Ok(Some(crate::util::vec_to_arr(&v.to_vec()))),
+        Some(_) => Err(QanError::Internal(format!("malformed contract storage value {}", hex::encode(key)))),
+        None => Ok(None)
+    }
+}
+
+/// Writes the contract storage `values`, zero values removing theirs.
+fn store_storage(accounts : &DB, values : &HashMap<[u8;32], [u8;32]>) -> Result<(), QanError> {
+    for (key, value) in values {
+        if *value == [0;32] {
+            accounts.delete(storage_db_key(key)).map_err(|e|QanError::Database(e))?;
+        }else{
+            accounts.put(storage_db_key(key), value).map_err(|e|QanError::Database(e))?;
+        }
+    }
+    Ok(())
+}
+
+fn undo_key(block_hash : &[u8;32]) -> Vec<u8> {
+    let mut key = b"undo".to_vec();
+    key.extend_from_slice(block_hash);
+    key
+}
+
+fn node_key(hash : &[u8;32]) -> Vec<u8> {
+    let mut key = b"smt".to_vec();
+    key.extend_from_slice(hash);
+    key
+}
+
+fn load_node(accounts : &DB, hash : &[u8;32]) -> Result<Option<Node>, QanError> {
+    match accounts.get(node_key(hash)).map_err(|e|QanError::Database(e))?{
+        Some(n) => Ok(Some(Node::from_bytes(&n).ok_or(QanError::Internal(format!("malformed state tree node {}", hex::encode(hash))))?)),
+        None => Ok(None)
+    }
+}
+
+/// The persisted state tree. Databases from before the tree was persisted get it built from their accounts once.
+fn load_tree(accounts : &DB) -> Result<Tree<impl Fn(&[u8;32]) -> Result<Option<Node>, QanError> + '_>, QanError> {
+    let root = match accounts.get(TREE_ROOT_KEY).map_err(|e|QanError::Database(e))?{
+        Some(r) if r.len() == 32 => crate::util::vec_to_arr(&r.to_vec()),
+        _ => {
+            let mut tree = Tree::new(EMPTY_ROOT, move |h : &[u8;32]|load_node(accounts, h));
+            for (k, v) in accounts.iterator(IteratorMode::Start) {
+                if k.len() == 36 && k.starts_with(b"code") {
+                    let (key, leaf) = code_leaf(&crate::util::vec_to_arr(&k[4..].to_vec()));
+                    tree.update(&key, Some(leaf))?;
+                }
+                if k.len() == 39 && k.starts_with(b"storage") {
+                    let key = crate::util::vec_to_arr(&k[7..].to_vec());
+                    tree.update(&key, Some(crate::smt::leaf_hash(&key, &v)))?;
+                }
+                if k.len() != 32 { continue }
+                let id = crate::util::vec_to_arr(&k.to_vec());
+                let account : AccountState = serde_json::from_slice(&v).map_err(|e|QanError::Serde(e))?;
+                if account != AccountState::default() { tree.update(&id, Some(account_leaf(&id, &account)?.1))?; }
+            }
+            store_tree(accounts, &tree)?;
+            tree.root()
+        }
+    };
+    Ok(Tree::new(root, move |h : &[u8;32]|load_node(accounts, h)))
+}
+
+/// Writes the nodes created by the updates of `tree` and its root.
+fn store_tree<F>(accounts : &DB, tree : &Tree<F>) -> Result<(), QanError> where F : Fn(&[u8;32]) -> Result<Option<Node>, QanError> {
+    for (hash, node) in tree.nodes() {
+        accounts.put(node_key(hash), node.to_bytes()).map_err(|e|QanError::Database(e))?;
+    }
+    accounts.put(TREE_ROOT_KEY, tree.root()).map_err(|e|QanError::Database(e))
+}
+
+/// Puts the `changed` accounts into the tree, removing the empty ones.
+fn update_tree<F>(tree : &mut Tree<F>, changed : &HashMap<[u8;32], AccountState>) -> Result<(), QanError> where F : Fn(&[u8;32]) -> Result<Option<Node>, QanError> {
+    for (id, account) in changed.iter() {
+        let leaf = if *account == AccountState::default() { None } else { Some(account_leaf(id, account)?.1) };
+        tree.update(id, leaf)?;
+    }
+    Ok(())
+}
+
+/// Adds the leaves of the contracts deployed at `addresses` to the tree, or removes them if `deployed` is false.
+fn update_contracts<F>(tree : &mut Tree<F>, addresses : &[[u8;32]], deployed : bool) -> Result<(), QanError> where F : Fn(&[u8;32]) -> Result<Option<Node>, QanError> {
+    for address in addresses {
+        let (key, leaf) = code_leaf(address);
+        tree.update(&key, if deployed { Some(leaf) } else { None })?;
+    }
+    Ok(())
+}
+
+/// Puts the contract storage `values` into the tree, removing the zero ones.
+fn update_storage<F>(tree : &mut Tree<F>, values : &HashMap<[u8;32], [u8;32]>) -> Result<(), QanError> where F : Fn(&[u8;32]) -> Result<Option<Node>, QanError> {
+    for (key, value) in values {
+        tree.update(key, if *value == [0;32] { None } else { Some(crate::smt::leaf_hash(key, value)) })?;
+    }
+    Ok(())
+}
+
+/// Root of the state tree over the stored accounts, contracts and contract storage, with the `changed` accounts replacing them,
+/// the `contracts` deployed and the `storage` values written. Empty accounts are left out. Only the paths of the changes are computed, nothing is written.
+pub fn state_root(accounts : &DB, changed : &HashMap<[u8;32], AccountState>, contracts : &[[u8;32]], storage : &HashMap<[u8;32], [u8;32]>) -> Result<[u8;32], QanError> {
+    let mut tree = load_tree(accounts)?;
+    update_tree(&mut tree, changed)?;
+    update_contracts(&mut tree, contracts, true)?;
+    update_storage(&mut tree, storage)?;
+    Ok(tree.root())
+}
+
+/// State root after `proposer` executes `txes` in order on top of the stored state, `None` if the transfers are invalid.
+pub fn root_after(accounts : &DB, txes : &[&Transaction], executed : &Execution, proposer : &[u8;32]) -> Result<Option<[u8;32]>, QanError> {
+    match apply_transfers(txes, &executed.receipts, proposer, |id|AccountState::load(accounts, id))?{
+        Some(changed) => {
+            let contracts : Vec<[u8;32]> = deployments(txes, &executed.receipts).iter().map(|(a, _)|*a).collect();
+            Ok(Some(state_root(accounts, &changed, &contracts, &executed.storage)?))
+        },
+        None => Ok(None)
+    }
+}
+
+/// State root of the initial `balances` of the genesis transaction.
+pub fn genesis_root(balances : &[([u8;32], u64)]) -> Result<[u8;32], QanError> {
+    let mut allocated : HashMap<[u8;32], AccountState> = HashMap::new();
+    for (id, balance) in balances {
+        allocated.insert(*id, AccountState{ balance: *balance, nonce: 0 });
+    }
+    let mut leaves = Vec::new();
+    for (id, account) in allocated.iter() {
+        if *account != AccountState::default() { leaves.push(account_leaf(id, account)?) }
+    }
+    Ok(crate::smt::root(leaves))
+}
+
+/// Stores the initial `balances` of the genesis transaction, and builds the state tree over them. Returns the state root.
+pub fn store_genesis(accounts : &DB, balances : &[([u8;32], u64)]) -> Result<[u8;32], QanError> {
+    let mut allocated : HashMap<[u8;32], AccountState> = HashMap::new();
+    for (id, balance) in balances {
+        allocated.insert(*id, AccountState{ balance: *balance, nonce: 0 });
+    }
+    let mut tree = Tree::new(EMPTY_ROOT, |h : &[u8;32]|load_node(accounts, h));
+    update_tree(&mut tree, &allocated)?;
+    for (id, account) in allocated.iter() {
+        account.store(accounts, id)?;
+    }
+    store_tree(accounts, &tree)?;
+    accounts.flush().map_err(|e|QanError::Database(e))?;
+    Ok(tree.root())
+}
+
+/// Mempool admission. Checks that the nonce of `tx` is not used on chain yet, that its spice limit covers the spice it consumes,
+/// and that the sender can pay for it and the highest fees together with its other waiting transactions.
+/// A waiting transaction with the same nonce doesn't count, `tx` replaces it if the mempool accepts its price.
+/// Transactions with a nonce above the next one are admitted, they wait in the mempool until the gap is filled.
+pub fn can_admit(accounts : &DB, mempool : &Mempool, tx : &Transaction) -> Result<bool, QanError> {
+    let txh = tx.hash()?;
+    let account = AccountState::load(accounts, &tx.pubkey)?;
+    if tx.transaction.nonce < account.nonce || crate::spice::fee(&tx.transaction).is_none() { return Ok(false) }
+    let cost = |t : &Transaction| crate::spice::max_fee(&t.transaction).and_then(|f|f.checked_add(t.transaction.balance));
+    let mut pending = match cost(tx) {
+        Some(c) => c,
+        None => return Ok(false)
+    };
+    for (h, t) in mempool.iter() {
+        if t.pubkey != tx.pubkey || *h == txh { continue }
+        if t.transaction.nonce == tx.transaction.nonce { continue }
+        pending = match cost(t).and_then(|c|pending.checked_add(c)) {
+            Some(p) => p,
+            None => return Ok(false)
+        };
+    }
+    Ok(account.balance >= pending)
+}
+
+/// Nonce of the next transaction of `id`, following its transactions in the mempool and the `sent` ones that didn't arrive there yet.
+pub fn next_nonce(accounts : &DB, mempool : &Mempool, id : &[u8;32], sent : u64) -> Result<u64, QanError> {
+    let pending = mempool.values().filter(|t|&t.pubkey == id).map(|t|t.transaction.nonce + 1).max().unwrap_or(0);
+    Ok(AccountState::load(accounts, id)?.nonce.max(pending).max(sent))
+}
+
+/// Removes the transactions whose nonce was used on chain already. Returns the number of removed transactions.
+pub fn drop_stale(accounts : &DB, mempool : &mut Mempool) -> Result<usize, QanError> {
+    let mut stale = Vec::new();
+    for (h, t) in mempool.iter() {
+        if t.transaction.nonce < AccountState::load(accounts, &t.pubkey)?.nonce { stale.push(*h) }
+    }
+    Ok(stale.iter().filter_map(|h|mempool.remove(h)).count())
+}
+
+/// Drops the chosen transactions `txes` that can't be executed on top of the previous ones, because of a nonce gap or a too low balance,
+/// keeping the order of the rest. `txes` has to hold the transactions of every sender in nonce order. The fees go to `proposer`.
+pub fn select_executable(accounts : &DB, mempool : &Mempool, txes : Vec<[u8;32]>, proposer : &[u8;32]) -> Result<Vec<[u8;32]>, QanError> {
+    let candidates : Vec<(&[u8;32], &Transaction)> = txes.iter().filter_map(|h|mempool.get_key_value(h)).collect();
+    select_with(&candidates, proposer, |id|AccountState::load(accounts, id))
+}
+
+/// Checks each candidate on top of the account states left by the selected ones, kept in a running map, with its highest fees.
+fn select_with<F>(candidates : &[(&[u8;32], &Transaction)], proposer : &[u8;32], mut load : F) -> Result<Vec<[u8;32]>, QanError>
+    where F : FnMut(&[u8;32]) -> Result<AccountState, QanError> {
+    let mut states : HashMap<[u8;32], AccountState> = HashMap::new();
+    let mut selected : Vec<[u8;32]> = Vec::new();
+    for (txh, tx) in candidates {
+        let estimate = crate::receipt::estimate(tx)?;
+        let changed = apply_transfers(&[*tx], &[estimate], proposer, |id|match states.get(id) {
+            Some(a) => Ok(*a),
+            None => load(id)
+        })?;
+        if let Some(changed) = changed {
+            states.extend(changed);
+            selected.push(**txh);
+        }
+    }
+    Ok(selected)
+}
+
+fn block_transactions(txdb : &DB, block : &Block) -> Result<Vec<Transaction>, QanError> {
+    let mut txes = Vec::new();
+    for txh in &block.body.txes {
+        match txdb.get(txh).map_err(|e|QanError::Database(e))?{
+            Some(t) => txes.push(serde_json::from_slice(&t).map_err(|e|QanError::Serde(e))?),
+            None => return Err(QanError::Internal(format!("missing transaction {} of block {}", hex::encode(txh), hex::encode(block.hash()))))
+        }
+    }
+    Ok(txes)
+}
+
+/// Applies the transfers, fees, contract deployments and contract storage writes of a block becoming canonical, executed into `executed`.
+/// The overwritten storage values are kept until the block is reverted. Every transaction of the block has to be in `txdb`.
+/// Fails without changing any account if a nonce is out of order, a sender overspends, or the resulting state doesn't match the block's state root.
+pub fn apply_block(accounts : &DB, txdb : &DB, block : &Block, executed : &Execution) -> Result<(), QanError> {
+    let txes = block_transactions(txdb, block)?;
+    let refs : Vec<&Transaction> = txes.iter().collect();
+    let changed = match apply_transfers(&refs, &executed.receipts, &block.proposer(), |id|AccountState::load(accounts, id))?{
+        Some(c) => c,
+        None => return Err(QanError::Internal(format!("block {} has invalid transfers", hex::encode(block.hash()))))
+    };
+    let deployed = deployments(&refs, &executed.receipts);
+    let mut tree = load_tree(accounts)?;
+    update_tree(&mut tree, &changed)?;
+    update_contracts(&mut tree, &deployed.iter().map(|(a, _)|*a).collect::<Vec<[u8;32]>>(), true)?;
+    update_storage(&mut tree, &executed.storage)?;
+    if tree.root() != block.signed.header.state_root {
+        return Err(QanError::Internal(format!("state root mismatch in block {}", hex::encode(block.hash()))))
+    }
+    for (id, account) in changed.iter() {
+        account.store(accounts, id)?;
+    }
+    for (address, code) in deployed {
+        accounts.put(code_key(&address), code).map_err(|e|QanError::Database(e))?;
+    }
+    let mut overwritten : Vec<([u8;32], [u8;32])> = Vec::new();
+    for key in executed.storage.keys() {
+        overwritten.push((*key, contract_storage(accounts, key)?.unwrap_or([0;32])));
+    }
+    accounts.put(undo_key(&block.hash()), serde_json::to_vec(&overwritten).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
+    store_storage(accounts, &executed.storage)?;
+    store_tree(accounts, &tree)?;
+    accounts.flush().map_err(|e|QanError::Database(e))
+}
+
+/// Reverts the transfers, fees, contract deployments and contract storage writes of a block retracted from the canonical chain.
+/// Its receipts have to be in `receipts_db` still.
+pub fn revert_block(accounts : &DB, txdb : &DB, receipts_db : &DB, block : &Block) -> Result<(), QanError> {
+    let txes = block_transactions(txdb, block)?;
+    let mut receipts = Vec::new();
+    for tx in &txes {
+        match crate::receipt::load(receipts_db, &tx.hash()?)? {
+            Some(r) => receipts.push(r.receipt),
+            None => return Err(QanError::Internal(format!("missing receipt of transaction {}", hex::encode(tx.hash()?))))
+        }
+    }
+    for (tx, receipt) in txes.iter().zip(&receipts).rev() {
+        let amount = if receipt.success { tx.transaction.balance } else { 0 };
+        let fee = receipt.fee;
+        let mut fee_recipient = AccountState::load(accounts, &block.proposer())?;
+        fee_recipient.balance = fee_recipient.balance.saturating_sub(fee);
+        fee_recipient.store(accounts, &block.proposer())?;
+        let mut recipient = AccountState::load(accounts, &tx.transaction.recipient)?;
+        recipient.balance = recipient.balance.saturating_sub(amount);
+        recipient.store(accounts, &tx.transaction.recipient)?;
+        let mut sender = AccountState::load(accounts, &tx.pubkey)?;
+        sender.balance = sender.balance.saturating_add(amount).saturating_add(fee);
+        sender.nonce = sender.nonce.saturating_sub(1);
+        sender.store(accounts, &tx.pubkey)?;
+    }
+    let mut reverted : HashMap<[u8;32], AccountState> = HashMap::new();
+    for id in txes.iter().flat_map(|tx|vec![tx.pubkey, tx.transaction.recipient]).chain(std::iter::once(block.proposer())) {
+        reverted.insert(id, AccountState::load(accounts, &id)?);
+    }
+    let refs : Vec<&Transaction> = txes.iter().collect();
+    let removed : Vec<[u8;32]> = deployments(&refs, &receipts).iter().map(|(a, _)|*a).collect();
+    for address in &removed {
+        accounts.delete(code_key(address)).map_err(|e|QanError::Database(e))?;
+    }
+    let restored : HashMap<[u8;32], [u8;32]> = match accounts.get(undo_key(&block.hash())).map_err(|e|QanError::Database(e))?{
+        Some(u) => serde_json::from_slice::<Vec<([u8;32], [u8;32])>>(&u).map_err(|e|QanError::Serde(e))?.into_iter().collect(),
+        None => HashMap::new()
+    };
+    store_storage(accounts, &restored)?;
+    accounts.delete(undo_key(&block.hash())).map_err(|e|QanError::Database(e))?;
+    let mut tree = load_tree(accounts)?;
+    update_tree(&mut tree, &reverted)?;
+    update_contracts(&mut tree, &removed, false)?;
+    update_storage(&mut tree, &restored)?;
+    store_tree(accounts, &tree)?;
+    accounts.flush().map_err(|e|QanError::Database(e))
+}
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn transfers() {
+    let kp = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng);
+    let sender = crate::util::do_hash(&kp.public.to_bytes().to_vec());
+    let tx = |nonce, amount| Transaction::new(crate::transaction::TxBody::new([1;32], nonce, amount, vec![]), &kp, &crate::chainspec::Domain::new("test")).unwrap();
+    let r = |txes : &[&Transaction]| txes.iter().map(|t|crate::receipt::estimate(t).unwrap()).collect::<Vec<Receipt>>();
+    let (a, b) = (tx(0, 60), tx(1, 50));
+    let load = |id : &[u8;32]| Ok(if *id == sender { AccountState{ balance: 100, nonce: 0 } }else{ AccountState::default() });
+    let changed = apply_transfers(&[&a], &r(&[&a]), &[2;32], load).unwrap().unwrap();
+    assert_eq!(changed[&sender], AccountState{ balance: 40, nonce: 1 });
+    assert_eq!(changed[&[1;32]].balance, 60);
+    assert!(apply_transfers(&[&a, &b], &r(&[&a, &b]), &[2;32], load).unwrap().is_none());
+    assert!(apply_transfers(&[&a, &a], &r(&[&a, &a]), &[2;32], load).unwrap().is_none());
+    assert!(apply_transfers(&[&tx(1, 10)], &r(&[&tx(1, 10)]), &[2;32], load).unwrap().is_none());
+    assert_eq!(apply_transfers(&[&a, &tx(1, 40)], &r(&[&a, &tx(1, 40)]), &[2;32], load).unwrap().unwrap()[&sender], AccountState{ balance: 0, nonce: 2 });
+    let mut body = crate::transaction::TxBody::new([1;32], 0, 50, vec![]).with_spice_price(1);
+    body.spice_limit = 2 * crate::spice::consumed(&body);
+    let fee = crate::spice::consumed(&body);
+    let load = |id : &[u8;32]| Ok(if *id == sender { AccountState{ balance: 50 + fee, nonce: 0 } }else{ AccountState::default() });
+    let paid = Transaction::new(body.clone(), &kp, &crate::chainspec::Domain::new("test")).unwrap();
+    let changed = apply_transfers(&[&paid], &r(&[&paid]), &[2;32], load).unwrap().unwrap();
+    assert_eq!(changed[&sender].balance, 0);
+    assert_eq!(changed[&[2;32]].balance, fee);
+    //a failed transaction pays the fee without transferring the amount
+    let failed = Receipt{ success: false, ..r(&[&paid]).remove(0) };
+    let changed = apply_transfers(&[&paid], &[failed], &[2;32], load).unwrap().unwrap();
+    assert_eq!((changed[&sender].balance, changed[&[1;32]].balance, changed[&[2;32]].balance), (50, 0, fee));
+    body.balance = 51;
+    let overspent = Transaction::new(body, &kp, &crate::chainspec::Domain::new("test")).unwrap();
+    assert!(apply_transfers(&[&overspent], &r(&[&overspent]), &[2;32], load).unwrap().is_none());
+    //selection skips the transactions the earlier ones leave unpayable and keeps the later ones that fit
+    let load = |id : &[u8;32]| Ok(if *id == sender { AccountState{ balance: 100, nonce: 0 } }else{ AccountState::default() });
+    let (c, d) = (tx(1, 50), tx(1, 30));
+    let hashes : Vec<[u8;32]> = [&a, &c, &d].iter().map(|t|t.hash().unwrap()).collect();
+    let candidates = vec![(&hashes[0], &a), (&hashes[1], &c), (&hashes[2], &d)];
+    assert_eq!(select_with(&candidates, &[2;32], load).unwrap(), vec![hashes[0], hashes[2]]);
+}
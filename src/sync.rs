@@ -14,18 +14,30 @@ use glp::glp::GlpPk;
 use crate::pk::{PetKey, PATHNAME};
 use ed25519_dalek::PublicKey;
 use crate::event::{SyncType, Event};
-use crate::block::{Block, merge};
+use crate::block::Block;
 use crate::conset::ConsensusSettings;
 use crate::util::{do_hash, vec_to_arr};
 use crate::error::QanError;
+use crate::authority::AuthoritySet;
 use rocksdb::DB;
 
 /// getter/init function for genesis block. The node first tries to load block height 0 (aka genesis block) from the database.
 /// In case this was unsuccesful it tries to load it from file. On yet another failure, the node asks for genesis on the network. 
+/// If nobody answers, a new genesis is generated with `authorities` and the own key as the authority set, starting with the `consensus` settings.
+/// With a chain spec none of that happens: the genesis is built from the spec, and a database holding another genesis is an error.
+/// The authority set, consensus settings, chain id, address prefix and initial balances of the genesis transaction are persisted,
+/// and addresses are displayed and parsed with the prefix of the chain from then on.
 pub fn genesis_getter(
-    genesis : &str, 
-    keys    : &PetKey,
-    client  : &Client)-> Result<Block, QanError>{
+    genesis     : &str, 
+    keys        : &PetKey,
+    client      : &Client,
+    authorities : &[[u8;32]],
+    consensus   : &ConsensusSettings,
+    spec        : Option<&crate::chainspec::ChainSpec>)-> Result<Block, QanError>{
+    let spec_genesis = match spec {
+        Some(s) => Some(s.genesis()?),
+        None => None
+    };
     #[cfg(feature = "quantum")]
     let mut txdb = DB::open_default("qtx.db").map_err(|e|QanError::Database(e))?;
     #[cfg(feature = "quantum")]
@@ -34,6 +46,10 @@ pub fn genesis_getter(
     let mut txdb = DB::open_default("tx.db").map_err(|e|QanError::Database(e))?;
     #[cfg(not(feature = "quantum"))]
     let mut blockdb = DB::open_default("db.db").map_err(|e|QanError::Database(e))?;
+    #[cfg(feature = "quantum")]
+    let mut accounts = DB::open_default("qaccounts.db").map_err(|e|QanError::Database(e))?;
+    #[cfg(not(feature = "quantum"))]
+    let mut accounts = DB::open_default("accounts.db").map_err(|e|QanError::Database(e))?;
     let head = match blockdb.get("block0"){
         Ok(Some(n)) => {
             info!("found zero block hash in db");
@@ -47,7 +63,12 @@ pub fn genesis_getter(
             }
         },
         Ok(None) => {
-            let head = if std::path::Path::new("genesis").exists(){
+            let head = if let Some((b, t)) = &spec_genesis {
+                info!("built genesis block from the chain spec");
+                txdb.put(t.hash()?, serde_json::to_vec(t).map_err(|e|QanError::Serde(e))?).map_err(|e|QanError::Database(e))?;
+                txdb.flush().map_err(|e|QanError::Database(e))?;
+                b.clone()
+            }else if std::path::Path::new("genesis").exists(){
                 info!("no zero block in db, but there is a file");
                 let mut nemezis = File::open(Path::new("genesis")).expect("I have a genesis block but also have filesystem problems");
                 let mut nemezis_buffer = Vec::new();
@@ -60,7 +81,7 @@ pub fn genesis_getter(
                         serde_json::from_slice(&n.payload).map_err(|e|QanError::Serde(e))?
                     }Err(_) => {
                         info!("had to make a genesis block");
-                        let (b, t) = crate::nemezis::generate_nemezis_block(&keys)?;
+                        let (b, t) = crate::nemezis::generate_nemezis_block(&keys, authorities, consensus)?;
                         let tx = serde_json::to_vec(&t).map_err(|e|QanError::Serde(e))?;
                         txdb.put(t.hash()?, tx).map_err(|e|QanError::Database(e))?;
                         txdb.flush().map_err(|e|QanError::Database(e))?;
@@ -75,15 +96,59 @@ pub fn genesis_getter(
         },
         Err(e) => panic!(e)
     };
+    if let Some((b, _)) = &spec_genesis {
+        if b.hash() != head.hash() {
+            return Err(QanError::Internal(format!("the database holds genesis {} but the chain spec defines {}", hex::encode(head.hash()), hex::encode(b.hash()))))
+        }
+    }
+    if AuthoritySet::load(&blockdb)?.is_none() {
+        let nemezis_txh = match head.body.txes.get(0){
+            Some(h) => *h,
+            None => return Err(QanError::Internal("genesis block has no transaction".to_string()))
+        };
+        let nemezis_tx : Transaction = match txdb.get(&nemezis_txh).map_err(|e|QanError::Database(e))?{
+            Some(t) => serde_json::from_slice(&t).map_err(|e|QanError::Serde(e))?,
+            None => {
+                info!("asking for the genesis transaction");
+                let req_tx = client.request("Synchronize", 
+                    &serde_json::to_vec(&SyncType::TransactionAtHash(nemezis_txh)).map_err(|e|QanError::Serde(e))?,
+                    std::time::Duration::new(8,0)).map_err(|e|QanError::Nats(e))?.payload;
+                let t : Transaction = serde_json::from_slice(&req_tx).map_err(|e|QanError::Serde(e))?;
+                if t.hash()? != nemezis_txh { return Err(QanError::Internal("received genesis transaction does not match genesis block".to_string())) }
+                txdb.put(&nemezis_txh, req_tx).map_err(|e|QanError::Database(e))?;
+                txdb.flush().map_err(|e|QanError::Database(e))?;
+                t
+            }
+        };
+        let authorities = AuthoritySet::from_nemezis(&nemezis_tx)?;
+        info!("authority set of genesis: {:?}", authorities.members().iter().map(|a|hex::encode(a)).collect::<Vec<String>>());
+        authorities.store(&blockdb)?;
+        crate::authority::AuthorityHistory::genesis(authorities, head.hash()).store(&blockdb)?;
+        let nemezis_data : crate::nemezis::NemezisData = serde_json::from_slice(&nemezis_tx.get_data()).map_err(|e|QanError::Serde(e))?;
+        info!("consensus settings of genesis: {:?}", nemezis_data.consensus);
+        crate::governance::ConsensusSchedule::genesis(nemezis_data.consensus, head.hash()).store(&blockdb)?;
+        info!("chain id of genesis: {:?}", nemezis_data.chain_id);
+        if nemezis_data.chain_id.is_empty() {
+            return Err(QanError::Internal("genesis defines no chain id".to_string()))
+        }
+        crate::chainspec::Domain::store_chain_id(&blockdb, &nemezis_data.chain_id)?;
+        crate::address::store_hrp(&blockdb, &crate::address::hrp_of(&nemezis_data.chain_id, &nemezis_data.address_prefix)?)?;
+        crate::codec::store_version(&blockdb)?;
+        if crate::state::store_genesis(&accounts, &nemezis_data.balances)? != head.signed.header.state_root {
+            return Err(QanError::Internal("genesis balances do not match the state root of the genesis block".to_string()))
+        }
+    }
+    crate::address::set_hrp(&crate::address::load_hrp(&blockdb)?);
+    info!("address prefix of the chain: {}", crate::address::hrp());
     Ok(head)
 }
 
 /// Synchronization logic implementation. It runs from current locally available height to network-wise available greatest height.  
-/// The parameters are a nats client reference, an u64 in case you only want to syncronize to a certain depth.
+/// The parameters are a nats client reference, an u64 in case you only want to syncronize to a certain depth, and whether blocks are checked against the proposer slots, which they aren't if blocks are ordered by raft.
 /// The last parameter is the latest locally available block, or whichever we want to use as a base to start syncing.
-/// Every block is checked and we ask for every transaction through the network. In case a public key is missing we also ask for that.
+/// Every block is checked, has to be proposed by a member of the authority set active at its height, and we ask for every transaction through the network. In case a public key is missing we also ask for that.
 /// If we cannot get every transaction or block, or they are corrupted, after 10 errors the code exits.
-pub fn sync(client : &Client, spv : u64, head : &mut Block) -> Result<u64, QanError>{
+pub fn sync(client : &Client, spv : u64, check_slots : bool, head : &mut Block) -> Result<u64, QanError>{
     #[cfg(feature = "quantum")]
     let mut txdb = DB::open_default("qtx.db").map_err(|e|QanError::Database(e))?;
     #[cfg(feature = "quantum")]
@@ -96,6 +161,15 @@ pub fn sync(client : &Client, spv : u64, head : &mut Block) -> Result<u64, QanEr
     let mut blockdb = DB::open_default("db.db").map_err(|e|QanError::Database(e))?;
     #[cfg(not(feature = "quantum"))]
     let mut pubkeys = DB::open_default("pubkeys.db").map_err(|e|QanError::Database(e))?;
+    #[cfg(feature = "quantum")]
+    let mut accounts = DB::open_default("qaccounts.db").map_err(|e|QanError::Database(e))?;
+    #[cfg(not(feature = "quantum"))]
+    let mut accounts = DB::open_default("accounts.db").map_err(|e|QanError::Database(e))?;
+    #[cfg(feature = "quantum")]
+    let mut receipts = DB::open_default("qreceipts.db").map_err(|e|QanError::Database(e))?;
+    #[cfg(not(feature = "quantum"))]
+    let mut receipts = DB::open_default("receipts.db").map_err(|e|QanError::Database(e))?;
+    let domain = crate::chainspec::Domain::load(&blockdb)?;
     let mut block_height : u64 = match blockdb.get("height"){
         Ok(Some(h))=>String::from_utf8_lossy(&h).parse::<u64>().expect("cannot parse my stored chain height before sync"),
         Ok(None)=>{blockdb.put("height",0.to_string()).map_err(|e|QanError::Database(e))?; 0},
@@ -138,8 +212,13 @@ pub fn sync(client : &Client, spv : u64, head : &mut Block) -> Result<u64, QanEr
                         };
                         // println!("got blockdata");
                     let block : Block = serde_json::from_slice(&req_block).map_err(|e|QanError::Serde(e))?;
-                    // println!("asking for pubkey : {:?}", &block.proposer_pub);
-                    let pubkey = match pubkeys.get(&block.proposer_pub).map_err(|e|QanError::Database(e))?{
+                    let authorities = crate::authority::set_at(&blockdb, block.height())?;
+                    if !authorities.contains(&block.proposer()) {
+                        error!("block {} at height {} was proposed by non-authority {}", hex::encode(block.hash()), block.height(), hex::encode(block.proposer()));
+                        error_count+=1;continue'blockloop
+                    }
+                    // println!("asking for pubkey : {:?}", &block.proposer());
+                    let pubkey = match pubkeys.get(&block.proposer()).map_err(|e|QanError::Database(e))?{
                         Some(pk) => {
                             // println!("got pubkey for block");
                             #[cfg(feature = "quantum")]
@@ -149,7 +228,7 @@ pub fn sync(client : &Client, spv : u64, head : &mut Block) -> Result<u64, QanEr
                             pk
                         }, None => {
                             // println!("dont got pubkey for block");
-                            let pubkey_vec : Vec<u8> = match client.request("PubKey", &block.proposer_pub, std::time::Duration::new(8,0)){
+                            let pubkey_vec : Vec<u8> = match client.request("PubKey", &block.proposer(), std::time::Duration::new(8,0)){
                                 Ok(pk) => pk.payload,
                                 Err(_) => {error_count+=1;println!("blockpubkey");continue'blockloop}
                             };
@@ -157,65 +236,76 @@ pub fn sync(client : &Client, spv : u64, head : &mut Block) -> Result<u64, QanEr
                             let pubkey = GlpPk::from_bytes(&pubkey_vec);
                             #[cfg(not(feature = "quantum"))]
                             let pubkey = PublicKey::from_bytes(&pubkey_vec).unwrap();
-                            pubkeys.put(&block.proposer_pub ,pubkey_vec).map_err(|e|QanError::Database(e))?;
+                            pubkeys.put(&block.proposer() ,pubkey_vec).map_err(|e|QanError::Database(e))?;
                             pubkey
                         }
                     };
-                    if !block.verify(&pubkey)? {
+                    if !block.verify(&pubkey, &domain)? {
                         panic!("found cryptographically invalid transaction in chain");
                     }
-                    if block.height == block_height {
+                    if !block.check_body() {
+                        error!("body of block {} doesn't match its header", hex::encode(block.hash()));
+                        error_count+=1;continue'blockloop
+                    }
+                    if block.height() == block_height {
                         if block.prev_hash() != head.hash() { 
                             println!("{} ||| {}", hex::encode(block.prev_hash()), hex::encode(head.hash()));
-                            error_count+=1;println!("{} ||| {}",block.height , block_height);
+                            error_count+=1;println!("{} ||| {}",block.height() , block_height);
                             continue'blockloop }
+                        if check_slots {
+                            let slot_duration = crate::governance::settings_at(&blockdb, block.height())?.slot_duration();
+                            if !crate::slot::is_proposer(&authorities, slot_duration, block.height(), head.timestamp(), block.timestamp(), &block.proposer()) {
+                                error!("block {} was proposed outside of its proposer's slot", hex::encode(block.hash()));
+                                error_count+=1;continue'blockloop }
+                        }
                     }else {
-                        error!("{} ||| {}", block.height, block_height);
+                        error!("{} ||| {}", block.height(), block_height);
                         error_count+=1;continue'blockloop
                     }
-                    'txloop:for txh in &block.hashedblock.blockdata.txes{
-                        match txdb.get_pinned(&txh) {
+                    let consensus_settings = crate::governance::settings_at(&blockdb, block.height())?;
+                    if !consensus_settings.within_capacity(block.body.txes.len(), 0) {
+                        error!("block {} holds too many transactions", hex::encode(block.hash()));
+                        error_count+=1;continue'blockloop
+                    }
+                    let mut bytes = 0;
+                    let mut fetched = Vec::new();
+                    'txloop:for txh in &block.body.txes{
+                        match txdb.get(&txh) {
                             Err(_)      =>{panic!("db failure")}
-                            Ok(Some(_)) =>{ continue }
+                            Ok(Some(t)) =>{ bytes += serde_json::from_slice::<Transaction>(&t).map_err(|e|QanError::Serde(e))?.len(); continue }
                             Ok(None)    =>{
                                 let req_tx = client.request("Synchronize", 
                                     &serde_json::to_vec(&SyncType::TransactionAtHash(*txh)).map_err(|e|QanError::Serde(e))? ,std::time::Duration::new(8,0))
                                         .expect(&format!("sync failed at getting txh: {}", hex::encode(&txh))).payload;
                                 match serde_json::from_slice::<Transaction>(&req_tx){
                                     Ok(tx) => {
-                                        let pubkey = match pubkeys.get(&tx.pubkey).map_err(|e|QanError::Database(e))?{
-                                            Some(pk) => {
-                                                // println!("got pubkey");
-                                                #[cfg(feature = "quantum")]
-                                                let pk = GlpPk::from_bytes(&pk);
-                                                #[cfg(not(feature = "quantum"))]
-                                                let pk = PublicKey::from_bytes(&pk).unwrap();
-                                                pk
-                                            }, None => {
-                                                let pubkey_vec : Vec<u8> = match client.request("PubKey", &tx.pubkey, std::time::Duration::new(8,0)){
-                                                    Ok(pk) => pk.payload,
-                                                    Err(_) => { error_count+=1;println!("txpubkey");continue'blockloop }
-                                                };
-                                                // println!("didnt have pubkey but someone gave it to me");
-                                                #[cfg(feature = "quantum")]
-                                                let pubkey = GlpPk::from_bytes(&pubkey_vec);
-                                                #[cfg(not(feature = "quantum"))]
-                                                let pubkey = PublicKey::from_bytes(&pubkey_vec).unwrap();
-                                                pubkeys.put(&tx.pubkey ,pubkey_vec).map_err(|e|QanError::Database(e))?;
-                                                pubkey
-                                            }
-                                        };
-                                        if tx.verify(&pubkey)?{
-                                            txdb.put(&txh, req_tx).map_err(|e|QanError::Database(e))?;
-                                        }else{
-                                            panic!("found cryptographically invalid transaction in chain");
+                                        if get_pubkey(&client, &pubkeys, &tx.pubkey)?.is_none() {
+                                            error_count+=1;println!("txpubkey");continue'blockloop
                                         }
+                                        fetched.push((*txh, tx, req_tx));
                                     }, Err(e) => panic!("")
                                 } 
                             }
                         }
                     }
-                    blockdb.put("block".to_owned()+&block_height.to_string(), block.hash()).map_err(|e|QanError::Database(e))?;
+                    //the fetched transactions are verified together on the worker pool
+                    let txes : Vec<&Transaction> = fetched.iter().map(|(_, tx, _)|tx).collect();
+                    if let Some(i) = crate::sigverify::first_invalid(&pubkeys, &txes, &domain)? {
+                        error!("transaction {} of block {} has an invalid signature", hex::encode(fetched[i].0), hex::encode(block.hash()));
+                        error_count+=1;continue'blockloop
+                    }
+                    for (txh, tx, req_tx) in fetched {
+                        bytes += tx.len();
+                        txdb.put(&txh, req_tx).map_err(|e|QanError::Database(e))?;
+                    }
+                    if !consensus_settings.within_capacity(block.body.txes.len(), bytes) {
+                        error!("block {} is over capacity", hex::encode(block.hash()));
+                        error_count+=1;continue'blockloop
+                    }
+                    if let Err(e) = crate::fork::index_block(&blockdb, &txdb, &accounts, &receipts, &block) {
+                        error!("couldn't apply block {}: {}", hex::encode(block.hash()), e);
+                        error_count+=1;continue'blockloop
+                    }
                     blockdb.put(&block_hash, req_block).map_err(|e|QanError::Database(e))?;
                     *head = block;
                 }
@@ -229,4 +319,39 @@ pub fn sync(client : &Client, spv : u64, head : &mut Block) -> Result<u64, QanEr
         info!("{}",block_height);
     }
     Ok(block_height)
-}
\ No newline at end of file
+}
+
+/// Looks up the public key belonging to the hash `id` in `pubkeys`, or asks the network for it and stores it.
+/// Returns `None` if nobody answered.
+pub fn get_pubkey(client : &Client, pubkeys : &DB, id : &[u8;32]) -> Result<Option<Vec<u8>>, QanError>{
+    match pubkeys.get(id).map_err(|e|QanError::Database(e))?{
+        Some(pk) => Ok(Some(pk)),
+        None => {
+            let pk = match client.request("PubKey", id, std::time::Duration::new(4,0)){
+                Ok(pk) => pk.payload,
+                Err(_) => return Ok(None)
+            };
+            if &do_hash(&pk) != id { return Ok(None) }
+            pubkeys.put(id, &pk).map_err(|e|QanError::Database(e))?;
+            Ok(Some(pk))
+        }
+    }
+}
+
+/// Asks the network for the transaction at `txh`, and makes sure its sender's public key is in `pubkeys`, asking for it on the network if needed.
+/// The signature is not checked, the fetched transactions of a block are verified together with `sigverify::first_invalid`.
+/// Returns `None` if the transaction or the key couldn't be obtained.
+pub fn fetch_transaction(client : &Client, pubkeys : &DB, txh : &[u8;32]) -> Result<Option<(Transaction, Vec<u8>)>, QanError>{
+    let req_tx = match client.request("Synchronize", 
+        &serde_json::to_vec(&SyncType::TransactionAtHash(*txh)).map_err(|e|QanError::Serde(e))?, std::time::Duration::new(4,0)){
+            Ok(t) => t.payload,
+            Err(e) => { error!("couldn't get tx {}: {}", hex::encode(txh), e); return Ok(None) }
+    };
+    let tx : Transaction = match serde_json::from_slice(&req_tx){
+        Ok(t) => t,
+        Err(_) => return Ok(None)
+    };
+    if &tx.hash()? != txh { return Ok(None) }
+    if get_pubkey(client, pubkeys, &tx.pubkey)?.is_none() { return Ok(None) }
+    Ok(Some((tx, req_tx)))
+}
@@ -2,15 +2,17 @@ use std::fmt;
 // use rmps::{Serializer, Deserializer};
 #[cfg(not(feature = "quantum"))]
 use ed25519_dalek::{Keypair, PublicKey, Signature};
-use rand::RngCore;
-use rand::rngs::OsRng;
 use crate::util::do_hash;
 use crate::error::QanError;
+use crate::chainspec::Domain;
 use hex::encode;
 #[cfg(feature = "quantum")]
 use glp::glp::{GlpSig, GlpSk, GlpPk, sign, verify, gen_pk};
 use jsonrpc_http_server::jsonrpc_core::Value;
 
+/// Recipient of contract deployments, the data of a transaction sent to it is the wasm code of a new contract.
+pub const DEPLOY_ACCOUNT : [u8;32] = [0xfe;32];
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct VmCall{
     pub prev_root: [u8; 32],
@@ -33,34 +35,61 @@ pub struct TxBody{
     pub timestamp: u64,         //size: 8     byte
     pub recipient: [u8; 32],    //size: 32    byte
     pub balance  : u64,         //size: 8     byte
+    #[serde(default)]
+    pub spice_price : u64,      //size: 8     byte
+    #[serde(default)]
+    pub spice_limit : u64,      //size: 8     byte
     pub data: Vec<u8>
 }
 
 impl fmt::Display for TxBody {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "\"nonce\":{},\n\"timestamp\":{},\n\"recipient\":{},\n\"balance\":{},\n\"data\":{:?}",
-        self.nonce, self.timestamp, encode(self.recipient), self.balance, self.data)
+        write!(f, "\"nonce\":{},\n\"timestamp\":{},\n\"recipient\":{},\n\"balance\":{},\n\"spice_price\":{},\n\"spice_limit\":{},\n\"data\":{:?}",
+        self.nonce, self.timestamp, encode(self.recipient), self.balance, self.spice_price, self.spice_limit, self.data)
     }
 }
 
 impl TxBody{
-    ///Constructor function for TxBody. Takes recipient address and data. Also includes a random nince and the timestamp of creation. In this edition the balance is 0.
-    pub fn new(recipient: [u8; 32], balance: u64 ,data: Vec<u8>) -> TxBody {
-        TxBody {
+    ///Constructor function for TxBody. Takes recipient address, the sender's next nonce, the transferred amount and data. Also includes the timestamp of creation.
+    ///The spice limit is the spice the transaction consumes, at a price of 0.
+    pub fn new(recipient: [u8; 32], nonce: u64, balance: u64 ,data: Vec<u8>) -> TxBody {
+        let mut body = TxBody {
             recipient: recipient,
-            nonce: OsRng.next_u64(),  
+            nonce: nonce,
             timestamp: crate::util::timestamp(),
             balance: balance,
+            spice_price: 0,
+            spice_limit: 0,
             data: data,
-        }
+        };
+        body.spice_limit = crate::spice::consumed(&body);
+        body
+    }
+
+    /// Sets the price paid per unit of spice.
+    pub fn with_spice_price(mut self, spice_price: u64) -> TxBody {
+        self.spice_price = spice_price;
+        self
     }
 
     pub fn hash(&self) -> Result<[u8;32],QanError>{
-        Ok(do_hash(&serde_json::to_vec(&self).map_err(|e|QanError::Serde(e))?))
+        Ok(do_hash(&crate::codec::encode(&self).map_err(|e|QanError::Codec(e))?))
     }
 
+    /// Size of the body: the fixed width fields, spice price and limit included, and the data.
     pub fn len(&self) -> usize{
-        56+self.data.len()
+        8+8+32+8+8+8+self.data.len()
+    }
+
+    /// Address of the contract the transaction deploys. Data sent to `DEPLOY_ACCOUNT` is the code of a new contract,
+    /// deployed at the hash of the code.
+    pub fn deployed_contract(&self) -> Option<[u8;32]> {
+        if self.recipient == DEPLOY_ACCOUNT && !self.data.is_empty() { Some(do_hash(&self.data)) } else { None }
+    }
+
+    /// The contract call the data holds. Calls are sent to the contract they call, other data isn't executed.
+    pub fn vm_call(&self) -> Option<VmCall> {
+        serde_json::from_slice::<VmCall>(&self.data).ok().filter(|c|c.sc_hash == self.recipient)
     }
 }
 
@@ -80,35 +109,38 @@ impl fmt::Display for Transaction {
 }
 
 impl Transaction{
-    /// New function in case `quantum` feature flag is not used. Takes ed25519_dalek Keypair to sign the transaction.
+    /// New function in case `quantum` feature flag is not used. Takes ed25519_dalek Keypair to sign the transaction in the chain's signing domain.
     #[cfg(not(feature = "quantum"))]
-    pub fn new( transaction: TxBody, kp: &Keypair ) -> Result<Self, QanError> {
-        let sig = kp.sign(&serde_json::to_vec(&transaction).map_err(|e|QanError::Serde(e))?);
+    pub fn new( transaction: TxBody, kp: &Keypair, domain: &Domain ) -> Result<Self, QanError> {
+        let sig = kp.sign(&domain.payload(&crate::codec::encode(&transaction).map_err(|e|QanError::Codec(e))?));
         Ok(Transaction { transaction , pubkey: do_hash(&kp.public.to_bytes().to_vec()), sig: sig.to_bytes().to_vec() })
     }
 
-    /// Verification method for transactions. Takes ed25519_dalek public key to use in verifiaction.
+    /// Verification method for transactions. Takes ed25519_dalek public key to use in verifiaction, and the signing domain of the chain.
     #[cfg(not(feature = "quantum"))]
-    pub fn verify(&self, pubkey : &PublicKey) -> Result<bool, QanError>{
-        let sig = Signature::from_bytes(&self.sig).unwrap();
-        Ok(match pubkey.verify(&serde_json::to_vec(&self.transaction).map_err(|e|QanError::Serde(e))?, &sig){
+    pub fn verify(&self, pubkey : &PublicKey, domain : &Domain) -> Result<bool, QanError>{
+        let sig = match Signature::from_bytes(&self.sig){
+            Ok(s) => s,
+            Err(_) => return Ok(false)
+        };
+        Ok(match pubkey.verify(&domain.payload(&crate::codec::encode(&self.transaction).map_err(|e|QanError::Codec(e))?), &sig){
             Ok(_)=>true,
             Err(_)=>false
         })
     }
     
-    /// New function in case `quantum` feature flag is used. Takes quantum secret key to sign the transaction.
+    /// New function in case `quantum` feature flag is used. Takes quantum secret key to sign the transaction in the chain's signing domain.
     #[cfg(feature = "quantum")]
-    pub fn new( transaction: TxBody, sk: &GlpSk ) -> Result<Self, QanError> {
-        let sig = sign(&sk, serde_json::to_vec(&transaction).map_err(|e|QanError::Serde(e))?).unwrap();
+    pub fn new( transaction: TxBody, sk: &GlpSk, domain: &Domain ) -> Result<Self, QanError> {
+        let sig = sign(&sk, domain.payload(&crate::codec::encode(&transaction).map_err(|e|QanError::Codec(e))?)).unwrap();
         Ok(Transaction { transaction , pubkey: do_hash(&gen_pk(&sk).to_bytes()), sig: sig.to_bytes() })
     }
 
-    /// Verification method for transactions. Takes quantum public key to use in verifiaction.
+    /// Verification method for transactions. Takes quantum public key to use in verifiaction, and the signing domain of the chain.
     #[cfg(feature = "quantum")]
-    pub fn verify(&self, pubkey : &GlpPk) -> Result<bool, QanError>{
+    pub fn verify(&self, pubkey : &GlpPk, domain : &Domain) -> Result<bool, QanError>{
         let qsig = GlpSig::from_bytes(&self.sig);
-        Ok(verify(&pubkey,&qsig,&serde_json::to_vec(&self.transaction).map_err(|e|QanError::Serde(e))?))
+        Ok(verify(&pubkey,&qsig,&domain.payload(&crate::codec::encode(&self.transaction).map_err(|e|QanError::Codec(e))?)))
     }
 
     pub fn get_data(&self) -> Vec<u8>{
@@ -116,7 +148,7 @@ impl Transaction{
     }
 
     pub fn hash(&self) -> Result<[u8;32], QanError>{
-        Ok(do_hash(&serde_json::to_vec(&self).map_err(|e|QanError::Serde(e))?))
+        Ok(do_hash(&crate::codec::encode(&self).map_err(|e|QanError::Codec(e))?))
     }
 
     pub fn len(&self) -> usize{
@@ -1,12 +1,14 @@
 use std::{
     thread, 
     io::{stdin, BufRead},
+    sync::{Arc, atomic::{AtomicU64, Ordering}},
 };
 use natsclient::{Client, ClientOptions};
 use crate::{
     event::Event,
     block::Block,
     error::QanError,
+    address::Address,
     transaction::Transaction
 };
 
@@ -33,6 +35,24 @@ pub fn start_client(opts: ClientOptions, sndr : &std::sync::mpsc::SyncSender<Eve
         Ok(())
     }).map_err(|e|QanError::Nats(e))?;
 
+    let votesndr = sndr.clone();
+    client.subscribe(crate::finality::VOTE_SUBJECT, move |msg| {
+        votesndr.send(Event::Vote(msg.payload.to_owned()));
+        Ok(())
+    }).map_err(|e|QanError::Nats(e))?;
+
+    let evsndr = sndr.clone();
+    client.subscribe(crate::evidence::EVIDENCE_SUBJECT, move |msg| {
+        evsndr.send(Event::Evidence(msg.payload.to_owned()));
+        Ok(())
+    }).map_err(|e|QanError::Nats(e))?;
+
+    let raftsndr = sndr.clone();
+    client.subscribe(crate::raft::RAFT_SUBJECT, move |msg| {
+        raftsndr.send(Event::Raft(msg.payload.to_owned()));
+        Ok(())
+    }).map_err(|e|QanError::Nats(e))?;
+
     let syncsndr = sndr.clone();
     client.subscribe("Synchronize", move |msg| {
         let rep = msg.reply_to.clone().unwrap();
@@ -52,7 +72,34 @@ pub fn start_stdin_handler(tsndr : &std::sync::mpsc::SyncSender<Event>){
         loop{
             let mut buffer = String::new();
             handle.read_line(&mut buffer);
+            if buffer.starts_with("transfer ") {
+                match parse_transfer(&buffer) {
+                    Ok((to, amount)) => { tsndr.send(Event::Transfer(to.0, amount)); },
+                    Err(e) => println!("{}", e),
+                }
+                continue
+            }
             tsndr.send(Event::Chat(buffer.as_bytes().to_vec()));
         }
     });
-}
\ No newline at end of file
+}
+
+/// Parses a `transfer <address> <amount>` terminal command.
+fn parse_transfer(line : &str) -> Result<(Address, u64), QanError> {
+    let words : Vec<&str> = line.split_whitespace().collect();
+    if words.len() != 3 { return Err(QanError::Internal("usage: transfer <address> <amount>".to_string())) }
+    let amount = words[2].parse().map_err(|_|QanError::Internal(format!("invalid amount: {}", words[2])))?;
+    Ok((words[1].parse()?, amount))
+}
+
+/// Starter function for the slot clock. Wakes the main loop up regularly so a proposer can act in its slot even without incoming messages.
+/// The main loop keeps `slot_duration` at the active setting, and the ticker re-reads it on every tick, so scheduled changes take effect.
+pub fn start_slot_ticker(tsndr : &std::sync::mpsc::SyncSender<Event>, slot_duration : Arc<AtomicU64>){
+    let tsndr = tsndr.clone();
+    thread::spawn( move ||{
+        loop{
+            thread::sleep(std::time::Duration::from_millis((slot_duration.load(Ordering::Relaxed)/4).max(1)));
+            if tsndr.send(Event::Tick).is_err() { break }
+        }
+    });
+}
@@ -0,0 +1,107 @@
+//! Stateless pre-validation of incoming transactions. These checks only look at the encoded transaction and the clock,
+//! and run before the public key lookup and the signature verification, so malformed transactions are dropped cheaply.
+//! Checks needing the chain state, like nonces, balances and spice limits, are done at mempool admission.
+use std::fmt;
+use crate::transaction::{Transaction, DEPLOY_ACCOUNT};
+use crate::governance::{SYSTEM_ACCOUNT, SystemCall};
+
+/// Maximum size of an encoded transaction.
+pub const MAX_TX_BYTES : usize = 256 * 1024;
+/// Maximum length of the data of a transaction.
+pub const MAX_DATA_BYTES : usize = 32 * 1024;
+/// Milliseconds a transaction's timestamp may be ahead of the local clock.
+pub const MAX_FUTURE_SKEW : u64 = 60 * 1000;
+/// Length of an ed25519 signature.
+#[cfg(not(feature = "quantum"))]
+pub const SIG_BYTES : usize = 64;
+/// Maximum length of a GLP signature.
+#[cfg(feature = "quantum")]
+pub const MAX_SIG_BYTES : usize = 16 * 1024;
+
+/// Reason a transaction failed pre-validation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Invalid {
+    /// Encoded size in bytes.
+    TooLarge(usize),
+    Undecodable(String),
+    /// Length of the data in bytes.
+    DataTooLong(usize),
+    /// Length of the signature in bytes.
+    SignatureLength(usize),
+    /// Milliseconds the timestamp is ahead of the local clock.
+    FutureTimestamp(u64),
+    Recipient(&'static str),
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Invalid::TooLarge(n) => write!(f, "encoded transaction of {} bytes is over {} bytes", n, MAX_TX_BYTES),
+            Invalid::Undecodable(e) => write!(f, "undecodable transaction: {}", e),
+            Invalid::DataTooLong(n) => write!(f, "data of {} bytes is over {} bytes", n, MAX_DATA_BYTES),
+            Invalid::SignatureLength(n) => write!(f, "signature of {} bytes is invalid for the signature scheme", n),
+            Invalid::FutureTimestamp(ms) => write!(f, "timestamp is {} ms ahead of the local clock", ms),
+            Invalid::Recipient(reason) => write!(f, "invalid recipient: {}", reason),
+        }
+    }
+}
+
+#[cfg(not(feature = "quantum"))]
+fn valid_sig_len(len : usize) -> bool {
+    len == SIG_BYTES
+}
+
+#[cfg(feature = "quantum")]
+fn valid_sig_len(len : usize) -> bool {
+    len > 0 && len <= MAX_SIG_BYTES
+}
+
+/// Checks a decoded transaction at local time `now`.
+/// The zero address, the deploy account and the system account don't take transfers, and the data sent to the system account has to be a `SystemCall`.
+pub fn check(tx : &Transaction, now : u64) -> Result<(), Invalid> {
+    if tx.transaction.data.len() > MAX_DATA_BYTES { return Err(Invalid::DataTooLong(tx.transaction.data.len())) }
+    if !valid_sig_len(tx.sig.len()) { return Err(Invalid::SignatureLength(tx.sig.len())) }
+    if tx.transaction.timestamp > now.saturating_add(MAX_FUTURE_SKEW) {
+        return Err(Invalid::FutureTimestamp(tx.transaction.timestamp - now))
+    }
+    let recipient = tx.transaction.recipient;
+    if recipient == [0;32] && tx.transaction.balance > 0 { return Err(Invalid::Recipient("transfer to the zero address")) }
+    if recipient == DEPLOY_ACCOUNT && tx.transaction.balance > 0 { return Err(Invalid::Recipient("transfer to the deploy account")) }
+    if recipient == SYSTEM_ACCOUNT {
+        if tx.transaction.balance > 0 { return Err(Invalid::Recipient("transfer to the system account")) }
+        if SystemCall::from_transaction(tx).is_none() { return Err(Invalid::Recipient("system account data is not a system call")) }
+    }
+    Ok(())
+}
+
+/// Decodes and checks an encoded transaction at local time `now`, the size is checked before decoding.
+pub fn pre_validate(raw : &[u8], now : u64) -> Result<Transaction, Invalid> {
+    if raw.len() > MAX_TX_BYTES { return Err(Invalid::TooLarge(raw.len())) }
+    let tx : Transaction = serde_json::from_slice(raw).map_err(|e|Invalid::Undecodable(e.to_string()))?;
+    check(&tx, now)?;
+    Ok(tx)
+}
+
+#[cfg(not(feature = "quantum"))]
+#[test]
+fn rejects_before_crypto() {
+    let kp = ed25519_dalek::Keypair::generate(&mut rand::rngs::OsRng);
+    let domain = crate::chainspec::Domain::new("test");
+    let now = crate::util::timestamp();
+    let encode = |body : crate::transaction::TxBody| serde_json::to_vec(&Transaction::new(body, &kp, &domain).unwrap()).unwrap();
+    let body = |recipient, balance, data : Vec<u8>| crate::transaction::TxBody::new(recipient, 0, balance, data);
+    assert!(pre_validate(&encode(body([1;32], 5, vec![])), now).is_ok());
+    assert!(pre_validate(&encode(body([0;32], 0, b"hi".to_vec())), now).is_ok());
+    assert_eq!(pre_validate(&vec![b' '; MAX_TX_BYTES + 1], now).err(), Some(Invalid::TooLarge(MAX_TX_BYTES + 1)));
+    assert!(matches!(pre_validate(b"{\"transaction\":1}", now), Err(Invalid::Undecodable(_))));
+    assert_eq!(pre_validate(&encode(body([1;32], 0, vec![0; MAX_DATA_BYTES + 1])), now).err(), Some(Invalid::DataTooLong(MAX_DATA_BYTES + 1)));
+    let mut future = body([1;32], 0, vec![]);
+    future.timestamp = now + MAX_FUTURE_SKEW + 1;
+    assert_eq!(pre_validate(&encode(future), now).err(), Some(Invalid::FutureTimestamp(MAX_FUTURE_SKEW + 1)));
+    assert!(matches!(pre_validate(&encode(body([0;32], 5, vec![])), now), Err(Invalid::Recipient(_))));
+    assert!(matches!(pre_validate(&encode(body(DEPLOY_ACCOUNT, 5, vec![0])), now), Err(Invalid::Recipient(_))));
+    assert!(matches!(pre_validate(&encode(body(SYSTEM_ACCOUNT, 0, b"{}".to_vec())), now), Err(Invalid::Recipient(_))));
+    let mut tx = Transaction::new(body([1;32], 0, vec![]), &kp, &domain).unwrap();
+    tx.sig.pop();
+    assert_eq!(check(&tx, now).err(), Some(Invalid::SignatureLength(SIG_BYTES - 1)));
+}
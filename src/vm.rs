@@ -8,9 +8,156 @@ use rmps::{Serializer, Deserializer};
 use serde::{Serialize, Deserialize};
 use jsonrpc_http_server::jsonrpc_core::Value;
 
-use std::convert::TryInto;
 use wasmi::{ImportsBuilder, Module, ModuleInstance, NopExternals, RuntimeValue, *};
 use crate::watparser;
+use crate::receipt::Log;
+use crate::error::QanError;
+
+/// Index of the `env.log` host function.
+const LOG_INDEX : usize = 0;
+/// Index of the `env.gas` function the metering injects into contracts.
+const GAS_INDEX : usize = 1;
+/// Index of the `env.storage_get` host function.
+const STORAGE_GET_INDEX : usize = 2;
+/// Index of the `env.storage_set` host function.
+const STORAGE_SET_INDEX : usize = 3;
+/// Most topics of a log entry.
+pub const MAX_TOPICS : u32 = 4;
+
+/// Host functions contracts can import from the `env` module.
+/// `log(topics_ptr, topics_count, data_ptr, data_len)` appends an entry to the log of the call,
+/// with `topics_count` topics of 32 bytes each read from `topics_ptr`, and `data_len` bytes of data read from `data_ptr`.
+/// `storage_get(key_ptr, value_ptr)` writes the 32 byte value the contract stored under the 32 byte key at `key_ptr` to `value_ptr`,
+/// zeros if it stored nothing, and `storage_set(key_ptr, value_ptr)` stores the value at `value_ptr` under the key, zeros removing it.
+/// Reads come from the writes of the call first, then from `load`.
+/// Contracts are metered before they run, every block of instructions calls `gas` with its cost, and the call traps once it uses more than `spice_limit`.
+struct Host<'a> {
+	memory		: Option<MemoryRef>,
+	address		: [u8;32],
+	logs		: Vec<Log>,
+	spice		: u64,
+	spice_limit	: u64,
+	storage		: HashMap<[u8;32], [u8;32]>,
+	load		: &'a mut dyn FnMut(&[u8;32]) -> Result<Option<[u8;32]>, QanError>,
+	error		: Option<QanError>,
+}
+
+impl<'a> Host<'a> {
+	fn charge(&mut self, cost : u64) -> Result<(), Trap> {
+		self.spice = self.spice.saturating_add(cost);
+		if self.spice > self.spice_limit { return Err(Trap::new(TrapKind::Unreachable)) }
+		Ok(())
+	}
+
+	fn read(&self, ptr : u32) -> Result<[u8;32], Trap> {
+		let memory = self.memory.as_ref().ok_or(Trap::new(TrapKind::MemoryAccessOutOfBounds))?;
+		let bytes = memory.get(ptr, 32).map_err(|_|Trap::new(TrapKind::MemoryAccessOutOfBounds))?;
+		Ok(crate::util::vec_to_arr(&bytes))
+	}
+}
+
+impl<'a> Externals for Host<'a> {
+	fn invoke_index(&mut self, index: usize, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+		match index {
+			LOG_INDEX => {
+				let topics_ptr : u32 = args.nth_checked(0)?;
+				let topics_count : u32 = args.nth_checked(1)?;
+				let data_ptr : u32 = args.nth_checked(2)?;
+				let data_len : u32 = args.nth_checked(3)?;
+				if topics_count > MAX_TOPICS { return Err(Trap::new(TrapKind::UnexpectedSignature)) }
+				let memory = self.memory.as_ref().ok_or(Trap::new(TrapKind::MemoryAccessOutOfBounds))?;
+				let raw_topics = memory.get(topics_ptr, 32 * topics_count as usize).map_err(|_|Trap::new(TrapKind::MemoryAccessOutOfBounds))?;
+				let data = memory.get(data_ptr, data_len as usize).map_err(|_|Trap::new(TrapKind::MemoryAccessOutOfBounds))?;
+				self.logs.push(Log{
+					address: self.address,
+					topics: raw_topics.chunks(32).map(|t|crate::util::vec_to_arr(&t.to_vec())).collect(),
+					data
+				});
+				Ok(None)
+			},
+			GAS_INDEX => {
+				let cost : u32 = args.nth_checked(0)?;
+				self.charge(cost as u64)?;
+				Ok(None)
+			},
+			STORAGE_GET_INDEX => {
+				self.charge(crate::spice::LOAD_SPICE)?;
+				let key = crate::state::storage_key(&self.address, &self.read(args.nth_checked(0)?)?);
+				let value = match self.storage.get(&key) {
+					Some(v) => *v,
+					None => match (self.load)(&key) {
+						Ok(v) => v.unwrap_or([0;32]),
+						Err(e) => {
+							self.error = Some(e);
+							return Err(Trap::new(TrapKind::Unreachable))
+						}
+					}
+				};
+				let memory = self.memory.as_ref().ok_or(Trap::new(TrapKind::MemoryAccessOutOfBounds))?;
+				memory.set(args.nth_checked(1)?, &value).map_err(|_|Trap::new(TrapKind::MemoryAccessOutOfBounds))?;
+				Ok(None)
+			},
+			STORAGE_SET_INDEX => {
+				self.charge(crate::spice::STORE_SPICE)?;
+				let key = crate::state::storage_key(&self.address, &self.read(args.nth_checked(0)?)?);
+				let value = self.read(args.nth_checked(1)?)?;
+				self.storage.insert(key, value);
+				Ok(None)
+			},
+			_ => Err(Trap::new(TrapKind::Unreachable))
+		}
+	}
+}
+
+struct HostResolver;
+
+impl ModuleImportResolver for HostResolver {
+	fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, Error> {
+		let log = Signature::new(&[ValueType::I32, ValueType::I32, ValueType::I32, ValueType::I32][..], None);
+		let gas = Signature::new(&[ValueType::I32][..], None);
+		let storage = Signature::new(&[ValueType::I32, ValueType::I32][..], None);
+		match field_name {
+			"log" if signature == &log => Ok(FuncInstance::alloc_host(log, LOG_INDEX)),
+			"gas" if signature == &gas => Ok(FuncInstance::alloc_host(gas, GAS_INDEX)),
+			"storage_get" if signature == &storage => Ok(FuncInstance::alloc_host(storage, STORAGE_GET_INDEX)),
+			"storage_set" if signature == &storage => Ok(FuncInstance::alloc_host(storage, STORAGE_SET_INDEX)),
+			_ => Err(Error::Instantiation(format!("host function {} not found", field_name)))
+		}
+	}
+}
+
+/// Spice of a page of memory a contract grows its memory by.
+pub const PAGE_SPICE : u32 = 1000;
+
+/// The contract `code` metered, every instruction costs a unit of spice and every page of grown memory `PAGE_SPICE`.
+/// Contracts importing `env.gas` themselves can't be metered.
+fn metered(code : &[u8]) -> Result<Module, String> {
+	let module = parity_wasm::deserialize_buffer::<parity_wasm::elements::Module>(code).map_err(|e|e.to_string())?;
+	if module.import_section().map_or(false, |s|s.entries().iter().any(|i|i.module() == "env" && i.field() == "gas")) {
+		return Err("contracts can't import env.gas".to_string())
+	}
+	let rules = pwasm_utils::rules::Set::default().with_grow_cost(PAGE_SPICE);
+	let injected = pwasm_utils::inject_gas_counter(module, &rules).map_err(|_|"the contract can't be metered".to_string())?;
+	Module::from_parity_wasm_module(injected).map_err(|e|e.to_string())
+}
+
+/// Outcome of a contract call: its result, if it has one, or the reason it failed, the log entries it emitted,
+/// its storage writes by storage key, and the spice it used. A call running out of spice used all of it.
+#[derive(Debug)]
+pub struct CallOutcome {
+	pub result	: Result<Option<VMReturn>, String>,
+	pub logs	: Vec<Log>,
+	pub storage	: HashMap<[u8;32], [u8;32]>,
+	pub spice	: u64,
+}
+
+/// Address of a contract in its logs. The contract hash if it is hex encoded 32 bytes, its hash otherwise.
+pub fn contract_address(sc_hash : &str) -> [u8;32] {
+	match hex::decode(sc_hash) {
+		Ok(h) if h.len() == 32 => crate::util::vec_to_arr(&h),
+		_ => crate::util::do_hash(&sc_hash.as_bytes().to_vec())
+	}
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Account {
@@ -54,28 +201,16 @@ pub enum VMReturn{
 	F64(f64),
 }
 
-pub fn parse_values(inc : Vec<Value>)->Vec<RuntimeValue>{
+/// Arguments of a contract call. Only integers fitting an `i64` are supported, anything else is an error.
+pub fn parse_values(inc : Vec<Value>)->Result<Vec<RuntimeValue>, String>{
 	let mut ret = Vec::new();
 	for v in inc{
-		match v {
-			Value::Number(n)=>{
-				if n.is_u64(){
-					ret.push(RuntimeValue::I64(n.as_u64().unwrap().try_into().unwrap()));
-					continue
-				}
-				if n.is_i64(){
-					ret.push(RuntimeValue::I64(n.as_i64().unwrap()));
-					continue
-				}
-				// if n.is_f64(){
-				// 	ret.push(RuntimeValue::F64(n.as_f64().unwrap()));
-				// 	continue
-				// }
-			},
-			_=>{},
+		match v.as_i64() {
+			Some(n) => ret.push(RuntimeValue::I64(n)),
+			None => return Err(format!("unsupported call parameter {}", v))
 		}
 	}
-	ret
+	Ok(ret)
 }
 
 impl VMReturn {
@@ -112,7 +247,7 @@ impl VM{
 
 	pub fn add_contract(&mut self, module: Vec<u8>) -> String{
 		let instance = Module::from_buffer(&module).unwrap();
-		let mod_instance = ModuleInstance::new(&instance, &ImportsBuilder::default())
+		let mod_instance = ModuleInstance::new(&instance, &ImportsBuilder::new().with_resolver("env", &HostResolver))
 			.expect("Instantiation failed.")
 			.run_start(&mut NopExternals)
 			.expect("Start function not found.");
@@ -139,12 +274,24 @@ impl VM{
 		
 		let hastring : String = hash.into_iter().collect();
 		self.smart_contracts.insert(hastring.clone(), module);
-		println!("{}", hastring);
+		debug!("added smart contract {}", hastring);
 		hastring
 	}
 
+	/// Adds the contract `module` under `sc_hash`, the way it was deployed on chain.
+	pub fn insert_contract(&mut self, sc_hash : String, module: Vec<u8>) {
+		self.smart_contracts.insert(sc_hash, module);
+	}
+
+	/// Checks that `module` is a contract the VM can meter and instantiate, with only the host functions as imports.
+	pub fn check_module(module: &[u8]) -> Result<(), String> {
+		let instance = metered(module)?;
+		ModuleInstance::new(&instance, &ImportsBuilder::new().with_resolver("env", &HostResolver)).map_err(|e|e.to_string())?;
+		Ok(())
+	}
+
 	pub fn build_from_file(&mut self, loadp : String)->String{
-		println!("Trying to load smart contract from file: {:?}", loadp);
+		debug!("loading smart contract from file {:?}", loadp);
 		let sc = Self::load_file_contract(&loadp);
 		let ret = self.add_contract(sc);
 		ret
@@ -167,34 +314,40 @@ impl VM{
 				let (sc, fun) = if inc[0].is_string() && inc[1].is_string(){
 					(inc[0].as_str().unwrap().to_string(), inc[1].as_str().unwrap().to_string())
 				}else {return None};
-				let mut ret = Vec::new();
-				if inc.len()>2{
-					for v in &inc[2..]{
-						match v {
-							Value::Number(n)=>{
-								if n.is_u64(){
-									ret.push(RuntimeValue::I64(n.as_u64().unwrap().try_into().unwrap()));
-									continue
-								}
-								if n.is_i64(){
-									ret.push(RuntimeValue::I64(n.as_i64().unwrap()));
-									continue
-								}
-								// if n.is_f64(){
-								// 	ret.push(RuntimeValue::F64(n.as_f64().unwrap()));
-								// 	continue
-								// }
-							},
-							_=>{},
-						}
-					}
-				}
+				let ret = parse_values(inc[2..].to_vec()).ok()?;
 				Some((sc,fun,ret))
 			}
 		}
 	}
 
-	pub fn call_fun(&self, sc_hash : String, fun_hash: String, params : Vec<RuntimeValue>) -> VMReturn{
+	/// Calls the function `fun_hash` of the contract `sc_hash`, using at most `spice_limit` spice, on top of the contract storage returned by `load`.
+	/// The log entries and storage writes of a failed call are dropped. Fails if `load` does.
+	pub fn call_fun(&self, sc_hash : String, fun_hash: String, params : Vec<RuntimeValue>, spice_limit : u64,
+		load : &mut dyn FnMut(&[u8;32]) -> Result<Option<[u8;32]>, QanError>) -> Result<CallOutcome, QanError> {
+		let mut host = Host{
+			memory: None,
+			address: contract_address(&sc_hash),
+			logs: Vec::new(),
+			spice: 0,
+			spice_limit,
+			storage: HashMap::new(),
+			load,
+			error: None,
+		};
+		let result = match self.run(&sc_hash, &fun_hash, &params, &mut host) {
+			_ if host.spice > spice_limit => Err("out of spice".to_string()),
+			r => r
+		};
+		if let Some(e) = host.error.take() { return Err(e) }
+		Ok(CallOutcome{
+			logs: if result.is_ok() { host.logs } else { Vec::new() },
+			storage: if result.is_ok() { host.storage } else { HashMap::new() },
+			spice: host.spice.min(spice_limit),
+			result,
+		})
+	}
+
+	fn run(&self, sc_hash : &str, fun_hash: &str, params : &[RuntimeValue], host : &mut Host) -> Result<Option<VMReturn>, String>{
 
 		let account_in =  Account {
 			nonce: 0,
@@ -212,22 +365,25 @@ impl VM{
 			data: Vec::new()
 		};
 	
-		let contract = Module::from_buffer(self.smart_contracts.get(&sc_hash).expect("callfun1")).expect("callfun2");
-		let loaded_module = ModuleInstance::new(&contract, &ImportsBuilder::default())
-							.expect("Instantiation failed")
-							.run_start(&mut NopExternals)
-							.expect("No main function");
+		let code = self.smart_contracts.get(sc_hash).ok_or(format!("unknown contract {}", sc_hash))?;
+		let contract = metered(code)?;
+		let not_started = ModuleInstance::new(&contract, &ImportsBuilder::new().with_resolver("env", &HostResolver))
+							.map_err(|e|e.to_string())?;
+		host.memory = not_started.not_started_instance().export_by_name("memory").and_then(|e|e.as_memory().cloned());
+		let loaded_module = not_started
+							.run_start(host)
+							.map_err(|e|e.to_string())?;
 		
 		let invoked = loaded_module
-			.invoke_export(&fun_hash, &params, &mut NopExternals).expect("callfun3").expect("callfun4");
+			.invoke_export(fun_hash, params, host).map_err(|e|e.to_string())?;
 
 		let is_string: bool = (match loaded_module
-			.invoke_export( &(format!("{}_index", &fun_hash)), &params, &mut NopExternals )
+			.invoke_export( &(format!("{}_index", &fun_hash)), params, host )
 			{
 				Ok(x) => true,
 				Err(x) => false
 			}) && (match loaded_module
-				.invoke_export( &(format!("{}_len", &fun_hash)), &params, &mut NopExternals )
+				.invoke_export( &(format!("{}_len", &fun_hash)), params, host )
 				{
 					Ok(x) => true,
 					Err(x) => false
@@ -235,27 +391,28 @@ impl VM{
 			);
 
 		if is_string {
-			let index: u32 = u32::from_runtime_value( loaded_module
-				.invoke_export( &(format!("{}_index", &fun_hash)), &params, &mut NopExternals ).expect("no index export").expect("no index??")
-				).expect("not u32");
-			let len: usize = u32::from_runtime_value( loaded_module
-				.invoke_export( &(format!("{}_len", &fun_hash)), &params, &mut NopExternals ).expect("no lenght export").expect("no length??")
-				).expect("not u32 2") as usize;
+			let mut export_u32 = |name : String| loaded_module.invoke_export(&name, params, host).ok()
+				.and_then(|v|v).and_then(|v|u32::from_runtime_value(v));
+			let (index, len) = match (export_u32(format!("{}_index", &fun_hash)), export_u32(format!("{}_len", &fun_hash))) {
+				(Some(i), Some(l)) => (i, l as usize),
+				_ => return Err(format!("{}_index and {}_len have to return an u32", fun_hash, fun_hash))
+			};
 
 			let str: Vec<char> = loaded_module
-				.export_by_name("memory").expect("memory export failed").as_memory().expect("OOB error").get(index, len).expect("OOB error 2")
+				.export_by_name("memory").and_then(|e|e.as_memory().cloned()).ok_or("memory export not found".to_string())?
+				.get(index, len).map_err(|e|e.to_string())?
 				.iter().map(|x| *x as char).collect();
 
-			return VMReturn::Chars(str);
+			return Ok(Some(VMReturn::Chars(str)));
 		}else{
-			let ret : VMReturn = match invoked{
-				RuntimeValue::I32(i)=>VMReturn::U32(i.try_into().unwrap()),
-				RuntimeValue::I64(i)=>VMReturn::U64(i.try_into().unwrap()),
+			let ret : Option<VMReturn> = invoked.map(|v|match v{
+				RuntimeValue::I32(i)=>VMReturn::U32(i as u32),
+				RuntimeValue::I64(i)=>VMReturn::U64(i as u64),
 				RuntimeValue::F32(f)=>VMReturn::F64(f.to_float().into()),
 				RuntimeValue::F64(f)=>VMReturn::F64(f.to_float()),
-			};
-			println!("SmartContract: \"{}\" has run its course, with function: \"{}\" and has given the result: {:?}",sc_hash, fun_hash, ret);
-			return ret;
+			});
+			debug!("smart contract {} returned {:?} from {}", sc_hash, ret, fun_hash);
+			return Ok(ret);
 		}
 		// let length: usize = i32::from_runtime_value(
 		// 	mod_instance.invoke_export("hash_len", &[], &mut NopExternals).unwrap().unwrap()